@@ -0,0 +1,515 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+use std::io::{ErrorKind, Result};
+use std::net::IpAddr;
+use std::io::prelude::*;
+
+use mio::{Interest, Token};
+use slab::Slab;
+
+use crate::config::ServerConfig;
+use crate::event_loop::{EventLoop, Sender, Timeout};
+#[cfg(feature = "http2")]
+use crate::http2;
+use crate::listener::Stream;
+use crate::output::Output;
+use crate::request::{Method, Request};
+use crate::response::Response;
+use crate::sse::Attached;
+use crate::websocket::Session;
+
+/// Something for the event loop to act on, sent by whichever thread
+/// produced it.
+pub(crate) enum Notice {
+    /// A response to a request.
+    Reply(Reply),
+    /// Switch a connection to the WebSocket protocol, once the handshake
+    /// response has been sent.
+    Upgrade(Token, u64, Vec<u8>, Box<Session>),
+    /// A frame from a WebSocket handler, and whether it closes the connection.
+    Frame(Token, u64, Vec<u8>, bool),
+    /// Data for an event stream on the given HTTP/2 stream (0 for HTTP/1.x),
+    /// and whether the stream has ended.
+    Chunk(Token, u64, u32, Vec<u8>, bool),
+    /// The server's `ShutdownHandle` has been triggered.
+    Shutdown,
+}
+
+/// A finished response.
+pub(crate) struct Reply {
+    pub token:      Token,
+    pub seq:        u64,
+    #[cfg_attr(not(feature = "http2"), allow(dead_code))]
+    pub stream:     u32,
+    pub response:   Response,
+    pub keep_alive: bool,
+}
+
+/// Where a response should be delivered once a handler has produced it.
+#[derive(Clone)]
+pub(crate) struct Responder {
+    pub token:      Token,
+    pub seq:        u64,
+    // the HTTP/2 stream the request arrived on, or 0 for HTTP/1.x
+    pub stream:     u32,
+    pub tx:         Sender<Notice>,
+    pub keep_alive: bool,
+}
+
+impl Responder {
+    /// Finish the response's headers and hand it to the event loop. The
+    /// connection is kept open only if both the client and the handler allow it.
+    /// An event stream's connection is closed once the stream ends.
+    pub fn send(&self, mut res: Response) {
+        let keep_alive = self.keep_alive && res.events.is_none() && match res.get_header("Connection") {
+            Some(conn) => !conn.eq_ignore_ascii_case("close"),
+            None       => true,
+        };
+
+        res.add_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+
+        let _ = self.tx.send(Notice::Reply(Reply {
+            token:      self.token,
+            seq:        self.seq,
+            stream:     self.stream,
+            response:   res,
+            keep_alive,
+        }));
+    }
+}
+
+/// The result of checking whether a client's input buffer holds a request.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Framing {
+    /// The request line and headers haven't all arrived yet.
+    Incomplete,
+    /// The headers have arrived, but not all of the body.
+    AwaitingBody,
+    /// The first `n` bytes of the buffer are a full request.
+    Complete(usize),
+    /// The request can't be accepted; respond with this status and close.
+    Reject(u16, &'static str),
+}
+
+/// The request line and headers at the start of `buf`, as far as they've
+/// arrived, for an error handler answering a request that won't be read in
+/// full. The body is left out, and an empty request stands in for one whose
+/// line can't be parsed.
+pub(crate) fn head(buf: &[u8]) -> Request {
+    let mut head = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => buf[..pos + 4].to_vec(),
+        None      => match buf.windows(2).rposition(|w| w == b"\r\n") {
+            // end the header section after the last complete line
+            Some(pos) => buf[..pos + 2].to_vec(),
+            None      => return Request::new(),
+        },
+    };
+
+    if !head.ends_with(b"\r\n\r\n") {
+        head.extend_from_slice(b"\r\n");
+    }
+
+    Request::from_bytes(&head).unwrap_or_default()
+}
+
+/// Finds where the request at the start of `buf` ends, enforcing the
+/// configured size limits. This is called as data arrives, so oversized
+/// requests are rejected before they've been read in full. `body_limit` gives
+/// the body size limit for a request's method and path.
+pub(crate) fn frame<F>(buf: &[u8], config: &ServerConfig, body_limit: F) -> Framing
+        where F: FnOnce(Method, &str) -> usize {
+    let line = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(pos) => pos,
+        None      => buf.len(),
+    };
+
+    if line > config.max_request_line {
+        return Framing::Reject(414, "request line too long");
+    }
+
+    let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None      => {
+            if buf.len() > config.max_header_size {
+                return Framing::Reject(431, "request header fields too large");
+            }
+
+            return Framing::Incomplete;
+        },
+    };
+
+    if end > config.max_header_size {
+        return Framing::Reject(431, "request header fields too large");
+    }
+
+    let mut length: usize = 0;
+
+    for line in String::from_utf8_lossy(&buf[..end]).split("\r\n").skip(1) {
+        let mut hdr = line.splitn(2, ':');
+        let name = hdr.next().unwrap_or("").trim();
+        let value = hdr.next().unwrap_or("").trim();
+
+        if name.eq_ignore_ascii_case("Content-Length") {
+            length = match value.parse::<usize>() {
+                Ok(len) => len,
+                Err(_)  => return Framing::Reject(400, "invalid Content-Length"),
+            };
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") && !value.eq_ignore_ascii_case("identity") {
+            return Framing::Reject(501, "request transfer encodings are not supported");
+        }
+    }
+
+    if length > 0 {
+        let head = String::from_utf8_lossy(&buf[..line]);
+        let mut ask = head.split(' ');
+        let method = Method::from(ask.next().unwrap_or(""));
+        let path = ask.next().unwrap_or("").split('?').next().unwrap_or("");
+
+        if length > body_limit(method, path) {
+            return Framing::Reject(413, "request body too large");
+        }
+    }
+
+    if buf.len() < end + length {
+        Framing::AwaitingBody
+    } else {
+        Framing::Complete(end + length)
+    }
+}
+
+/// What a connection is waiting on. Each phase has its own timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Phase {
+    /// Between requests on a kept-alive connection.
+    Idle,
+    /// Reading the request line and headers.
+    Header,
+    /// Reading the request body.
+    Body,
+    /// Waiting for a handler's response.
+    Handling,
+    /// Writing the response.
+    Writing,
+    /// Sending an event stream, with comments sent whenever it's quiet.
+    Streaming,
+    /// Upgraded to the WebSocket protocol, with no timeout.
+    WebSocket,
+    /// Serving HTTP/2, with any number of requests in flight at once.
+    #[cfg(feature = "http2")]
+    Http2,
+}
+
+/// The readiness a client is waiting for. Unlike a bare `Interest` it can be
+/// empty, in which case the socket is left deregistered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Wanted(Option<Interest>);
+
+impl Wanted {
+    pub fn insert(&mut self, interest: Interest) {
+        self.0 = Some(self.0.map_or(interest, |wanted| wanted | interest));
+    }
+
+    pub fn remove(&mut self, interest: Interest) {
+        self.0 = self.0.and_then(|wanted| wanted.remove(interest));
+    }
+}
+
+pub(crate) struct Client {
+    pub sock:       Stream,
+    pub token:      Token,
+    // the name of the listener the connection was accepted on
+    pub listener:   Option<String>,
+    // the peer's address, for TCP connections
+    pub peer:       Option<IpAddr>,
+    // the body size limit for the request being read, once it's known
+    pub body_limit: Option<usize>,
+    pub events:     Wanted,
+    pub i_buf:      Vec<u8>,
+    // set once the client has shut down its side of the connection; what it
+    // sent before then is still answered
+    pub eof:        bool,
+    pub o_buf:      Output,
+    pub phase:      Phase,
+    // identifies the request being handled, so that a response arriving
+    // after its deadline has passed can be told apart
+    pub seq:        u64,
+    // whether to wait for another request once o_buf has been written
+    pub keep_alive: bool,
+    pub timer:      Option<Timeout>,
+    // set once the connection has been upgraded to a WebSocket
+    pub ws:         Option<Box<Session>>,
+    // set while an HTTP/1.x response is an event stream
+    pub sse:        Option<Attached>,
+    // set once the client has started speaking HTTP/2
+    #[cfg(feature = "http2")]
+    pub h2:         Option<Box<http2::Connection>>,
+    chunk:          usize,
+    // whether the socket is registered with the event loop
+    registered:     bool,
+}
+
+impl Client {
+    pub fn new(sock: Stream, token: Token, listener: Option<String>, peer: Option<IpAddr>, chunk: usize) -> Client {
+        Client {
+            sock,
+            token,
+            listener,
+            peer,
+            body_limit: None,
+            events:     Wanted::default(),
+            i_buf:      Vec::with_capacity(chunk),
+            eof:        false,
+            o_buf:      Output::new(),
+            phase:      Phase::Header,
+            seq:        0,
+            keep_alive: false,
+            timer:      None,
+            ws:         None,
+            sse:        None,
+            #[cfg(feature = "http2")]
+            h2:         None,
+            chunk,
+            registered: false,
+        }
+    }
+
+    // read everything available from the socket, stopping early once the
+    // buffer is past `limit` (framing will reject the request), or at the
+    // end of the client's input, which sets `eof`.
+    pub fn receive(&mut self, limit: usize) -> Result<()> {
+        let mut buf = vec![0; self.chunk];
+
+        while self.i_buf.len() <= limit {
+            match self.sock.read(&mut buf) {
+                Ok(0)                                           => { self.eof = true; break; },
+                Ok(len)                                         => self.i_buf.extend_from_slice(&buf[..len]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e)                                          => return Err(e),
+            }
+        }
+
+        if self.sock.wants_write() {
+            // e.g. a TLS handshake message the socket couldn't take yet
+            self.events.insert(Interest::WRITABLE);
+        }
+
+        Ok(())
+    }
+
+    // write the client's output buffer to the socket.
+    //
+    // the following return values mean:
+    //  - Ok(true):  we're done with this response; the buffer has been
+    //               written in full
+    //  - Ok(false): keep listening for writeable event and continue next time
+    //  - Err(e):    something dun fucked up
+    pub fn send(&mut self) -> Result<bool> {
+        while !self.o_buf.is_empty() {
+            match self.o_buf.write_to(&mut self.sock) {
+                Ok(0)                                               => return Err(ErrorKind::WriteZero.into()),
+                Ok(_)                                               => {},
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock     => {
+                    // the socket is full; carry on once it drains
+                    self.events.insert(Interest::WRITABLE);
+                    return Ok(false);
+                },
+                Err(ref e) if e.kind() == ErrorKind::Interrupted    => {},
+                Err(e)                                              => return Err(e),
+            }
+        }
+
+        if !self.flush()? {
+            return Ok(false);
+        }
+
+        self.events.remove(Interest::WRITABLE);
+        Ok(true)
+    }
+
+    // write out anything the stream itself has buffered, such as encrypted
+    // output on a TLS connection.
+    //
+    // the following return values mean:
+    //  - Ok(true):  everything has been written
+    //  - Ok(false): the socket is full; try again when it's writable
+    pub fn flush(&mut self) -> Result<bool> {
+        match self.sock.flush() {
+            Ok(())                                          => Ok(true),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e)                                          => Err(e),
+        }
+    }
+
+    // a request has been dispatched and its response hasn't been written yet,
+    // or the connection is in use as a WebSocket or an event stream
+    pub fn is_busy(&self) -> bool {
+        #[cfg(feature = "http2")]
+        {
+            if let Some(ref conn) = self.h2 {
+                return conn.open_streams() > 0 || !self.o_buf.is_empty();
+            }
+        }
+
+        matches!(self.phase, Phase::Handling | Phase::Writing | Phase::Streaming | Phase::WebSocket)
+    }
+
+    pub fn register(&mut self, evl: &mut EventLoop) -> Result<()> {
+        self.events.insert(Interest::READABLE);
+        self.reregister(evl)
+    }
+
+    // bring the socket's registration in line with `events`. a socket that
+    // isn't waiting for anything is deregistered until it is again.
+    pub fn reregister(&mut self, evl: &mut EventLoop) -> Result<()> {
+        match (self.events.0, self.registered) {
+            (Some(interest), true)  => evl.reregister(&mut self.sock, self.token, interest),
+            (Some(interest), false) => {
+                evl.register(&mut self.sock, self.token, interest)?;
+                self.registered = true;
+                Ok(())
+            },
+            (None, true)            => {
+                self.registered = false;
+                evl.deregister(&mut self.sock)
+            },
+            (None, false)           => Ok(()),
+        }
+    }
+}
+
+/// The open client connections, found by their tokens.
+pub(crate) struct Clients {
+    slab:     Slab<Client>,
+    // the token of the first slot; lower ones are used by the event loop
+    first:    usize,
+    capacity: usize,
+}
+
+impl Clients {
+    pub fn new(first: Token, capacity: usize) -> Clients {
+        Clients { slab: Slab::new(), first: first.0, capacity }
+    }
+
+    pub fn count(&self) -> usize {
+        self.slab.len()
+    }
+
+    pub fn contains(&self, token: Token) -> bool {
+        self.get(token).is_some()
+    }
+
+    pub fn get(&self, token: Token) -> Option<&Client> {
+        token.0.checked_sub(self.first).and_then(|key| self.slab.get(key))
+    }
+
+    pub fn get_mut(&mut self, token: Token) -> Option<&mut Client> {
+        token.0.checked_sub(self.first).and_then(move |key| self.slab.get_mut(key))
+    }
+
+    /// Add a client built for the token it's given, unless every slot is
+    /// taken.
+    pub fn insert_with<F: FnOnce(Token) -> Client>(&mut self, build: F) -> Option<Token> {
+        if self.slab.len() >= self.capacity {
+            return None;
+        }
+
+        let entry = self.slab.vacant_entry();
+        let token = Token(self.first + entry.key());
+
+        entry.insert(build(token));
+        Some(token)
+    }
+
+    pub fn remove(&mut self, token: Token) -> Option<Client> {
+        let key = token.0.checked_sub(self.first)?;
+
+        self.slab.try_remove(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Client> {
+        self.slab.iter().map(|(_, client)| client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ServerConfig {
+        ServerConfig {
+            max_request_line: 256,
+            max_header_size:  1024,
+            max_body_size:    16,
+            ..ServerConfig::default()
+        }
+    }
+
+    fn frame(buf: &[u8], config: &ServerConfig) -> Framing {
+        super::frame(buf, config, |_, _| config.max_body_size)
+    }
+
+    #[test]
+    fn test_frame_complete() {
+        let req = b"GET / HTTP/1.1\r\nHost: foo\r\n\r\nGET /next";
+
+        assert_eq!(Framing::Complete(29), frame(req, &config()));
+    }
+
+    #[test]
+    fn test_frame_body() {
+        let partial = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nab";
+        let full = b"POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nabcde";
+
+        assert_eq!(Framing::AwaitingBody, frame(partial, &config()));
+        assert_eq!(Framing::Complete(full.len()), frame(full, &config()));
+    }
+
+    #[test]
+    fn test_frame_limits() {
+        let big_body = b"POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n";
+        let mut big_head = b"GET / HTTP/1.1\r\n".to_vec();
+        big_head.extend(vec![b'a'; 1025]);
+        let long_line = vec![b'a'; 257];
+        let chunked = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+        assert_eq!(Framing::Reject(413, "request body too large"), frame(big_body, &config()));
+        assert_eq!(Framing::Reject(431, "request header fields too large"), frame(&big_head, &config()));
+        assert_eq!(Framing::Reject(414, "request line too long"), frame(&long_line, &config()));
+        assert_eq!(Framing::Incomplete, frame(b"GET / HTTP/1.1\r\n", &config()));
+        assert!(matches!(frame(chunked, &config()), Framing::Reject(501, _)));
+    }
+
+    #[test]
+    fn test_frame_route_body_limit() {
+        let upload = b"POST /upload?x=1 HTTP/1.1\r\nContent-Length: 17\r\n\r\n";
+        let limit = |method: Method, path: &str| {
+            if method == Method::Post && path == "/upload" { 1024 } else { 16 }
+        };
+
+        assert_eq!(Framing::AwaitingBody, super::frame(upload, &config(), limit));
+    }
+
+    #[test]
+    fn test_head() {
+        let req = head(b"POST /upload HTTP/1.1\r\nAccept: application/json\r\n\r\nbody");
+
+        assert_eq!("/upload", req.path);
+        assert_eq!(Some("application/json".to_string()), req.get_header("Accept"));
+        assert!(req.payload.is_empty());
+
+        // headers that haven't all arrived are read as far as the last
+        // complete line
+        let req = head(b"GET /slow HTTP/1.1\r\nAccept: text/html\r\nX-Par");
+
+        assert_eq!("/slow", req.path);
+        assert_eq!(Some("text/html".to_string()), req.get_header("Accept"));
+        assert_eq!(None, req.get_header("X-Par"));
+        assert_eq!("", head(b"GET /no-end").path);
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+//! Compressing responses for clients that accept it. With the `compression`
+//! feature, `Canteen::compress` compresses handlers' responses as they're
+//! sent, in whichever encoding the client's `Accept-Encoding` header
+//! prefers, and `CompressOptions` controls which responses are compressed.
+//!
+//! ```rust,ignore
+//! use canteen::Canteen;
+//! use canteen::compress::{CompressOptions, Encoding};
+//!
+//! let mut cnt = Canteen::new();
+//!
+//! cnt.compress(CompressOptions::new().encodings(&[Encoding::Gzip])
+//!                                    .min_size(512));
+//! ```
+//!
+//! Files can also be compressed ahead of time: `utils::static_file`, and
+//! mounts with `StaticOptions::precompressed`, send `app.js.br` or
+//! `app.js.gz`, if there's one, for `app.js`.
+//!
+//! The feature also decompresses request bodies sent with a
+//! `Content-Encoding`, so that handlers see them as they were before they
+//! were compressed. Bodies that decompress to more than the route's body size
+//! limit are answered with a 413, and ones in an encoding that isn't
+//! supported with a 415. Without the feature no encoding is supported, so
+//! every compressed body is answered with a 415 rather than being passed on
+//! to a handler as it is.
+
+#[cfg(feature = "compression")]
+use std::io::{self, Read, Write};
+
+/// A content coding a response can be compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+}
+
+impl Encoding {
+    /// The name of the encoding in HTTP headers.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::compress::Encoding;
+    ///
+    /// assert_eq!("br", Encoding::Brotli.name());
+    /// ```
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip      => "gzip",
+            Encoding::Deflate   => "deflate",
+            Encoding::Brotli    => "br",
+            Encoding::Zstd      => "zstd",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Encoding> {
+        match name.to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip"   => Some(Encoding::Gzip),
+            "deflate"           => Some(Encoding::Deflate),
+            "br"                => Some(Encoding::Brotli),
+            "zstd"              => Some(Encoding::Zstd),
+            _                   => None,
+        }
+    }
+}
+
+/// Pick an encoding for a response, from those `available`, in order of
+/// preference, going by the request's `Accept-Encoding` header. None means
+/// the response is sent as it is.
+pub(crate) fn negotiate(header: Option<&str>, available: &[Encoding]) -> Option<Encoding> {
+    let header = header?;
+    let mut weights: Vec<(Option<Encoding>, f32)> = Vec::new();
+    let mut any = 0.0;
+
+    for item in header.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let mut weight = 1.0;
+
+        for param in params {
+            if let Some((key, value)) = param.split_once('=') {
+                if key.trim().eq_ignore_ascii_case("q") {
+                    weight = value.trim().parse::<f32>().unwrap_or(0.0);
+                }
+            }
+        }
+
+        if name == "*" {
+            any = weight;
+        } else if !name.is_empty() {
+            weights.push((Encoding::from_name(name), weight));
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+
+    for &encoding in available {
+        // encodings the client doesn't name get the weight of "*"
+        let weight = weights.iter()
+                            .find(|&&(named, _)| named == Some(encoding))
+                            .map_or(any, |&(_, weight)| weight);
+
+        if weight > 0.0 && best.is_none_or(|(_, top)| weight > top) {
+            best = Some((encoding, weight));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Which responses `Canteen::compress` compresses, and how.
+///
+/// By default, responses of at least 1 KiB whose Content-Type is text, JSON,
+/// JavaScript, XML or SVG are compressed with Brotli, zstd, gzip or deflate,
+/// in that order of preference.
+#[cfg(feature = "compression")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressOptions {
+    encodings: Vec<Encoding>,
+    min_size:  usize,
+    types:     Vec<String>,
+}
+
+#[cfg(feature = "compression")]
+impl Default for CompressOptions {
+    fn default() -> CompressOptions {
+        let types = [
+            "text/*", "application/json", "application/javascript", "application/xml",
+            "application/xhtml+xml", "application/rss+xml", "application/atom+xml", "image/svg+xml",
+        ];
+
+        CompressOptions {
+            encodings: vec![Encoding::Brotli, Encoding::Zstd, Encoding::Gzip, Encoding::Deflate],
+            min_size:  1024,
+            types:     types.iter().map(|ctype| ctype.to_string()).collect(),
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+impl CompressOptions {
+    /// Create the default options.
+    pub fn new() -> CompressOptions {
+        CompressOptions::default()
+    }
+
+    /// The encodings to use, in order of preference where the client has
+    /// none.
+    pub fn encodings(mut self, encodings: &[Encoding]) -> CompressOptions {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// The size, in bytes, below which a body isn't worth compressing.
+    pub fn min_size(mut self, size: usize) -> CompressOptions {
+        self.min_size = size;
+        self
+    }
+
+    /// The content types to compress. A type like `text/*` covers all of
+    /// its subtypes.
+    pub fn content_types(mut self, types: &[&str]) -> CompressOptions {
+        self.types = types.iter().map(|ctype| ctype.to_string()).collect();
+        self
+    }
+
+    pub(crate) fn available(&self) -> &[Encoding] {
+        &self.encodings
+    }
+
+    /// Whether a body of `size` bytes and type `ctype` is to be compressed.
+    pub(crate) fn allows(&self, ctype: &str, size: usize) -> bool {
+        let mime = ctype.split(';').next().unwrap_or("").trim();
+
+        size >= self.min_size && self.types.iter().any(|allowed| match allowed.strip_suffix("/*") {
+            Some(kind)  => mime.split_once('/').is_some_and(|(major, _)| major.eq_ignore_ascii_case(kind)),
+            None        => mime.eq_ignore_ascii_case(allowed),
+        })
+    }
+}
+
+/// Compress `data` with `encoding`.
+#[cfg(feature = "compression")]
+pub(crate) fn encode(encoding: Encoding, data: &[u8]) -> io::Result<Vec<u8>> {
+    use flate2::Compression;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+
+    match encoding {
+        Encoding::Gzip      => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+
+            encoder.write_all(data)?;
+            encoder.finish()
+        },
+        Encoding::Deflate   => {
+            // "deflate" in HTTP means the zlib format
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+
+            encoder.write_all(data)?;
+            encoder.finish()
+        },
+        Encoding::Brotli    => {
+            // a middling quality: the best are far too slow to run per response
+            let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+
+            encoder.write_all(data)?;
+            Ok(encoder.into_inner())
+        },
+        Encoding::Zstd      => zstd::bulk::compress(data, 3),
+    }
+}
+
+/// Why a request body couldn't be decompressed.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "compression"), allow(dead_code))]
+pub(crate) enum DecodeError {
+    /// The body's in an encoding that isn't supported, named here.
+    Unsupported(String),
+    /// The body decompresses to more than the limit.
+    TooLarge,
+    /// The body isn't valid data in its encoding.
+    Corrupt(Encoding),
+}
+
+/// Decompress `data` from `encoding`, giving up if it comes to more than
+/// `limit` bytes, so that a small body can't fill memory.
+#[cfg(feature = "compression")]
+pub(crate) fn decode(encoding: Encoding, data: &[u8], limit: usize) -> Result<Vec<u8>, DecodeError> {
+    use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+
+    // one more byte than the limit is read to tell whether it's been passed
+    let read = |reader: &mut dyn Read| {
+        let mut out = Vec::new();
+
+        match reader.take(limit as u64 + 1).read_to_end(&mut out) {
+            Ok(_) if out.len() > limit  => Err(DecodeError::TooLarge),
+            Ok(_)                       => Ok(out),
+            Err(_)                      => Err(DecodeError::Corrupt(encoding)),
+        }
+    };
+
+    match encoding {
+        Encoding::Gzip      => read(&mut MultiGzDecoder::new(data)),
+        // some clients send raw deflate data rather than the zlib format
+        Encoding::Deflate   => match read(&mut ZlibDecoder::new(data)) {
+            Err(DecodeError::Corrupt(_))    => read(&mut DeflateDecoder::new(data)),
+            decoded                         => decoded,
+        },
+        Encoding::Brotli    => read(&mut brotli::Decompressor::new(data, 4096)),
+        Encoding::Zstd      => match zstd::stream::read::Decoder::new(data) {
+            Ok(mut decoder) => read(&mut decoder),
+            Err(_)          => Err(DecodeError::Corrupt(encoding)),
+        },
+    }
+}
+
+/// Without the `compression` feature there's nothing to decompress with.
+#[cfg(not(feature = "compression"))]
+pub(crate) fn decode(encoding: Encoding, _: &[u8], _: usize) -> Result<Vec<u8>, DecodeError> {
+    Err(DecodeError::Unsupported(encoding.name().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let all = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip, Encoding::Deflate];
+        let cases = vec![
+            (None,                                  None),
+            (Some(""),                              None),
+            (Some("identity"),                      None),
+            (Some("gzip"),                          Some(Encoding::Gzip)),
+            (Some("x-gzip, deflate"),               Some(Encoding::Gzip)),
+            (Some("gzip, deflate, br, zstd"),       Some(Encoding::Brotli)),
+            (Some("GZIP;q=0.5, Deflate"),           Some(Encoding::Deflate)),
+            (Some("br;q=0, gzip;q=0.1"),            Some(Encoding::Gzip)),
+            (Some("*"),                             Some(Encoding::Brotli)),
+            (Some("*;q=0.5, zstd"),                 Some(Encoding::Zstd)),
+            (Some("*, br;q=0, zstd;q=0"),           Some(Encoding::Gzip)),
+            (Some("gzip;q=bogus"),                  None),
+        ];
+
+        for (header, encoding) in cases {
+            assert_eq!(encoding, negotiate(header, &all), "{:?}", header);
+        }
+
+        assert_eq!(None, negotiate(Some("br"), &[Encoding::Gzip]));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_options_and_encode() {
+        use std::io::Read;
+
+        let options = CompressOptions::new();
+
+        assert!(options.allows("text/html; charset=utf-8", 1024));
+        assert!(options.allows("application/json", 4096));
+        assert!(!options.allows("text/html", 1023));
+        assert!(!options.allows("image/png", 4096));
+        assert!(!options.allows("textual/html", 4096));
+
+        let options = options.content_types(&["image/*"]).min_size(0);
+
+        assert!(options.allows("image/png", 0));
+        assert!(!options.allows("text/plain", 0));
+
+        let data = "canteen ".repeat(1000).into_bytes();
+        let mut out = Vec::new();
+
+        flate2::read::GzDecoder::new(&encode(Encoding::Gzip, &data).unwrap()[..]).read_to_end(&mut out).unwrap();
+        assert_eq!(data, out);
+
+        out.clear();
+        flate2::read::ZlibDecoder::new(&encode(Encoding::Deflate, &data).unwrap()[..]).read_to_end(&mut out).unwrap();
+        assert_eq!(data, out);
+
+        out.clear();
+        brotli::Decompressor::new(&encode(Encoding::Brotli, &data).unwrap()[..], 4096).read_to_end(&mut out).unwrap();
+        assert_eq!(data, out);
+
+        assert_eq!(data, zstd::stream::decode_all(&encode(Encoding::Zstd, &data).unwrap()[..]).unwrap());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_decode() {
+        let data = "canteen ".repeat(1000).into_bytes();
+
+        for &encoding in &[Encoding::Gzip, Encoding::Deflate, Encoding::Brotli, Encoding::Zstd] {
+            let encoded = encode(encoding, &data).unwrap();
+
+            assert_eq!(Ok(data.clone()), decode(encoding, &encoded, data.len()), "{:?}", encoding);
+            assert_eq!(Err(DecodeError::TooLarge), decode(encoding, &encoded, data.len() - 1), "{:?}", encoding);
+            assert_eq!(Err(DecodeError::Corrupt(encoding)), decode(encoding, b"\x1f\x8b garbage", 8000), "{:?}", encoding);
+        }
+
+        // raw deflate data, without the zlib header
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+
+        encoder.write_all(&data).unwrap();
+        assert_eq!(Ok(data.clone()), decode(Encoding::Deflate, &encoder.finish().unwrap(), 8000));
+
+        // a bomb: 16 MB of zeros compresses to around 16 KB
+        let bomb = encode(Encoding::Gzip, &vec![0; 16 << 20]).unwrap();
+
+        assert!(bomb.len() < 64 << 10);
+        assert_eq!(Err(DecodeError::TooLarge), decode(Encoding::Gzip, &bomb, 1 << 20));
+    }
+}
@@ -0,0 +1,360 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+//! Entity tags and conditional requests. A GET handler that sets an `ETag` or
+//! a `Last-Modified` date on its response has `If-None-Match`,
+//! `If-Modified-Since`, `If-Match` and `If-Unmodified-Since` answered for it
+//! with a 304 or a 412.
+//!
+//! ```rust
+//! use canteen::{Request, Response};
+//! use canteen::conditional::ETag;
+//!
+//! fn handler(_: &Request) -> Response {
+//!     let mut res = Response::new();
+//!
+//!     res.append("Hello, world!");
+//!     res.set_etag(&ETag::strong("v1"));
+//!
+//!     // a request with "If-None-Match: \"v1\"" is sent a 304
+//!     res
+//! }
+//! ```
+//!
+//! The check happens once the handler has run, so it's only made for GETs.
+//! For any other method, such as a PUT, the preconditions have to be checked
+//! before the handler changes anything: either by the handler itself, with
+//! `check_preconditions`, or by giving the route a `Validator` with
+//! `Canteen::validate_route`.
+
+use std::fmt;
+use std::hash::Hasher;
+use std::str::FromStr;
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+
+use crate::error::ErrorContext;
+use crate::request::{Method, Request};
+use crate::response::Response;
+use crate::utils;
+
+/// Gives the tag and last modification date of the current version of the
+/// resource a request is for, so that its preconditions can be checked
+/// before the handler runs.
+pub type Validator = Arc<dyn Fn(&Request) -> (Option<ETag>, Option<DateTime<Utc>>) + Send + Sync>;
+
+/// An entity tag, which identifies a version of a resource.
+///
+/// A strong tag changes whenever the body does, so parts of bodies with the
+/// same strong tag can be combined. A weak tag only changes when the body
+/// does in some way that matters, such as for a page with a timestamp.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ETag {
+    tag:  String,
+    weak: bool,
+}
+
+impl ETag {
+    /// Create a strong tag. The tag mustn't contain double quotes.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::conditional::ETag;
+    ///
+    /// assert_eq!("\"v1\"", ETag::strong("v1").to_string());
+    /// ```
+    pub fn strong(tag: &str) -> ETag {
+        ETag { tag: String::from(tag), weak: false }
+    }
+
+    /// Create a weak tag. The tag mustn't contain double quotes.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::conditional::ETag;
+    ///
+    /// assert_eq!("W/\"v1\"", ETag::weak("v1").to_string());
+    /// ```
+    pub fn weak(tag: &str) -> ETag {
+        ETag { tag: String::from(tag), weak: true }
+    }
+
+    /// Create a strong tag from a hash of `data`. The same data always gets
+    /// the same tag.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::conditional::ETag;
+    ///
+    /// assert_eq!(ETag::from_data(b"abc"), ETag::from_data(b"abc"));
+    /// assert_ne!(ETag::from_data(b"abc"), ETag::from_data(b"abd"));
+    /// ```
+    pub fn from_data(data: &[u8]) -> ETag {
+        let mut hasher = Fnv::new();
+
+        hasher.write(data);
+        hasher.etag()
+    }
+
+    /// The tag, without its quotes.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Whether the tag is weak.
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+
+    /// Whether both tags are strong and the same, as `If-Match` requires.
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Whether the tags are the same, strong or not, as `If-None-Match`
+    /// requires.
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.weak {
+            write!(f, "W/\"{}\"", self.tag)
+        } else {
+            write!(f, "\"{}\"", self.tag)
+        }
+    }
+}
+
+impl FromStr for ETag {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ETag, ()> {
+        match parse_list(s) {
+            Some(ref mut tags) if tags.len() == 1   => Ok(tags.remove(0)),
+            _                                       => Err(()),
+        }
+    }
+}
+
+// FNV-1a, which is quick and gives the same hash on every platform and
+// release, so tags survive a restart
+pub(crate) struct Fnv(u64);
+
+impl Fnv {
+    pub fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn etag(&self) -> ETag {
+        ETag { tag: format!("{:016x}", self.0), weak: false }
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// the tags in an If-Match or If-None-Match header, or None if it's "*"
+fn parse_list(header: &str) -> Option<Vec<ETag>> {
+    let header = header.trim();
+
+    if header == "*" {
+        return None;
+    }
+
+    let mut tags = Vec::new();
+    let mut rest = header;
+
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+
+        if rest.is_empty() {
+            break;
+        }
+
+        let weak = rest.starts_with("W/");
+
+        if weak {
+            rest = &rest[2..];
+        }
+
+        // a tag can hold commas, so it ends at the closing quote
+        let end = match rest.strip_prefix('"').and_then(|quoted| quoted.find('"')) {
+            Some(end)   => end,
+            None        => break,
+        };
+
+        tags.push(ETag { tag: String::from(&rest[1..=end]), weak });
+        rest = &rest[end + 2..];
+    }
+
+    Some(tags)
+}
+
+/// Evaluate a request's preconditions against the current version of the
+/// resource, as identified by its tag and last modification date. Gives the
+/// status to send instead of the usual response: 304 when the client's copy
+/// is current, or 412 when a precondition fails.
+///
+/// # Examples
+///
+/// ```rust
+/// use canteen::{Request, Response};
+/// use canteen::conditional::{self, ETag};
+/// use canteen::utils;
+///
+/// // Given the route "/doc" for PUT
+/// fn handler(req: &Request) -> Response {
+///     let current = ETag::strong("v1");
+///
+///     if let Some(status) = conditional::check_preconditions(req, Some(&current), None) {
+///         return utils::make_response("", "text/plain", status);
+///     }
+///
+///     // ... save the document
+///     utils::make_response("saved", "text/plain", 200)
+/// }
+/// ```
+pub fn check_preconditions(req: &Request, etag: Option<&ETag>, last: Option<DateTime<Utc>>) -> Option<u16> {
+    let safe = req.method == Method::Get;
+
+    if let Some(header) = req.get_header("If-Match") {
+        let matched = match (parse_list(&header), etag) {
+            (None, current)             => current.is_some(),
+            (Some(tags), Some(current)) => tags.iter().any(|tag| tag.strong_eq(current)),
+            (Some(_), None)             => false,
+        };
+
+        if !matched {
+            return Some(412);
+        }
+    } else if let (Some(header), Some(last)) = (req.get_header("If-Unmodified-Since"), last) {
+        if let Some(since) = utils::parse_http_date(&header) {
+            if last > since {
+                return Some(412);
+            }
+        }
+    }
+
+    if let Some(header) = req.get_header("If-None-Match") {
+        let matched = match (parse_list(&header), etag) {
+            (None, _)                   => true,
+            (Some(tags), Some(current)) => tags.iter().any(|tag| tag.weak_eq(current)),
+            (Some(_), None)             => false,
+        };
+
+        if matched {
+            return Some(if safe { 304 } else { 412 });
+        }
+    } else if let (Some(header), Some(last), true) = (req.get_header("If-Modified-Since"), last, safe) {
+        if let Some(since) = utils::parse_http_date(&header) {
+            if last <= since {
+                return Some(304);
+            }
+        }
+    }
+
+    None
+}
+
+// check a request's preconditions before its handler runs, giving a 304 to
+// send when the client's copy is current, or an error for the 412 handler
+// when a precondition fails.
+pub(crate) fn precheck(req: &Request, validator: &Validator) -> Option<Result<Response, ErrorContext>> {
+    let (etag, last) = validator(req);
+
+    match check_preconditions(req, etag.as_ref(), last)? {
+        304     => {
+            let mut res = Response::new();
+
+            res.set_status(304);
+
+            if let Some(ref etag) = etag {
+                res.set_etag(etag);
+            }
+
+            if let Some(last) = last {
+                res.set_last_modified(last);
+            }
+
+            Some(Ok(res))
+        },
+        status  => Some(Err(ErrorContext::new(status, "precondition failed"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn check(method: &str, headers: &str, etag: Option<&str>) -> Option<u16> {
+        let req = Request::from_str(&format!("{} /doc HTTP/1.1\r\n{}\r\n", method, headers)).unwrap();
+        let etag = etag.map(|etag| etag.parse::<ETag>().unwrap());
+        let last = Utc.with_ymd_and_hms(2018, 1, 1, 0, 0, 0).unwrap();
+
+        check_preconditions(&req, etag.as_ref(), Some(last))
+    }
+
+    #[test]
+    fn test_etag() {
+        assert_eq!(Ok(ETag::strong("a,b")), "\"a,b\"".parse());
+        assert_eq!(Ok(ETag::weak("")), " W/\"\" ".parse());
+        assert_eq!(Err(()), "abc".parse::<ETag>());
+        assert_eq!(Err(()), "\"a\", \"b\"".parse::<ETag>());
+        assert_eq!(None, parse_list("*"));
+        assert_eq!(Some(vec![ETag::strong("a"), ETag::weak("b")]), parse_list("\"a\",W/\"b\", ,"));
+
+        assert!(ETag::strong("a").strong_eq(&ETag::strong("a")));
+        assert!(!ETag::strong("a").strong_eq(&ETag::weak("a")));
+        assert!(ETag::strong("a").weak_eq(&ETag::weak("a")));
+        assert!(!ETag::weak("a").weak_eq(&ETag::weak("b")));
+    }
+
+    #[test]
+    fn test_check_preconditions() {
+        let cases = vec![
+            ("GET",  "",                                                        Some("\"a\""),  None),
+            ("GET",  "If-None-Match: \"a\"\r\n",                                Some("\"a\""),  Some(304)),
+            ("GET",  "If-None-Match: \"b\", W/\"a\"\r\n",                       Some("\"a\""),  Some(304)),
+            ("GET",  "If-None-Match: \"b\"\r\n",                                Some("\"a\""),  None),
+            ("GET",  "If-None-Match: *\r\n",                                    None,           Some(304)),
+            ("PUT",  "If-None-Match: *\r\n",                                    None,           Some(412)),
+            ("GET",  "If-Match: \"a\"\r\n",                                     Some("\"a\""),  None),
+            ("GET",  "If-Match: W/\"a\"\r\n",                                   Some("\"a\""),  Some(412)),
+            ("PUT",  "If-Match: \"b\"\r\n",                                     Some("\"a\""),  Some(412)),
+            ("PUT",  "If-Match: *\r\n",                                         None,           Some(412)),
+            ("GET",  "If-Modified-Since: Mon, 01 Jan 2018 00:00:00 GMT\r\n",    None,           Some(304)),
+            ("GET",  "If-Modified-Since: Sun, 31 Dec 2017 23:59:59 GMT\r\n",    None,           None),
+            ("GET",  "If-Modified-Since: garbage\r\n",                          None,           None),
+            ("PUT",  "If-Modified-Since: Mon, 01 Jan 2018 00:00:00 GMT\r\n",    None,           None),
+            ("PUT",  "If-Unmodified-Since: Sun, 31 Dec 2017 23:59:59 GMT\r\n",  None,           Some(412)),
+            ("PUT",  "If-Unmodified-Since: Mon, 01 Jan 2018 00:00:00 GMT\r\n",  None,           None),
+            // a tag takes precedence over a date
+            ("GET",  "If-None-Match: \"b\"\r\nIf-Modified-Since: Mon, 01 Jan 2018 00:00:00 GMT\r\n", Some("\"a\""), None),
+            ("PUT",  "If-Match: \"a\"\r\nIf-Unmodified-Since: Sun, 31 Dec 2017 23:59:59 GMT\r\n",   Some("\"a\""), None),
+        ];
+
+        for (method, headers, etag, status) in cases {
+            assert_eq!(status, check(method, headers, etag), "{} {:?}", method, headers);
+        }
+    }
+}
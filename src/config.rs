@@ -0,0 +1,390 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+use std::thread;
+use std::time::Duration;
+
+use crate::Canteen;
+use crate::error::ConfigError;
+
+/// The tunable settings of a Canteen server. The defaults are sized from the
+/// number of CPUs available; use `CanteenBuilder` to change them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    /// The number of threadpool workers running synchronous handlers.
+    pub workers:          usize,
+    /// The number of threads polling async handlers' futures.
+    pub async_threads:    usize,
+    /// The maximum number of simultaneously open client connections.
+    pub max_connections:  usize,
+    /// The size of each read from a client socket, in bytes.
+    pub read_buffer_size: usize,
+    /// The maximum length of a request line, in bytes. Longer ones are
+    /// answered with a 414.
+    pub max_request_line: usize,
+    /// The maximum size of a request's line and headers, in bytes. Larger
+    /// ones are answered with a 431.
+    pub max_header_size:  usize,
+    /// The maximum size of a request's body, in bytes. Larger ones are
+    /// answered with a 413. Routes can override this with
+    /// `Canteen::limit_route_body`.
+    pub max_body_size:    usize,
+    /// The maximum size of a message received on a WebSocket, in bytes.
+    /// Larger ones close the connection with a 1009.
+    pub max_message_size: usize,
+    /// How long a client has to send a request's line and headers.
+    pub header_timeout:   Duration,
+    /// How long a client has to send a request's body once its headers have
+    /// arrived.
+    pub body_timeout:     Duration,
+    /// How long a client may go without reading any of a response before
+    /// the connection is closed. A slow download is fine as long as it's
+    /// making progress.
+    pub write_timeout:    Duration,
+    /// How long a handler has to produce a response before the client is
+    /// sent a 504, or `None` to wait indefinitely. The handler isn't
+    /// interrupted; its response is discarded when it finishes. Off by
+    /// default, since handlers could always take as long as they needed.
+    pub handler_timeout:  Option<Duration>,
+    /// How long an idle connection is kept open between requests, or `None`
+    /// to close every connection after its response is sent. Off by default.
+    pub keep_alive:       Option<Duration>,
+    /// How long an event stream may go without sending anything before a
+    /// comment is sent to keep it open.
+    pub event_keep_alive: Duration,
+    /// The maximum number of simultaneously open connections from a single
+    /// IP address, or `None` for no limit.
+    pub max_connections_per_ip: Option<usize>,
+    /// The maximum number of requests waiting for a worker. Requests beyond
+    /// this are answered with a 503 straight away.
+    pub max_queued_jobs:  usize,
+    /// The delay suggested to clients in the `Retry-After` header of 503
+    /// responses sent when the server is overloaded.
+    pub retry_after:      Duration,
+    /// The length of the listening socket's queue of pending connections.
+    pub backlog:          u32,
+    /// How long a shutdown waits for in-flight requests to be answered.
+    pub shutdown_timeout: Duration,
+}
+
+fn cpu_count() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        let cpus = cpu_count();
+
+        ServerConfig {
+            // handlers are free to block, so allow several per core
+            workers:          (cpus * 8).clamp(8, 256),
+            async_threads:    cpus.min(2),
+            max_connections:  (cpus * 1024).clamp(2048, 65536),
+            max_connections_per_ip: None,
+            max_queued_jobs:  1024,
+            retry_after:      Duration::from_secs(1),
+            read_buffer_size: 4096,
+            max_request_line: 8 * 1024,
+            max_header_size:  16 * 1024,
+            max_body_size:    8 * 1024 * 1024,
+            max_message_size: 8 * 1024 * 1024,
+            header_timeout:   Duration::from_secs(10),
+            body_timeout:     Duration::from_secs(30),
+            write_timeout:    Duration::from_secs(30),
+            handler_timeout:  None,
+            keep_alive:       None,
+            event_keep_alive: Duration::from_secs(15),
+            backlog:          1024,
+            shutdown_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Checks that every setting is usable.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::ServerConfig;
+    ///
+    /// let mut config = ServerConfig::default();
+    /// assert!(config.validate().is_ok());
+    ///
+    /// config.workers = 0;
+    /// assert!(config.validate().is_err());
+    /// ```
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.workers == 0 {
+            return Err(ConfigError::new("workers", "must be at least 1"));
+        }
+
+        if self.async_threads == 0 {
+            return Err(ConfigError::new("async_threads", "must be at least 1"));
+        }
+
+        if self.max_connections == 0 {
+            return Err(ConfigError::new("max_connections", "must be at least 1"));
+        }
+
+        if self.max_connections_per_ip == Some(0) {
+            return Err(ConfigError::new("max_connections_per_ip", "must be at least 1, or None to disable it"));
+        }
+
+        if self.max_queued_jobs == 0 {
+            return Err(ConfigError::new("max_queued_jobs", "must be at least 1"));
+        }
+
+        if self.read_buffer_size < 512 {
+            return Err(ConfigError::new("read_buffer_size", "must be at least 512 bytes"));
+        }
+
+        if self.max_request_line < 256 {
+            return Err(ConfigError::new("max_request_line", "must be at least 256 bytes"));
+        }
+
+        if self.max_header_size < 1024 {
+            return Err(ConfigError::new("max_header_size", "must be at least 1024 bytes"));
+        }
+
+        if self.max_header_size < self.max_request_line {
+            return Err(ConfigError::new("max_header_size", "must be at least max_request_line"));
+        }
+
+        if self.max_message_size == 0 {
+            return Err(ConfigError::new("max_message_size", "must be at least 1 byte"));
+        }
+
+        if self.header_timeout == Duration::from_secs(0) {
+            return Err(ConfigError::new("header_timeout", "must be non-zero"));
+        }
+
+        if self.body_timeout == Duration::from_secs(0) {
+            return Err(ConfigError::new("body_timeout", "must be non-zero"));
+        }
+
+        if self.write_timeout == Duration::from_secs(0) {
+            return Err(ConfigError::new("write_timeout", "must be non-zero"));
+        }
+
+        if self.handler_timeout == Some(Duration::from_secs(0)) {
+            return Err(ConfigError::new("handler_timeout", "must be non-zero, or None to disable it"));
+        }
+
+        if self.keep_alive == Some(Duration::from_secs(0)) {
+            return Err(ConfigError::new("keep_alive", "must be non-zero, or None to disable it"));
+        }
+
+        if self.event_keep_alive == Duration::from_secs(0) {
+            return Err(ConfigError::new("event_keep_alive", "must be non-zero"));
+        }
+
+        if self.backlog == 0 || self.backlog > i32::MAX as u32 {
+            return Err(ConfigError::new("backlog", "must be between 1 and 2^31 - 1"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds a Canteen server with non-default settings.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::time::Duration;
+/// use canteen::Canteen;
+///
+/// let cnt = Canteen::builder()
+///     .workers(16)
+///     .max_connections(4096)
+///     .max_body_size(1024 * 1024)
+///     .keep_alive(Some(Duration::from_secs(15)))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CanteenBuilder {
+    config: ServerConfig,
+}
+
+impl CanteenBuilder {
+    /// Create a builder starting from the default configuration.
+    pub fn new() -> CanteenBuilder {
+        CanteenBuilder::default()
+    }
+
+    /// Create a builder starting from an existing configuration.
+    pub fn from_config(config: ServerConfig) -> CanteenBuilder {
+        CanteenBuilder { config }
+    }
+
+    /// Sets the number of threadpool workers running synchronous handlers.
+    pub fn workers(mut self, workers: usize) -> CanteenBuilder {
+        self.config.workers = workers;
+        self
+    }
+
+    /// Sets the number of threads polling async handlers' futures.
+    pub fn async_threads(mut self, threads: usize) -> CanteenBuilder {
+        self.config.async_threads = threads;
+        self
+    }
+
+    /// Sets the maximum number of simultaneously open client connections.
+    pub fn max_connections(mut self, max: usize) -> CanteenBuilder {
+        self.config.max_connections = max;
+        self
+    }
+
+    /// Sets the maximum number of simultaneously open connections from a
+    /// single IP address. `None` removes the limit.
+    pub fn max_connections_per_ip(mut self, max: Option<usize>) -> CanteenBuilder {
+        self.config.max_connections_per_ip = max;
+        self
+    }
+
+    /// Sets the maximum number of requests waiting for a worker.
+    pub fn max_queued_jobs(mut self, max: usize) -> CanteenBuilder {
+        self.config.max_queued_jobs = max;
+        self
+    }
+
+    /// Sets the delay suggested to clients turned away while the server is
+    /// overloaded.
+    pub fn retry_after(mut self, delay: Duration) -> CanteenBuilder {
+        self.config.retry_after = delay;
+        self
+    }
+
+    /// Sets the size of each read from a client socket, in bytes.
+    pub fn read_buffer_size(mut self, size: usize) -> CanteenBuilder {
+        self.config.read_buffer_size = size;
+        self
+    }
+
+    /// Sets the maximum length of a request line, in bytes.
+    pub fn max_request_line(mut self, size: usize) -> CanteenBuilder {
+        self.config.max_request_line = size;
+        self
+    }
+
+    /// Sets the maximum size of a request's line and headers, in bytes.
+    pub fn max_header_size(mut self, size: usize) -> CanteenBuilder {
+        self.config.max_header_size = size;
+        self
+    }
+
+    /// Sets the maximum size of a request's body, in bytes.
+    pub fn max_body_size(mut self, size: usize) -> CanteenBuilder {
+        self.config.max_body_size = size;
+        self
+    }
+
+    /// Sets the maximum size of a message received on a WebSocket, in bytes.
+    pub fn max_message_size(mut self, size: usize) -> CanteenBuilder {
+        self.config.max_message_size = size;
+        self
+    }
+
+    /// Sets how long a client has to send a request's line and headers.
+    pub fn header_timeout(mut self, timeout: Duration) -> CanteenBuilder {
+        self.config.header_timeout = timeout;
+        self
+    }
+
+    /// Sets how long a client has to send a request's body.
+    pub fn body_timeout(mut self, timeout: Duration) -> CanteenBuilder {
+        self.config.body_timeout = timeout;
+        self
+    }
+
+    /// Sets how long a client may go without reading any of a response.
+    pub fn write_timeout(mut self, timeout: Duration) -> CanteenBuilder {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    /// Sets how long a handler has to respond before the client is sent a
+    /// 504. `None`, the default, waits indefinitely.
+    pub fn handler_timeout(mut self, timeout: Option<Duration>) -> CanteenBuilder {
+        self.config.handler_timeout = timeout;
+        self
+    }
+
+    /// Sets how long an idle connection is kept open between requests. `None`,
+    /// the default, closes every connection once its response has been sent.
+    pub fn keep_alive(mut self, timeout: Option<Duration>) -> CanteenBuilder {
+        self.config.keep_alive = timeout;
+        self
+    }
+
+    /// Sets how often a comment is sent on an otherwise quiet event stream.
+    pub fn event_keep_alive(mut self, interval: Duration) -> CanteenBuilder {
+        self.config.event_keep_alive = interval;
+        self
+    }
+
+    /// Sets the length of the listening socket's queue of pending connections.
+    pub fn backlog(mut self, backlog: u32) -> CanteenBuilder {
+        self.config.backlog = backlog;
+        self
+    }
+
+    /// Sets how long a shutdown waits for in-flight requests to be answered.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> CanteenBuilder {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Validates the configuration and creates the Canteen instance.
+    pub fn build(self) -> Result<Canteen, ConfigError> {
+        self.config.validate()?;
+
+        Ok(Canteen::with_config(self.config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config_is_valid() {
+        let config = ServerConfig::default();
+
+        assert!(config.validate().is_ok());
+        assert!(config.workers >= 8);
+        assert!(config.max_connections >= 2048);
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        let err = CanteenBuilder::new().max_connections(0).build().err().unwrap();
+        assert_eq!("max_connections", err.field);
+
+        let err = CanteenBuilder::new().max_connections_per_ip(Some(0)).build().err().unwrap();
+        assert_eq!("max_connections_per_ip", err.field);
+
+        let err = CanteenBuilder::new().read_buffer_size(16).build().err().unwrap();
+        assert_eq!("read_buffer_size", err.field);
+
+        let err = CanteenBuilder::new().max_message_size(0).build().err().unwrap();
+        assert_eq!("max_message_size", err.field);
+
+        let err = CanteenBuilder::new().header_timeout(Duration::from_secs(0)).build().err().unwrap();
+        assert_eq!("header_timeout", err.field);
+
+        let err = CanteenBuilder::new().keep_alive(Some(Duration::from_secs(0))).build().err().unwrap();
+        assert_eq!("keep_alive", err.field);
+
+        let err = CanteenBuilder::new().handler_timeout(Some(Duration::from_secs(0))).build().err().unwrap();
+        assert_eq!("handler_timeout", err.field);
+
+        assert!(CanteenBuilder::new().workers(4).keep_alive(None).build().is_ok());
+    }
+}
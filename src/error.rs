@@ -0,0 +1,128 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+use std::fmt;
+use std::io;
+
+use crate::request::Request;
+use crate::response::Response;
+
+/// The signature of a function that renders an error response for a given
+/// HTTP status. Register one with `Canteen::error_handler`.
+pub type ErrorHandler = fn(&Request, &ErrorContext) -> Response;
+
+/// Describes an error that occurred while servicing a request. This is passed
+/// to the error handler registered for its status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorContext {
+    pub status:  u16,
+    pub message: String,
+}
+
+impl ErrorContext {
+    /// Create a new ErrorContext for the given status.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::ErrorContext;
+    ///
+    /// let ctx = ErrorContext::new(404, "not found");
+    /// assert_eq!(404, ctx.status);
+    /// ```
+    pub fn new<T: Into<String>>(status: u16, message: T) -> ErrorContext {
+        ErrorContext {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.status, self.message)
+    }
+}
+
+/// Describes a server setting that failed validation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub field:  &'static str,
+    pub reason: String,
+}
+
+impl ConfigError {
+    pub(crate) fn new<T: Into<String>>(field: &'static str, reason: T) -> ConfigError {
+        ConfigError {
+            field,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid {}: {}", self.field, self.reason)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// An error that prevented a Canteen server from starting or from continuing
+/// to accept connections.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The address passed to `bind` couldn't be resolved.
+    Resolve(io::Error),
+    /// The listening socket couldn't be created or bound.
+    Bind(io::Error),
+    /// `run` was called before `bind`.
+    NotBound,
+    /// The event loop couldn't be created, or failed while running.
+    EventLoop(io::Error),
+    /// The listening socket failed while accepting a connection.
+    Accept(io::Error),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerError::Resolve(ref err)   => write!(f, "unable to resolve address: {}", err),
+            ServerError::Bind(ref err)      => write!(f, "unable to bind listener: {}", err),
+            ServerError::NotBound           => write!(f, "server not bound to an address"),
+            ServerError::EventLoop(ref err) => write!(f, "event loop failure: {}", err),
+            ServerError::Accept(ref err)    => write!(f, "unable to accept connections: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ServerError::Resolve(ref err)   => Some(err),
+            ServerError::Bind(ref err)      => Some(err),
+            ServerError::NotBound           => None,
+            ServerError::EventLoop(ref err) => Some(err),
+            ServerError::Accept(ref err)    => Some(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_server_error_display() {
+        let err = ServerError::Bind(io::Error::new(io::ErrorKind::AddrInUse, "address in use"));
+
+        assert_eq!("unable to bind listener: address in use", format!("{}", err));
+        assert!(err.source().is_some());
+        assert!(ServerError::NotBound.source().is_none());
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+//! The event loop driving every connection, built on `mio::Poll`. Besides
+//! socket readiness it delivers timeouts, which mio doesn't provide, and
+//! notices sent from other threads, which wake the poll through a `Waker`.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SendError};
+use std::time::{Duration, Instant};
+
+use mio::event::{Event, Source};
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use crate::client::Notice;
+
+/// The token the `Waker` is registered under. No socket uses it.
+pub(crate) const WAKER: Token = Token(0);
+
+// how many socket events are taken from the poll at once
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Reacts to whatever the event loop has to deliver.
+pub(crate) trait Handler {
+    /// A registered socket is ready.
+    fn ready(&mut self, evl: &mut EventLoop, token: Token, event: &Event);
+
+    /// A notice has arrived from another thread.
+    fn notify(&mut self, evl: &mut EventLoop, notice: Notice);
+
+    /// A timeout set with `EventLoop::timeout` has passed.
+    fn timeout(&mut self, evl: &mut EventLoop, token: Token);
+}
+
+/// Sends messages to the event loop from any thread, waking it up to handle
+/// them.
+pub(crate) struct Sender<M> {
+    tx:    mpsc::Sender<M>,
+    waker: Arc<Waker>,
+}
+
+impl<M> Sender<M> {
+    pub fn send(&self, msg: M) -> Result<(), SendError<M>> {
+        self.tx.send(msg)?;
+
+        // if this fails the message still arrives, just not straight away
+        let _ = self.waker.wake();
+        Ok(())
+    }
+}
+
+impl<M> Clone for Sender<M> {
+    fn clone(&self) -> Sender<M> {
+        Sender { tx: self.tx.clone(), waker: self.waker.clone() }
+    }
+}
+
+/// Identifies a pending timeout, so that it can be cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Timeout(Instant, u64);
+
+/// Pending timeouts, in the order they fall due.
+#[derive(Debug, Default)]
+struct Timers {
+    queue: BTreeMap<Timeout, Token>,
+    next:  u64,
+}
+
+impl Timers {
+    fn add(&mut self, token: Token, at: Instant) -> Timeout {
+        let timeout = Timeout(at, self.next);
+
+        self.next += 1;
+        self.queue.insert(timeout, token);
+        timeout
+    }
+
+    fn remove(&mut self, timeout: Timeout) {
+        self.queue.remove(&timeout);
+    }
+
+    // how long until the next timeout falls due
+    fn delay(&self, now: Instant) -> Option<Duration> {
+        self.queue.first_key_value().map(|(&Timeout(at, _), _)| at.saturating_duration_since(now))
+    }
+
+    // take every timeout that has fallen due
+    fn expired(&mut self, now: Instant) -> Vec<Token> {
+        let mut tokens = Vec::new();
+
+        while let Some(entry) = self.queue.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+
+            tokens.push(entry.remove());
+        }
+
+        tokens
+    }
+}
+
+pub(crate) struct EventLoop {
+    poll:    Poll,
+    tx:      Sender<Notice>,
+    rx:      Receiver<Notice>,
+    timers:  Timers,
+    running: bool,
+}
+
+impl EventLoop {
+    pub fn new() -> io::Result<EventLoop> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (tx, rx) = mpsc::channel();
+
+        Ok(EventLoop {
+            poll,
+            tx:      Sender { tx, waker },
+            rx,
+            timers:  Timers::default(),
+            running: false,
+        })
+    }
+
+    /// A sender for notices to the loop's handler.
+    pub fn channel(&self) -> Sender<Notice> {
+        self.tx.clone()
+    }
+
+    pub fn register<S: Source + ?Sized>(&self, source: &mut S, token: Token, interest: Interest) -> io::Result<()> {
+        self.poll.registry().register(source, token, interest)
+    }
+
+    pub fn reregister<S: Source + ?Sized>(&self, source: &mut S, token: Token, interest: Interest) -> io::Result<()> {
+        self.poll.registry().reregister(source, token, interest)
+    }
+
+    pub fn deregister<S: Source + ?Sized>(&self, source: &mut S) -> io::Result<()> {
+        self.poll.registry().deregister(source)
+    }
+
+    /// Call the handler's `timeout` for `token` once `delay` has passed.
+    pub fn timeout(&mut self, token: Token, delay: Duration) -> Timeout {
+        self.timers.add(token, Instant::now() + delay)
+    }
+
+    pub fn clear_timeout(&mut self, timeout: Timeout) {
+        self.timers.remove(timeout);
+    }
+
+    /// Stop the loop once it has finished handling the current events.
+    pub fn shutdown(&mut self) {
+        self.running = false;
+    }
+
+    /// Deliver events to `handler` until `shutdown` is called.
+    pub fn run<H: Handler>(&mut self, handler: &mut H) -> io::Result<()> {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+
+        self.running = true;
+
+        while self.running {
+            let delay = self.timers.delay(Instant::now());
+
+            match self.poll.poll(&mut events, delay) {
+                Ok(())                                                  => {},
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err)                                                => return Err(err),
+            }
+
+            for event in events.iter() {
+                if event.token() != WAKER {
+                    handler.ready(self, event.token(), event);
+                }
+            }
+
+            while let Ok(notice) = self.rx.try_recv() {
+                handler.notify(self, notice);
+            }
+
+            for token in self.timers.expired(Instant::now()) {
+                handler.timeout(self, token);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timers() {
+        let now = Instant::now();
+        let mut timers = Timers::default();
+
+        assert_eq!(None, timers.delay(now));
+
+        let late = timers.add(Token(3), now + Duration::from_secs(5));
+        let first = timers.add(Token(4), now + Duration::from_secs(1));
+        let cleared = timers.add(Token(5), now + Duration::from_secs(2));
+
+        timers.remove(cleared);
+        assert!(late > first);
+        assert_eq!(Some(Duration::from_secs(1)), timers.delay(now));
+
+        assert_eq!(Vec::<Token>::new(), timers.expired(now));
+        assert_eq!(vec![Token(4)], timers.expired(now + Duration::from_secs(3)));
+        assert_eq!(vec![Token(3)], timers.expired(now + Duration::from_secs(5)));
+        assert_eq!(None, timers.delay(now));
+    }
+
+    #[test]
+    fn test_sender_wakes_loop() {
+        let mut evl = EventLoop::new().unwrap();
+        let mut events = Events::with_capacity(8);
+
+        let _ = evl.channel().send(Notice::Frame(Token(2), 1, Vec::new(), false));
+
+        evl.poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(Some(WAKER), events.iter().next().map(|event| event.token()));
+        assert!(evl.rx.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+//! A small executor for async route handlers. Tasks are polled by a handful of
+//! dedicated threads, separate from the threadpool that runs synchronous
+//! handlers, so a handler awaiting a slow upstream doesn't pin a worker.
+//! Dropping the executor stops its threads.
+
+use std::future::Future;
+use std::panic;
+use std::pin::Pin;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, JoinHandle};
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// the sending end of the run queue, shared by the executor and its tasks so
+// that taking it closes the queue for all of them
+type Queue = Arc<Mutex<Option<Sender<Arc<Task>>>>>;
+
+struct Task {
+    future: Mutex<Option<BoxFuture>>,
+    queue:  Queue,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        let queue = self.queue.lock().unwrap().clone();
+
+        // tasks woken once the executor has stopped are dropped
+        if let Some(queue) = queue {
+            let _ = queue.send(self);
+        }
+    }
+}
+
+pub(crate) struct Executor {
+    queue:   Queue,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Executor {
+    /// Start an executor polling tasks on `threads` threads.
+    pub(crate) fn new(threads: usize) -> Executor {
+        let (queue, rx) = channel::<Arc<Task>>();
+        let rx = Arc::new(Mutex::new(rx));
+        let workers = (0..threads).map(|i| {
+            let rx = rx.clone();
+
+            thread::Builder::new()
+                .name(format!("canteen-async-{}", i))
+                .spawn(move || Executor::work(rx))
+                .expect("unable to start async executor thread")
+        }).collect();
+
+        Executor { queue: Arc::new(Mutex::new(Some(queue))), workers }
+    }
+
+    /// Queue a future to be driven to completion.
+    pub(crate) fn spawn<F: Future<Output = ()> + Send + 'static>(&self, future: F) {
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(future))),
+            queue:  self.queue.clone(),
+        });
+
+        if let Some(ref queue) = *self.queue.lock().unwrap() {
+            let _ = queue.send(task);
+        }
+    }
+
+    fn work(rx: Arc<Mutex<Receiver<Arc<Task>>>>) {
+        loop {
+            let task = match rx.lock().unwrap().recv() {
+                Ok(task) => task,
+                Err(_)   => return,
+            };
+
+            let waker = Waker::from(task.clone());
+            let mut cx = Context::from_waker(&waker);
+            let mut slot = task.future.lock().unwrap();
+
+            // a task woken after it completed has nothing left to poll
+            if let Some(mut future) = slot.take() {
+                if future.as_mut().poll(&mut cx).is_pending() {
+                    *slot = Some(future);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Executor {
+    // close the queue and wait for the threads to finish the tasks already
+    // on it. tasks still waiting to be woken are abandoned.
+    fn drop(&mut self) {
+        self.queue.lock().unwrap().take();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Wraps a future so that a panic while polling it resolves the future to an
+/// `Err`, rather than unwinding through the executor.
+pub(crate) struct CatchUnwind<F>(pub F);
+
+impl<F: Future + Unpin> Future for CatchUnwind<F> {
+    type Output = thread::Result<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let inner = &mut self.0;
+
+        match panic::catch_unwind(panic::AssertUnwindSafe(|| Pin::new(inner).poll(cx))) {
+            Ok(Poll::Pending)    => Poll::Pending,
+            Ok(Poll::Ready(val)) => Poll::Ready(Ok(val)),
+            Err(err)             => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    // a future that is pending until another thread flips its flag and wakes it
+    struct Flag {
+        set:   Arc<AtomicBool>,
+        waker: Arc<Mutex<Option<Waker>>>,
+    }
+
+    impl Future for Flag {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.set.load(Ordering::SeqCst) {
+                return Poll::Ready(());
+            }
+
+            *self.waker.lock().unwrap() = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_executor_wakes_pending_tasks() {
+        let exec = Executor::new(2);
+        let set = Arc::new(AtomicBool::new(false));
+        let waker: Arc<Mutex<Option<Waker>>> = Arc::new(Mutex::new(None));
+        let (tx, rx) = channel();
+
+        let flag = Flag { set: set.clone(), waker: waker.clone() };
+        exec.spawn(async move {
+            flag.await;
+            tx.send(42).unwrap();
+        });
+
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        set.store(true, Ordering::SeqCst);
+        loop {
+            if let Some(w) = waker.lock().unwrap().take() {
+                w.wake();
+                break;
+            }
+            thread::yield_now();
+        }
+
+        assert_eq!(42, rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    fn test_catch_unwind() {
+        let exec = Executor::new(1);
+        let (tx, rx) = channel();
+
+        exec.spawn(async move {
+            let fut: Pin<Box<dyn Future<Output = u32> + Send>> = Box::pin(async { panic!("boom") });
+            tx.send(CatchUnwind(fut).await.is_err()).unwrap();
+        });
+
+        assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    fn test_executor_drop_joins_threads() {
+        let exec = Executor::new(2);
+        let waker: Arc<Mutex<Option<Waker>>> = Arc::new(Mutex::new(None));
+        let (tx, rx) = channel();
+
+        // one task that finishes, and one that waits forever
+        exec.spawn(async move { tx.send(1).unwrap() });
+        exec.spawn(Flag { set: Arc::new(AtomicBool::new(false)), waker: waker.clone() });
+
+        assert_eq!(1, rx.recv_timeout(Duration::from_secs(5)).unwrap());
+
+        while waker.lock().unwrap().is_none() {
+            thread::yield_now();
+        }
+
+        // the waiting task doesn't hold the threads up, and waking it once
+        // they've gone does nothing
+        drop(exec);
+        waker.lock().unwrap().take().unwrap().wake();
+    }
+}
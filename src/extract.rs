@@ -0,0 +1,416 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+//! Typed handler arguments.
+//!
+//! Instead of taking a `&Request` and pulling values out of it by hand, a
+//! handler can take up to six arguments that implement `FromRequest`. Each one
+//! is extracted before the handler runs; if any of them fails, the handler is
+//! skipped and the client receives the error (usually a 400) from the error
+//! handler registered for that status.
+//!
+//! ```rust
+//! use canteen::{Canteen, Method, Json};
+//! use canteen::extract::{Path, Query, State};
+//! use serde_derive::Deserialize;
+//! use std::collections::HashMap;
+//!
+//! #[derive(Deserialize)]
+//! struct Paging {
+//!     page: u32,
+//! }
+//!
+//! fn list(Path((user_id, kind)): Path<(i32, String)>, Query(paging): Query<Paging>,
+//!         State(names): State<HashMap<i32, String>>) -> String {
+//!     let name = names.get(&user_id).cloned().unwrap_or_default();
+//!     format!("{}'s {} on page {}", name, kind, paging.page)
+//! }
+//!
+//! let mut cnt = Canteen::new();
+//! cnt.manage(HashMap::<i32, String>::new());
+//! cnt.add_route("/user/<int:user_id>/<str:kind>", &[Method::Get], list);
+//! ```
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+
+use crate::error::ErrorContext;
+use crate::request::{FromUri, Request};
+use crate::response::{IntoResponse, Json, Response};
+
+/// Shared application state registered with `Canteen::manage`, keyed by type.
+#[derive(Clone, Default)]
+pub(crate) struct StateMap(HashMap<TypeId, Arc<dyn Any + Send + Sync>>);
+
+impl StateMap {
+    pub(crate) fn insert<T: Send + Sync + 'static>(&mut self, state: T) {
+        self.0.insert(TypeId::of::<T>(), Arc::new(state));
+    }
+
+    pub(crate) fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.0.get(&TypeId::of::<T>())
+              .and_then(|state| state.clone().downcast::<T>().ok())
+    }
+}
+
+impl fmt::Debug for StateMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StateMap({} entries)", self.0.len())
+    }
+}
+
+/// A trait for types that can be extracted from an incoming request and
+/// passed to a handler as an argument.
+pub trait FromRequest: Sized {
+    /// Extract the value, or describe why the request can't supply it.
+    fn from_request(req: &Request) -> Result<Self, ErrorContext>;
+}
+
+/// Optional extraction: yields `None` rather than rejecting the request.
+impl<T: FromRequest> FromRequest for Option<T> {
+    fn from_request(req: &Request) -> Result<Self, ErrorContext> {
+        Ok(T::from_request(req).ok())
+    }
+}
+
+/// A trait for types that can be built from a route's path variables, in the
+/// order they appear in the route definition.
+pub trait FromPath: Sized {
+    fn from_path(values: &[&str]) -> Result<Self, ErrorContext>;
+}
+
+fn path_value<T: FromUri>(values: &[&str], idx: usize) -> Result<T, ErrorContext> {
+    match values.get(idx) {
+        Some(val) => T::try_from_uri(val).map_err(|err| ErrorContext::new(400, format!("invalid path variable: {}", err))),
+        None      => Err(ErrorContext::new(500, format!("route has no path variable #{}", idx + 1))),
+    }
+}
+
+impl<T: FromUri> FromPath for T {
+    fn from_path(values: &[&str]) -> Result<Self, ErrorContext> {
+        path_value(values, 0)
+    }
+}
+
+macro_rules! impl_from_path {
+    ($($ty:ident => $idx:tt),+) => {
+        impl<$($ty: FromUri),+> FromPath for ($($ty,)+) {
+            fn from_path(values: &[&str]) -> Result<Self, ErrorContext> {
+                Ok(($(path_value::<$ty>(values, $idx)?,)+))
+            }
+        }
+    };
+}
+
+impl_from_path!(A => 0);
+impl_from_path!(A => 0, B => 1);
+impl_from_path!(A => 0, B => 1, C => 2);
+impl_from_path!(A => 0, B => 1, C => 2, D => 3);
+impl_from_path!(A => 0, B => 1, C => 2, D => 3, E => 4);
+impl_from_path!(A => 0, B => 1, C => 2, D => 3, E => 4, F => 5);
+
+/// Extracts the variables defined in the route's path, such as
+/// `Path<(i32, String)>` for the route `/user/<int:id>/<str:name>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path<T>(pub T);
+
+impl<T: FromPath> FromRequest for Path<T> {
+    fn from_request(req: &Request) -> Result<Self, ErrorContext> {
+        let values: Vec<&str> = req.param_order.iter()
+                                               .filter_map(|name| req.params.get(name))
+                                               .map(|val| val.as_str())
+                                               .collect();
+
+        Ok(Path(T::from_path(&values)?))
+    }
+}
+
+/// Deserializes the URI's query string, e.g. `?page=2&per_page=20`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(req: &Request) -> Result<Self, ErrorContext> {
+        match serde_urlencoded::from_str(&req.query) {
+            Ok(val)  => Ok(Query(val)),
+            Err(err) => Err(ErrorContext::new(400, format!("invalid query string: {}", err))),
+        }
+    }
+}
+
+/// Deserializes a JSON request body.
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(req: &Request) -> Result<Self, ErrorContext> {
+        match req.get_json_obj() {
+            Ok(val)  => Ok(Json(val)),
+            Err(err) => Err(ErrorContext::new(400, format!("invalid JSON body: {}", err))),
+        }
+    }
+}
+
+/// Deserializes an `application/x-www-form-urlencoded` request body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Form<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Form<T> {
+    fn from_request(req: &Request) -> Result<Self, ErrorContext> {
+        match serde_urlencoded::from_bytes(&req.payload) {
+            Ok(val)  => Ok(Form(val)),
+            Err(err) => Err(ErrorContext::new(400, format!("invalid form body: {}", err))),
+        }
+    }
+}
+
+/// Names a request header for use with the `Header` extractor. Implement it
+/// with the `header_name!` macro.
+pub trait HeaderName {
+    const NAME: &'static str;
+}
+
+/// Defines a marker type naming a header, for use with `Header`.
+///
+/// # Examples
+///
+/// ```rust
+/// use canteen::header_name;
+/// use canteen::extract::Header;
+///
+/// header_name!(XRequestId, "X-Request-Id");
+///
+/// fn handler(Header(id, ..): Header<XRequestId>) -> String {
+///     format!("request {}", id)
+/// }
+/// ```
+#[macro_export]
+macro_rules! header_name {
+    ($name:ident, $header:expr) => {
+        pub struct $name;
+
+        impl $crate::extract::HeaderName for $name {
+            const NAME: &'static str = $header;
+        }
+    };
+}
+
+/// Extracts the value of the header named by `N`, rejecting the request if
+/// it is missing. Wrap it in an `Option` to make the header optional.
+pub struct Header<N: HeaderName>(pub String, pub PhantomData<N>);
+
+impl<N: HeaderName> Deref for Header<N> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<N: HeaderName> fmt::Debug for Header<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Header({}: {:?})", N::NAME, self.0)
+    }
+}
+
+impl<N: HeaderName> FromRequest for Header<N> {
+    fn from_request(req: &Request) -> Result<Self, ErrorContext> {
+        match req.get_header(N::NAME) {
+            Some(val) => Ok(Header(val, PhantomData)),
+            None      => Err(ErrorContext::new(400, format!("missing header {}", N::NAME))),
+        }
+    }
+}
+
+/// Shared application state of type `T`, registered with `Canteen::manage`.
+#[derive(Debug)]
+pub struct State<T>(pub Arc<T>);
+
+impl<T> Clone for State<T> {
+    fn clone(&self) -> Self {
+        State(self.0.clone())
+    }
+}
+
+impl<T> Deref for State<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Send + Sync + 'static> FromRequest for State<T> {
+    fn from_request(req: &Request) -> Result<Self, ErrorContext> {
+        match req.state.get::<T>() {
+            Some(state) => Ok(State(state)),
+            None        => Err(ErrorContext::new(500, format!("no state of type {} is managed",
+                                                              std::any::type_name::<T>()))),
+        }
+    }
+}
+
+/// A trait implemented for every function that can be used as a route
+/// handler: functions taking a `&Request`, and functions taking up to six
+/// `FromRequest` arguments. The `Args` parameter only exists to tell the two
+/// apart and can always be inferred.
+pub trait HandlerFn<Args>: Send + Sync + 'static {
+    /// Extract the arguments from the request and run the handler.
+    fn call(&self, req: &Request) -> Result<Response, ErrorContext>;
+}
+
+impl<F, R> HandlerFn<fn(&Request) -> R> for F
+        where F: Fn(&Request) -> R + Send + Sync + 'static,
+              R: IntoResponse {
+    fn call(&self, req: &Request) -> Result<Response, ErrorContext> {
+        Ok(self(req).into_response())
+    }
+}
+
+impl<F, R> HandlerFn<fn() -> R> for F
+        where F: Fn() -> R + Send + Sync + 'static,
+              R: IntoResponse {
+    fn call(&self, _: &Request) -> Result<Response, ErrorContext> {
+        Ok(self().into_response())
+    }
+}
+
+macro_rules! impl_handler_fn {
+    ($($arg:ident),+) => {
+        impl<F, R, $($arg),+> HandlerFn<fn($($arg),+) -> R> for F
+                where F: Fn($($arg),+) -> R + Send + Sync + 'static,
+                      R: IntoResponse,
+                      $($arg: FromRequest),+ {
+            #[allow(non_snake_case)]
+            fn call(&self, req: &Request) -> Result<Response, ErrorContext> {
+                $(let $arg = <$arg as FromRequest>::from_request(req)?;)+
+
+                Ok(self($($arg),+).into_response())
+            }
+        }
+    };
+}
+
+impl_handler_fn!(A);
+impl_handler_fn!(A, B);
+impl_handler_fn!(A, B, C);
+impl_handler_fn!(A, B, C, D);
+impl_handler_fn!(A, B, C, D, E);
+impl_handler_fn!(A, B, C, D, E, G);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use crate::route::Route;
+    use crate::request::Method;
+
+    header_name!(XFoo, "X-Foo");
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Paging {
+        page:     u32,
+        per_page: Option<u32>,
+    }
+
+    fn request(rqstr: &str, route: &Route) -> Request {
+        let mut req = Request::from_str(rqstr).unwrap();
+
+        req.params = route.parse(&req.path);
+        req.param_order = route.param_names();
+
+        req
+    }
+
+    fn status_of<H: HandlerFn<Args>, Args>(handler: H, req: &Request) -> u16 {
+        match handler.call(req) {
+            Ok(res)  => res.get_status(),
+            Err(err) => err.status,
+        }
+    }
+
+    #[test]
+    fn test_extract_path_tuple() {
+        let rt = Route::new("/user/<int:id>/<str:name>", Method::Get, |_: &Request| "");
+        let req = request("GET /user/42/bob HTTP/1.1\r\n\r\n", &rt);
+        let Path((id, name)): Path<(i32, String)> = Path::from_request(&req).unwrap();
+
+        assert_eq!(42, id);
+        assert_eq!("bob", name);
+    }
+
+    #[test]
+    fn test_extract_path_invalid() {
+        let rt = Route::new("/user/<str:id>", Method::Get, |_: &Request| "");
+        let req = request("GET /user/bob HTTP/1.1\r\n\r\n", &rt);
+
+        assert_eq!(400, Path::<i32>::from_request(&req).unwrap_err().status);
+    }
+
+    #[test]
+    fn test_extract_query() {
+        let req = Request::from_str("GET /items?page=2 HTTP/1.1\r\n\r\n").unwrap();
+        let Query(paging): Query<Paging> = Query::from_request(&req).unwrap();
+
+        assert_eq!(Paging { page: 2, per_page: None }, paging);
+
+        let bad = Request::from_str("GET /items?page=two HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(400, Query::<Paging>::from_request(&bad).unwrap_err().status);
+    }
+
+    #[test]
+    fn test_extract_form() {
+        let req = Request::from_str("POST /items HTTP/1.1\r\n\r\npage=3&per_page=10").unwrap();
+        let Form(paging): Form<Paging> = Form::from_request(&req).unwrap();
+
+        assert_eq!(Paging { page: 3, per_page: Some(10) }, paging);
+    }
+
+    #[test]
+    fn test_extract_header() {
+        let req = Request::from_str("GET / HTTP/1.1\r\nX-Foo: bar\r\n\r\n").unwrap();
+        let missing = Request::from_str("GET / HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_eq!("bar", &*Header::<XFoo>::from_request(&req).unwrap());
+        assert_eq!(400, Header::<XFoo>::from_request(&missing).unwrap_err().status);
+        assert!(Option::<Header<XFoo>>::from_request(&missing).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_extract_state() {
+        let mut req = Request::new();
+        let mut state = StateMap::default();
+
+        assert_eq!(500, State::<String>::from_request(&req).unwrap_err().status);
+
+        state.insert(String::from("shared"));
+        req.state = Arc::new(state);
+
+        assert_eq!("shared", State::<String>::from_request(&req).unwrap().as_str());
+    }
+
+    #[test]
+    fn test_handler_fn_arities() {
+        fn plain(_: &Request) -> Response { Response::new() }
+        fn none() -> &'static str { "none" }
+        fn two(Path(id): Path<i32>, Json(val): Json<serde_json::Value>) -> (u16, String) {
+            (201, format!("{} {}", id, val))
+        }
+
+        let rt = Route::new("/item/<int:id>", Method::Post, two);
+        let good = request("POST /item/7 HTTP/1.1\r\n\r\n{\"a\": 1}", &rt);
+        let bad = request("POST /item/7 HTTP/1.1\r\n\r\nnope", &rt);
+
+        assert_eq!(200, status_of(plain, &good));
+        assert_eq!(200, status_of(none, &good));
+        assert_eq!(201, status_of(two, &good));
+        assert_eq!(400, status_of(two, &bad));
+    }
+}
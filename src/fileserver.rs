@@ -0,0 +1,406 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+//! Serving a directory of files under a URL prefix. A mount is added with
+//! `Canteen::mount_static`, and `StaticOptions` controls what it exposes.
+//!
+//! ```rust
+//! use canteen::Canteen;
+//! use canteen::fileserver::{StaticOptions, Symlinks};
+//!
+//! let mut cnt = Canteen::new();
+//!
+//! // "/assets/app.js" is served from "./public/app.js"
+//! cnt.mount_static("/assets", "./public", StaticOptions::new())
+//!    .mount_static("/downloads", "/srv/files", StaticOptions::new().listing(true)
+//!                                                                  .symlinks(Symlinks::Deny));
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::request::Request;
+use crate::response::{IntoResponse, Redirect, Response};
+use crate::utils;
+
+/// What a static mount does with symbolic links in its directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Follow every link, wherever it leads.
+    Follow,
+    /// Follow links that lead to somewhere inside the mounted directory.
+    WithinRoot,
+    /// Serve nothing that's reached through a link.
+    Deny,
+}
+
+/// How a static mount serves its directory.
+///
+/// By default a directory is served by its `index.html`, directories without
+/// one aren't listed, hidden files (those whose names start with a dot)
+/// aren't served, symbolic links are only followed if they lead to
+/// somewhere inside the mounted directory, files are sent with strong entity
+/// tags, and precompressed copies of files aren't looked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticOptions {
+    index:    Vec<String>,
+    listing:  bool,
+    hidden:   bool,
+    symlinks: Symlinks,
+    pub(crate) weak:          bool,
+    pub(crate) precompressed: bool,
+}
+
+impl Default for StaticOptions {
+    fn default() -> StaticOptions {
+        StaticOptions {
+            index:    vec![String::from("index.html")],
+            listing:  false,
+            hidden:   false,
+            symlinks: Symlinks::WithinRoot,
+            weak:     false,
+            precompressed: false,
+        }
+    }
+}
+
+impl StaticOptions {
+    /// Create the default options.
+    pub fn new() -> StaticOptions {
+        StaticOptions::default()
+    }
+
+    /// The files to serve for a directory, in order of preference. Pass an
+    /// empty list to never serve an index file.
+    pub fn index_files(mut self, names: &[&str]) -> StaticOptions {
+        self.index = names.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    /// Whether a directory without an index file is served as a list of
+    /// its contents, rather than a 404.
+    pub fn listing(mut self, listing: bool) -> StaticOptions {
+        self.listing = listing;
+        self
+    }
+
+    /// Whether hidden files and directories are served and listed.
+    pub fn hidden(mut self, hidden: bool) -> StaticOptions {
+        self.hidden = hidden;
+        self
+    }
+
+    /// What to do with symbolic links.
+    pub fn symlinks(mut self, symlinks: Symlinks) -> StaticOptions {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Whether files are sent with weak entity tags, rather than strong
+    /// ones. Weak tags can't be used to ask for ranges of a file.
+    pub fn weak_etags(mut self, weak: bool) -> StaticOptions {
+        self.weak = weak;
+        self
+    }
+
+    /// Whether a file is sent as its Brotli or gzip compressed copy, such as
+    /// `app.js.br` or `app.js.gz` for `app.js`, to clients that accept it.
+    /// Copies that are symbolic links are ignored.
+    pub fn precompressed(mut self, precompressed: bool) -> StaticOptions {
+        self.precompressed = precompressed;
+        self
+    }
+}
+
+/// A directory served under a URL prefix.
+pub(crate) struct Mount {
+    // the prefix, without a trailing slash, so "" for the root
+    prefix:  String,
+    root:    PathBuf,
+    options: StaticOptions,
+}
+
+impl Mount {
+    pub fn new(prefix: &str, root: &Path, options: StaticOptions) -> Mount {
+        Mount {
+            prefix:  String::from(prefix.trim_end_matches('/')),
+            root:    root.to_path_buf(),
+            options,
+        }
+    }
+
+    /// The route definitions the mount is served on.
+    pub fn routes(&self) -> [String; 2] {
+        let base = if self.prefix.is_empty() { String::from("/") } else { self.prefix.clone() };
+
+        [base, format!("{}/<path:path>", self.prefix)]
+    }
+
+    pub fn serve(&self, req: &Request) -> Response {
+        let fpath = match self.resolve(&req.path) {
+            Some(fpath) => fpath,
+            None        => return utils::err_404(req),
+        };
+
+        if !fpath.is_dir() {
+            return utils::send_file(req, &fpath, &self.options);
+        }
+
+        // relative links in an index page or listing need the trailing slash
+        if !req.path.ends_with('/') {
+            return Redirect::permanent(&format!("{}/", req.path)).into_response();
+        }
+
+        for name in &self.options.index {
+            if let Some(index) = self.check(&fpath.join(name)) {
+                if index.is_file() {
+                    return utils::send_file(req, &index, &self.options);
+                }
+            }
+        }
+
+        if self.options.listing {
+            if let Ok(page) = self.listing(&req.path, &fpath) {
+                return utils::make_response(page, "text/html", 200);
+            }
+        }
+
+        utils::err_404(req)
+    }
+
+    // find the file for a request path, or None if it's outside the mount or
+    // the options rule it out
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let clean = utils::replace_escape(path);
+        let rest = clean.strip_prefix(&self.prefix)?;
+
+        if !rest.is_empty() && !rest.starts_with('/') {
+            // e.g. "/staticfoo" for the prefix "/static"
+            return None;
+        }
+
+        let mut fpath = self.root.clone();
+
+        for chunk in rest.split('/').filter(|chunk| !chunk.is_empty()) {
+            if chunk == "." || chunk == ".." || chunk.contains('\0') {
+                return None;
+            }
+
+            if chunk.starts_with('.') && !self.options.hidden {
+                return None;
+            }
+
+            fpath.push(chunk);
+
+            if self.options.symlinks == Symlinks::Deny && is_symlink(&fpath) {
+                return None;
+            }
+        }
+
+        self.check(&fpath)
+    }
+
+    // apply the symlink policy to the final path
+    fn check(&self, fpath: &Path) -> Option<PathBuf> {
+        match self.options.symlinks {
+            Symlinks::Follow        => Some(fpath.to_path_buf()),
+            Symlinks::Deny          => if is_symlink(fpath) { None } else { Some(fpath.to_path_buf()) },
+            Symlinks::WithinRoot    => {
+                let root = self.root.canonicalize().ok()?;
+                let real = fpath.canonicalize().ok()?;
+
+                if real.starts_with(&root) { Some(fpath.to_path_buf()) } else { None }
+            },
+        }
+    }
+
+    // an HTML page linking to each entry in a directory
+    fn listing(&self, path: &str, dir: &Path) -> std::io::Result<String> {
+        let mut names: Vec<String> = Vec::new();
+
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let mut name = entry.file_name().to_string_lossy().into_owned();
+
+            if name.starts_with('.') && !self.options.hidden {
+                continue;
+            }
+
+            if entry.path().is_dir() {
+                name.push('/');
+            }
+
+            names.push(name);
+        }
+
+        names.sort();
+
+        let title = utils::escape_html(&utils::replace_escape(path));
+        let mut page = format!("<html><head><title>Index of {0}</title></head><body><h3>Index of {0}</h3><ul>", title);
+
+        if dir != self.root {
+            page.push_str("<li><a href=\"../\">../</a></li>");
+        }
+
+        for name in names {
+            page.push_str(&format!("<li><a href=\"{}\">{}</a></li>", encode_href(&name), utils::escape_html(&name)));
+        }
+
+        page.push_str("</ul></body></html>");
+        Ok(page)
+    }
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).map(|meta| meta.file_type().is_symlink()).unwrap_or(false)
+}
+
+// percent-encode a file name for use as a relative link
+fn encode_href(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+
+    for b in name.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' |
+            b'-' | b'.' | b'_' | b'~' | b'/'        => encoded.push(b as char),
+            _                                       => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    encoded
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::str::FromStr;
+
+    // a directory tree to serve, removed when the test ends
+    struct Tree(PathBuf);
+
+    impl Tree {
+        fn new(tag: &str) -> Tree {
+            let root = env::temp_dir().join(format!("canteen-test-{}-{}", tag, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+
+            fs::create_dir_all(root.join("public/docs")).unwrap();
+            fs::create_dir_all(root.join("public/empty")).unwrap();
+            fs::write(root.join("public/app.js"), "app").unwrap();
+            fs::write(root.join("public/.env"), "secret").unwrap();
+            fs::write(root.join("public/docs/index.html"), "docs").unwrap();
+            fs::write(root.join("public/empty/a <b>.txt"), "a").unwrap();
+            fs::write(root.join("outside.txt"), "outside").unwrap();
+            symlink(root.join("outside.txt"), root.join("public/out.txt")).unwrap();
+            symlink(root.join("public/app.js"), root.join("public/link.js")).unwrap();
+
+            Tree(root)
+        }
+
+        fn mount(&self, options: StaticOptions) -> Mount {
+            Mount::new("/static/", &self.0.join("public"), options)
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn get(mount: &Mount, path: &str) -> (u16, String) {
+        let req = Request::from_str(&format!("GET {} HTTP/1.1\r\n\r\n", path)).unwrap();
+        let res = mount.serve(&req);
+        let output = String::from_utf8_lossy(&res.gen_output().unwrap()).into_owned();
+        let body = output.split_once("\r\n\r\n").map_or("", |(_, body)| body).to_string();
+
+        (res.get_status(), body)
+    }
+
+    #[test]
+    fn test_mount_defaults() {
+        let tree = Tree::new("mount-defaults");
+        let mount = tree.mount(StaticOptions::new());
+
+        assert_eq!(["/static".to_string(), "/static/<path:path>".to_string()], mount.routes());
+        assert_eq!((200, String::from("app")), get(&mount, "/static/app.js"));
+        assert_eq!((200, String::from("app")), get(&mount, "/static/link.js"));
+        assert_eq!((200, String::from("docs")), get(&mount, "/static/docs/"));
+        assert_eq!(301, get(&mount, "/static/docs").0);
+        assert_eq!(404, get(&mount, "/static/.env").0);
+        assert_eq!(404, get(&mount, "/static/out.txt").0);
+        assert_eq!(404, get(&mount, "/static/empty/").0);
+        assert_eq!(404, get(&mount, "/static/../outside.txt").0);
+        assert_eq!(404, get(&mount, "/static/%2E%2E/outside.txt").0);
+        assert_eq!(404, get(&mount, "/staticapp.js").0);
+        assert_eq!(404, get(&mount, "/static/missing").0);
+    }
+
+    #[test]
+    fn test_mount_options() {
+        let tree = Tree::new("mount-options");
+
+        let mount = tree.mount(StaticOptions::new().hidden(true).symlinks(Symlinks::Follow));
+        assert_eq!((200, String::from("secret")), get(&mount, "/static/.env"));
+        assert_eq!((200, String::from("outside")), get(&mount, "/static/out.txt"));
+
+        let mount = tree.mount(StaticOptions::new().symlinks(Symlinks::Deny));
+        assert_eq!(404, get(&mount, "/static/link.js").0);
+        assert_eq!(200, get(&mount, "/static/app.js").0);
+
+        let mount = tree.mount(StaticOptions::new().index_files(&[]).listing(true));
+        let (status, page) = get(&mount, "/static/");
+
+        assert_eq!(200, status);
+        assert!(page.contains("<a href=\"docs/\">docs/</a>"));
+        assert!(page.contains("<a href=\"app.js\">app.js</a>"));
+        assert!(!page.contains(".env"));
+        assert!(!page.contains("href=\"../\""));
+
+        let (status, page) = get(&mount, "/static/empty/");
+
+        assert_eq!(200, status);
+        assert!(page.contains("<a href=\"a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a>"));
+        assert!(page.contains("href=\"../\""));
+    }
+
+    // the encoding, Vary header and body of the response to a request that
+    // accepts `accept`
+    fn fetch(mount: &Mount, path: &str, accept: &str) -> (Option<String>, Option<String>, String) {
+        let req = Request::from_str(&format!("GET {} HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", path, accept)).unwrap();
+        let res = mount.serve(&req);
+        let output = String::from_utf8_lossy(&res.gen_output().unwrap()).into_owned();
+        let body = output.split_once("\r\n\r\n").map_or("", |(_, body)| body).to_string();
+
+        (res.get_header("Content-Encoding"), res.get_header("Vary"), body)
+    }
+
+    #[test]
+    fn test_mount_precompressed() {
+        let tree = Tree::new("mount-precompressed");
+
+        fs::write(tree.0.join("public/app.js.gz"), "gzipped").unwrap();
+        fs::write(tree.0.join("public/app.js.br"), "brotlied").unwrap();
+        symlink(tree.0.join("public/app.js.gz"), tree.0.join("public/link.js.gz")).unwrap();
+
+        let mount = tree.mount(StaticOptions::new().precompressed(true));
+        let (br, gzip, vary) = (Some(String::from("br")), Some(String::from("gzip")), Some(String::from("Accept-Encoding")));
+
+        assert_eq!((br, vary.clone(), String::from("brotlied")), fetch(&mount, "/static/app.js", "gzip, br"));
+        assert_eq!((gzip, vary.clone(), String::from("gzipped")), fetch(&mount, "/static/app.js", "gzip, br;q=0.5"));
+        assert_eq!((None, vary, String::from("app")), fetch(&mount, "/static/app.js", "identity"));
+        assert_eq!((None, None, String::from("app")), fetch(&mount, "/static/link.js", "gzip"));
+
+        // the copy is sent as the type of the original
+        let req = Request::from_str("GET /static/app.js HTTP/1.1\r\nAccept-Encoding: br\r\n\r\n").unwrap();
+        assert!(String::from_utf8_lossy(&mount.serve(&req).gen_output().unwrap()).contains("javascript\r\n"));
+
+        let mount = tree.mount(StaticOptions::new());
+        assert_eq!((None, None, String::from("app")), fetch(&mount, "/static/app.js", "br"));
+    }
+}
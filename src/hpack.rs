@@ -0,0 +1,486 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+//! HPACK header compression for HTTP/2 (RFC 7541). The decoder is complete;
+//! the encoder never adds to the dynamic table, so it needs no state.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// A header block that couldn't be decoded. The connection can't continue
+/// after this, since the peer's compression state is now unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct HpackError(&'static str);
+
+impl fmt::Display for HpackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "hpack: {}", self.0)
+    }
+}
+
+type Result<T> = std::result::Result<T, HpackError>;
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// the canonical Huffman code for each octet, and for EOS (256), as
+// (code, length in bits)
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x00001ff8, 13), (0x007fffd8, 23), (0x0fffffe2, 28), (0x0fffffe3, 28),
+    (0x0fffffe4, 28), (0x0fffffe5, 28), (0x0fffffe6, 28), (0x0fffffe7, 28),
+    (0x0fffffe8, 28), (0x00ffffea, 24), (0x3ffffffc, 30), (0x0fffffe9, 28),
+    (0x0fffffea, 28), (0x3ffffffd, 30), (0x0fffffeb, 28), (0x0fffffec, 28),
+    (0x0fffffed, 28), (0x0fffffee, 28), (0x0fffffef, 28), (0x0ffffff0, 28),
+    (0x0ffffff1, 28), (0x0ffffff2, 28), (0x3ffffffe, 30), (0x0ffffff3, 28),
+    (0x0ffffff4, 28), (0x0ffffff5, 28), (0x0ffffff6, 28), (0x0ffffff7, 28),
+    (0x0ffffff8, 28), (0x0ffffff9, 28), (0x0ffffffa, 28), (0x0ffffffb, 28),
+    (0x00000014,  6), (0x000003f8, 10), (0x000003f9, 10), (0x00000ffa, 12),
+    (0x00001ff9, 13), (0x00000015,  6), (0x000000f8,  8), (0x000007fa, 11),
+    (0x000003fa, 10), (0x000003fb, 10), (0x000000f9,  8), (0x000007fb, 11),
+    (0x000000fa,  8), (0x00000016,  6), (0x00000017,  6), (0x00000018,  6),
+    (0x00000000,  5), (0x00000001,  5), (0x00000002,  5), (0x00000019,  6),
+    (0x0000001a,  6), (0x0000001b,  6), (0x0000001c,  6), (0x0000001d,  6),
+    (0x0000001e,  6), (0x0000001f,  6), (0x0000005c,  7), (0x000000fb,  8),
+    (0x00007ffc, 15), (0x00000020,  6), (0x00000ffb, 12), (0x000003fc, 10),
+    (0x00001ffa, 13), (0x00000021,  6), (0x0000005d,  7), (0x0000005e,  7),
+    (0x0000005f,  7), (0x00000060,  7), (0x00000061,  7), (0x00000062,  7),
+    (0x00000063,  7), (0x00000064,  7), (0x00000065,  7), (0x00000066,  7),
+    (0x00000067,  7), (0x00000068,  7), (0x00000069,  7), (0x0000006a,  7),
+    (0x0000006b,  7), (0x0000006c,  7), (0x0000006d,  7), (0x0000006e,  7),
+    (0x0000006f,  7), (0x00000070,  7), (0x00000071,  7), (0x00000072,  7),
+    (0x000000fc,  8), (0x00000073,  7), (0x000000fd,  8), (0x00001ffb, 13),
+    (0x0007fff0, 19), (0x00001ffc, 13), (0x00003ffc, 14), (0x00000022,  6),
+    (0x00007ffd, 15), (0x00000003,  5), (0x00000023,  6), (0x00000004,  5),
+    (0x00000024,  6), (0x00000005,  5), (0x00000025,  6), (0x00000026,  6),
+    (0x00000027,  6), (0x00000006,  5), (0x00000074,  7), (0x00000075,  7),
+    (0x00000028,  6), (0x00000029,  6), (0x0000002a,  6), (0x00000007,  5),
+    (0x0000002b,  6), (0x00000076,  7), (0x0000002c,  6), (0x00000008,  5),
+    (0x00000009,  5), (0x0000002d,  6), (0x00000077,  7), (0x00000078,  7),
+    (0x00000079,  7), (0x0000007a,  7), (0x0000007b,  7), (0x00007ffe, 15),
+    (0x000007fc, 11), (0x00003ffd, 14), (0x00001ffd, 13), (0x0ffffffc, 28),
+    (0x000fffe6, 20), (0x003fffd2, 22), (0x000fffe7, 20), (0x000fffe8, 20),
+    (0x003fffd3, 22), (0x003fffd4, 22), (0x003fffd5, 22), (0x007fffd9, 23),
+    (0x003fffd6, 22), (0x007fffda, 23), (0x007fffdb, 23), (0x007fffdc, 23),
+    (0x007fffdd, 23), (0x007fffde, 23), (0x00ffffeb, 24), (0x007fffdf, 23),
+    (0x00ffffec, 24), (0x00ffffed, 24), (0x003fffd7, 22), (0x007fffe0, 23),
+    (0x00ffffee, 24), (0x007fffe1, 23), (0x007fffe2, 23), (0x007fffe3, 23),
+    (0x007fffe4, 23), (0x001fffdc, 21), (0x003fffd8, 22), (0x007fffe5, 23),
+    (0x003fffd9, 22), (0x007fffe6, 23), (0x007fffe7, 23), (0x00ffffef, 24),
+    (0x003fffda, 22), (0x001fffdd, 21), (0x000fffe9, 20), (0x003fffdb, 22),
+    (0x003fffdc, 22), (0x007fffe8, 23), (0x007fffe9, 23), (0x001fffde, 21),
+    (0x007fffea, 23), (0x003fffdd, 22), (0x003fffde, 22), (0x00fffff0, 24),
+    (0x001fffdf, 21), (0x003fffdf, 22), (0x007fffeb, 23), (0x007fffec, 23),
+    (0x001fffe0, 21), (0x001fffe1, 21), (0x003fffe0, 22), (0x001fffe2, 21),
+    (0x007fffed, 23), (0x003fffe1, 22), (0x007fffee, 23), (0x007fffef, 23),
+    (0x000fffea, 20), (0x003fffe2, 22), (0x003fffe3, 22), (0x003fffe4, 22),
+    (0x007ffff0, 23), (0x003fffe5, 22), (0x003fffe6, 22), (0x007ffff1, 23),
+    (0x03ffffe0, 26), (0x03ffffe1, 26), (0x000fffeb, 20), (0x0007fff1, 19),
+    (0x003fffe7, 22), (0x007ffff2, 23), (0x003fffe8, 22), (0x01ffffec, 25),
+    (0x03ffffe2, 26), (0x03ffffe3, 26), (0x03ffffe4, 26), (0x07ffffde, 27),
+    (0x07ffffdf, 27), (0x03ffffe5, 26), (0x00fffff1, 24), (0x01ffffed, 25),
+    (0x0007fff2, 19), (0x001fffe3, 21), (0x03ffffe6, 26), (0x07ffffe0, 27),
+    (0x07ffffe1, 27), (0x03ffffe7, 26), (0x07ffffe2, 27), (0x00fffff2, 24),
+    (0x001fffe4, 21), (0x001fffe5, 21), (0x03ffffe8, 26), (0x03ffffe9, 26),
+    (0x0ffffffd, 28), (0x07ffffe3, 27), (0x07ffffe4, 27), (0x07ffffe5, 27),
+    (0x000fffec, 20), (0x00fffff3, 24), (0x000fffed, 20), (0x001fffe6, 21),
+    (0x003fffe9, 22), (0x001fffe7, 21), (0x001fffe8, 21), (0x007ffff3, 23),
+    (0x003fffea, 22), (0x003fffeb, 22), (0x01ffffee, 25), (0x01ffffef, 25),
+    (0x00fffff4, 24), (0x00fffff5, 24), (0x03ffffea, 26), (0x007ffff4, 23),
+    (0x03ffffeb, 26), (0x07ffffe6, 27), (0x03ffffec, 26), (0x03ffffed, 26),
+    (0x07ffffe7, 27), (0x07ffffe8, 27), (0x07ffffe9, 27), (0x07ffffea, 27),
+    (0x07ffffeb, 27), (0x0ffffffe, 28), (0x07ffffec, 27), (0x07ffffed, 27),
+    (0x07ffffee, 27), (0x07ffffef, 27), (0x07fffff0, 27), (0x03ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+// every entry in the dynamic table costs its name and value plus this
+const ENTRY_OVERHEAD: usize = 32;
+
+/// Decodes header blocks from one HTTP/2 connection. Blocks must be decoded
+/// in the order they were received.
+pub(crate) struct Decoder {
+    table:    VecDeque<(String, String)>,
+    size:     usize,
+    max_size: usize,
+    limit:    usize,
+}
+
+impl Decoder {
+    /// Create a decoder whose dynamic table may grow to `limit` bytes, the
+    /// value we advertise as SETTINGS_HEADER_TABLE_SIZE.
+    pub fn new(limit: usize) -> Decoder {
+        Decoder {
+            table:    VecDeque::new(),
+            size:     0,
+            max_size: limit,
+            limit,
+        }
+    }
+
+    /// Decode a complete header block into its fields, in order.
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>> {
+        let mut fields = Vec::new();
+        let mut pos = 0;
+
+        while pos < block.len() {
+            let first = block[pos];
+
+            if first & 0x80 != 0 {
+                // indexed field
+                let index = decode_int(block, &mut pos, 7)?;
+                fields.push(self.lookup(index)?);
+            } else if first & 0x40 != 0 {
+                // literal, added to the dynamic table
+                let field = self.decode_literal(block, &mut pos, 6)?;
+                self.insert(field.clone());
+                fields.push(field);
+            } else if first & 0x20 != 0 {
+                // dynamic table size update
+                let size = decode_int(block, &mut pos, 5)?;
+
+                if size > self.limit {
+                    return Err(HpackError("table size update over the limit"));
+                }
+
+                self.max_size = size;
+                self.evict();
+            } else {
+                // literal, not indexed or never indexed
+                fields.push(self.decode_literal(block, &mut pos, 4)?);
+            }
+        }
+
+        Ok(fields)
+    }
+
+    fn decode_literal(&self, block: &[u8], pos: &mut usize, prefix: u8) -> Result<(String, String)> {
+        let index = decode_int(block, pos, prefix)?;
+        let name = match index {
+            0 => decode_str(block, pos)?,
+            _ => self.lookup(index)?.0,
+        };
+
+        Ok((name, decode_str(block, pos)?))
+    }
+
+    fn lookup(&self, index: usize) -> Result<(String, String)> {
+        match index {
+            0                   => Err(HpackError("index 0")),
+            1..=61              => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_string(), value.to_string()))
+            },
+            _                   => self.table.get(index - 62)
+                                             .cloned()
+                                             .ok_or(HpackError("index out of range")),
+        }
+    }
+
+    fn insert(&mut self, field: (String, String)) {
+        self.size += field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        self.table.push_front(field);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None                => break,
+            }
+        }
+    }
+}
+
+/// Encode a response's status and headers as a header block. Header names
+/// are sent in lowercase, as HTTP/2 requires.
+pub(crate) fn encode_response(status: u16, headers: &[(String, String)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    let indexed = STATIC_TABLE.iter()
+                              .position(|&(name, value)| name == ":status" && value == status.to_string());
+
+    match indexed {
+        Some(index) => encode_int(&mut block, index + 1, 7, 0x80),
+        None        => {
+            // literal without indexing, with the name from the static table
+            encode_int(&mut block, 8, 4, 0x00);
+            encode_str(&mut block, status.to_string().as_bytes());
+        },
+    }
+
+    for (name, value) in headers {
+        block.push(0x00);
+        encode_str(&mut block, name.to_lowercase().as_bytes());
+        encode_str(&mut block, value.as_bytes());
+    }
+
+    block
+}
+
+fn decode_int(buf: &[u8], pos: &mut usize, prefix: u8) -> Result<usize> {
+    let mask = ((1u16 << prefix) - 1) as u8;
+    let first = *buf.get(*pos).ok_or(HpackError("truncated integer"))? & mask;
+
+    *pos += 1;
+
+    if first < mask {
+        return Ok(first as usize);
+    }
+
+    let mut value = mask as usize;
+    let mut shift = 0;
+
+    loop {
+        let byte = *buf.get(*pos).ok_or(HpackError("truncated integer"))?;
+
+        *pos += 1;
+
+        if shift > 28 {
+            return Err(HpackError("integer too large"));
+        }
+
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_int(out: &mut Vec<u8>, value: usize, prefix: u8, flags: u8) {
+    let mask = ((1u16 << prefix) - 1) as usize;
+
+    if value < mask {
+        out.push(flags | value as u8);
+        return;
+    }
+
+    out.push(flags | mask as u8);
+
+    let mut rest = value - mask;
+
+    while rest >= 0x80 {
+        out.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+
+    out.push(rest as u8);
+}
+
+fn decode_str(buf: &[u8], pos: &mut usize) -> Result<String> {
+    let huffman = buf.get(*pos).map(|b| b & 0x80 != 0).unwrap_or(false);
+    let len = decode_int(buf, pos, 7)?;
+    let end = *pos + len;
+
+    if end > buf.len() {
+        return Err(HpackError("truncated string"));
+    }
+
+    let raw = &buf[*pos..end];
+    *pos = end;
+
+    let bytes = if huffman { huffman_decode(raw)? } else { raw.to_vec() };
+
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn encode_str(out: &mut Vec<u8>, s: &[u8]) {
+    encode_int(out, s.len(), 7, 0x00);
+    out.extend_from_slice(s);
+}
+
+// a binary tree of the Huffman codes: each node holds its two children, as
+// the index of another node, the negated symbol plus one for a leaf, or 0 for
+// nothing.
+fn huffman_tree() -> &'static Vec<[i32; 2]> {
+    static TREE: OnceLock<Vec<[i32; 2]>> = OnceLock::new();
+
+    TREE.get_or_init(|| {
+        let mut tree: Vec<[i32; 2]> = vec![[0, 0]];
+
+        for (sym, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+
+            for i in (0..len).rev() {
+                let bit = ((code >> i) & 1) as usize;
+
+                if i == 0 {
+                    tree[node][bit] = -(sym as i32) - 1;
+                } else {
+                    if tree[node][bit] == 0 {
+                        tree.push([0, 0]);
+                        tree[node][bit] = (tree.len() - 1) as i32;
+                    }
+
+                    node = tree[node][bit] as usize;
+                }
+            }
+        }
+
+        tree
+    })
+}
+
+fn huffman_decode(raw: &[u8]) -> Result<Vec<u8>> {
+    let tree = huffman_tree();
+    let mut out = Vec::with_capacity(raw.len() * 8 / 5);
+    let mut node = 0;
+    // bits read since the last symbol, and whether they were all ones
+    let mut pending = 0;
+    let mut ones = true;
+
+    for byte in raw {
+        for i in (0..8).rev() {
+            let bit = ((byte >> i) & 1) as usize;
+            let next = tree[node][bit];
+
+            pending += 1;
+            ones = ones && bit == 1;
+
+            if next < 0 {
+                let sym = (-next - 1) as usize;
+
+                if sym == 256 {
+                    return Err(HpackError("EOS in string"));
+                }
+
+                out.push(sym as u8);
+                node = 0;
+                pending = 0;
+                ones = true;
+            } else {
+                node = next as usize;
+            }
+        }
+    }
+
+    // whatever is left must be a prefix of EOS, i.e. up to seven 1 bits
+    if pending > 7 || !ones {
+        return Err(HpackError("invalid Huffman padding"));
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn fields(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|&(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_decode_rfc_examples() {
+        // RFC 7541 C.3 and C.4: the same requests, without and with Huffman
+        for blocks in &[
+            ["828684410f7777772e6578616d706c652e636f6d", "828684be58086e6f2d6361636865"],
+            ["828684418cf1e3c2e5f23a6ba0ab90f4ff", "828684be5886a8eb10649cbf"],
+        ] {
+            let mut decoder = Decoder::new(4096);
+
+            assert_eq!(fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+                                (":authority", "www.example.com")]),
+                       decoder.decode(&hex(blocks[0])).unwrap());
+            assert_eq!(fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+                                (":authority", "www.example.com"), ("cache-control", "no-cache")]),
+                       decoder.decode(&hex(blocks[1])).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut decoder = Decoder::new(4096);
+
+        assert!(decoder.decode(&hex("80")).is_err());
+        assert!(decoder.decode(&hex("be")).is_err());
+        assert!(decoder.decode(&hex("3fe21f")).is_err());
+        assert!(decoder.decode(&hex("0085f2b24a87ff")).is_err());
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let headers = fields(&[("Content-Type", "text/plain"), ("x-long", &"a".repeat(300))]);
+        let mut decoder = Decoder::new(4096);
+
+        let decoded = decoder.decode(&encode_response(200, &headers)).unwrap();
+        assert_eq!((":status".to_string(), "200".to_string()), decoded[0]);
+        assert_eq!(("content-type".to_string(), "text/plain".to_string()), decoded[1]);
+        assert_eq!(300, decoded[2].1.len());
+
+        let decoded = decoder.decode(&encode_response(418, &[])).unwrap();
+        assert_eq!(fields(&[(":status", "418")]), decoded);
+    }
+}
@@ -0,0 +1,1165 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+//! HTTP/2 (RFC 7540) framing and stream state for a single connection. This
+//! does no I/O of its own: bytes read from the socket are fed to `receive`,
+//! which turns finished requests into the same raw form an HTTP/1.1 request
+//! has, and whatever should be written back accumulates in `output`.
+
+use std::collections::HashMap;
+
+use crate::hpack::{self, Decoder};
+use crate::output::Output;
+use crate::request::Method;
+use crate::sse::Attached;
+
+/// The bytes every HTTP/2 connection opens with.
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER: usize = 9;
+const MAX_FRAME_SIZE: usize = 16_384;
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+const HEADER_TABLE_SIZE: usize = 4096;
+
+// how many requests a client may have in flight on one connection
+const MAX_STREAMS: usize = 100;
+
+// how many more requests a client may cancel than it lets us answer before
+// the connection is closed. each cancelled request may already have been
+// handed to a handler, so resetting streams as fast as they're opened
+// ("rapid reset") would otherwise keep the server busy for free.
+const MAX_RESETS: usize = 2 * MAX_STREAMS;
+
+// how much output is built up before waiting for the socket to take it.
+// response bodies may be files, and a client's window may be huge, so this
+// keeps their memory use in check.
+const MAX_OUTPUT: usize = 256 * 1024;
+
+// frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// response headers that only mean something to HTTP/1.x
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// An error that ends the whole connection. A GOAWAY with its code has
+/// already been queued by the time it's returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct H2Error {
+    pub code:   u32,
+    pub reason: &'static str,
+}
+
+fn error(code: u32, reason: &'static str) -> H2Error {
+    H2Error { code, reason }
+}
+
+/// Something the event loop has to act on.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Event {
+    /// A complete request on a stream, in HTTP/1.x form, for `Request::from_bytes`.
+    Request(u32, Vec<u8>),
+    /// A request that can't be accepted; answer it with this status. The
+    /// request line and headers are included in HTTP/1.x form, if they could
+    /// be read.
+    Reject(u32, u16, &'static str, Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // the request headers have arrived, but not all of the body
+    Receiving,
+    // the request has been handed over; waiting for its response
+    Handling,
+    // the response headers have been sent, and the body is being sent
+    Sending,
+}
+
+struct Stream {
+    state:      State,
+    // the request line and headers, rewritten as HTTP/1.x
+    head:       Vec<u8>,
+    body:       Vec<u8>,
+    body_limit: usize,
+    // whether the client has finished sending its request
+    closed:     bool,
+    // how much of the response body we may send before a WINDOW_UPDATE
+    window:     i64,
+    pending:    Output,
+    // whether the whole response body has been queued; an event stream's
+    // isn't until it ends
+    ended:      bool,
+    events:     Option<Attached>,
+}
+
+/// The HTTP/2 side of one client connection.
+pub(crate) struct Connection {
+    decoder:        Decoder,
+    streams:        HashMap<u32, Stream>,
+    preface:        bool,
+    // a header block split over CONTINUATION frames: the stream, the flags
+    // of the HEADERS frame, and the fragments so far
+    continuation:   Option<(u32, u8, Vec<u8>)>,
+    last_stream:    u32,
+    window:         i64,
+    initial_window: i64,
+    max_frame:      usize,
+    closing:        bool,
+    max_path:       usize,
+    max_header:     usize,
+    // streams the client has reset less those we've answered
+    resets:         usize,
+    /// Frames waiting to be written to the socket.
+    pub output:     Vec<u8>,
+}
+
+impl Connection {
+    /// Start a connection, queueing our SETTINGS. `max_path` and `max_header`
+    /// are the request line and header size limits, as for HTTP/1.1.
+    pub fn new(max_path: usize, max_header: usize) -> Connection {
+        let mut conn = Connection {
+            decoder:        Decoder::new(HEADER_TABLE_SIZE),
+            streams:        HashMap::new(),
+            preface:        false,
+            continuation:   None,
+            last_stream:    0,
+            window:         DEFAULT_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            max_frame:      MAX_FRAME_SIZE,
+            closing:        false,
+            max_path,
+            max_header,
+            resets:         0,
+            output:         Vec::new(),
+        };
+
+        let mut settings = Vec::new();
+
+        for &(id, value) in &[(SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS as u32),
+                              (SETTINGS_MAX_HEADER_LIST_SIZE, max_header as u32)] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+
+        conn.frame(SETTINGS, 0, 0, &settings);
+        conn
+    }
+
+    /// How many streams have a request or response in progress.
+    pub fn open_streams(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Whether the connection is winding down: no new streams are accepted,
+    /// and it can be closed once `open_streams` reaches zero.
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    /// Refuse any new streams, telling the client which have been accepted.
+    pub fn goaway(&mut self, code: u32) {
+        if !self.closing {
+            let mut payload = self.last_stream.to_be_bytes().to_vec();
+
+            payload.extend_from_slice(&code.to_be_bytes());
+            self.frame(GOAWAY, 0, 0, &payload);
+            self.closing = true;
+        }
+    }
+
+    /// Refuse new streams with NO_ERROR, for a graceful shutdown.
+    pub fn shutdown(&mut self) {
+        self.goaway(NO_ERROR);
+    }
+
+    /// Consume every complete frame at the start of `buf`. `body_limit` gives
+    /// the body size limit for a request's method and path.
+    pub fn receive<F>(&mut self, buf: &mut Vec<u8>, mut body_limit: F) -> Result<Vec<Event>, H2Error>
+            where F: FnMut(Method, &str) -> usize {
+        let mut events = Vec::new();
+        let mut pos = 0;
+
+        if !self.preface {
+            if buf.len() < PREFACE.len() {
+                if PREFACE.starts_with(buf) {
+                    return Ok(events);
+                }
+
+                return Err(self.fail(error(PROTOCOL_ERROR, "invalid connection preface")));
+            }
+
+            if !buf.starts_with(PREFACE) {
+                return Err(self.fail(error(PROTOCOL_ERROR, "invalid connection preface")));
+            }
+
+            self.preface = true;
+            pos = PREFACE.len();
+        }
+
+        let result = loop {
+            if buf.len() - pos < FRAME_HEADER {
+                break Ok(());
+            }
+
+            let head = &buf[pos..pos + FRAME_HEADER];
+            let len = (head[0] as usize) << 16 | (head[1] as usize) << 8 | head[2] as usize;
+            let (kind, flags) = (head[3], head[4]);
+            let id = u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff;
+
+            if len > MAX_FRAME_SIZE {
+                break Err(error(FRAME_SIZE_ERROR, "frame too large"));
+            }
+
+            if buf.len() - pos - FRAME_HEADER < len {
+                break Ok(());
+            }
+
+            let payload = buf[pos + FRAME_HEADER..pos + FRAME_HEADER + len].to_vec();
+            pos += FRAME_HEADER + len;
+
+            if let Err(err) = self.on_frame(kind, flags, id, &payload, &mut events, &mut body_limit) {
+                break Err(err);
+            }
+        };
+
+        buf.drain(..pos);
+
+        match result {
+            Ok(())   => Ok(events),
+            Err(err) => Err(self.fail(err)),
+        }
+    }
+
+    /// Send the response to a stream. Responses to streams the client has
+    /// since reset are dropped. If `events` is set, the response is an event
+    /// stream, and its body is sent with `push` as it arrives. Any file in the
+    /// body is read as flow control allows.
+    pub fn respond<B>(&mut self, id: u32, status: u16, headers: &[(String, String)], body: B,
+                      events: Option<Attached>) where B: Into<Output> {
+        let body = body.into();
+
+        match self.streams.get(&id) {
+            Some(stream) if stream.state == State::Handling => {},
+            _                                               => return,
+        }
+
+        let headers: Vec<(String, String)> = headers.iter()
+                                                    .filter(|(name, _)| !CONNECTION_HEADERS.iter().any(|h| name.eq_ignore_ascii_case(h)))
+                                                    .cloned()
+                                                    .collect();
+        let block = hpack::encode_response(status, &headers);
+        let mut chunks = block.chunks(self.max_frame).peekable();
+        let mut kind = HEADERS;
+
+        while let Some(chunk) = chunks.next() {
+            let mut flags = if chunks.peek().is_none() { END_HEADERS } else { 0 };
+
+            if kind == HEADERS && body.is_empty() && events.is_none() {
+                flags |= END_STREAM;
+            }
+
+            self.frame(kind, flags, id, chunk);
+            kind = CONTINUATION;
+        }
+
+        if body.is_empty() && events.is_none() {
+            return self.finish(id);
+        }
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            stream.state = State::Sending;
+            stream.pending = body;
+            stream.ended = events.is_none();
+            stream.events = events;
+        }
+
+        self.send_data(id);
+    }
+
+    /// Send more of an event stream's body, ending the stream if `end` is set.
+    pub fn push(&mut self, id: u32, data: &[u8], end: bool) {
+        match self.streams.get_mut(&id) {
+            Some(stream) if stream.state == State::Sending && !stream.ended => {
+                stream.pending.extend_from_slice(data);
+                stream.ended = end;
+            },
+            _                                                               => return,
+        }
+
+        self.send_data(id);
+    }
+
+    /// Send a comment on every event stream with nothing waiting to be sent,
+    /// so that they aren't timed out by anything in between.
+    pub fn keep_alive_events(&mut self) {
+        for id in self.event_streams() {
+            if self.streams[&id].pending.is_empty() {
+                self.push(id, b":\n\n", false);
+            }
+        }
+    }
+
+    /// End every event stream, e.g. when the server is shutting down.
+    pub fn end_events(&mut self) {
+        for id in self.event_streams() {
+            self.push(id, &[], true);
+        }
+    }
+
+    fn event_streams(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.streams.iter()
+                                            .filter(|(_, stream)| stream.events.is_some() && !stream.ended)
+                                            .map(|(id, _)| *id)
+                                            .collect();
+
+        ids.sort();
+        ids
+    }
+
+    // a connection error: let the client know, and stop accepting streams
+    fn fail(&mut self, err: H2Error) -> H2Error {
+        self.closing = false;
+        self.goaway(err.code);
+        err
+    }
+
+    fn frame(&mut self, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+        let len = payload.len() as u32;
+
+        self.output.extend_from_slice(&len.to_be_bytes()[1..]);
+        self.output.push(kind);
+        self.output.push(flags);
+        self.output.extend_from_slice(&id.to_be_bytes());
+        self.output.extend_from_slice(payload);
+    }
+
+    fn reset(&mut self, id: u32, code: u32) {
+        self.frame(RST_STREAM, 0, id, &code.to_be_bytes());
+    }
+
+    fn window_update(&mut self, id: u32, increment: usize) {
+        self.frame(WINDOW_UPDATE, 0, id, &(increment as u32).to_be_bytes());
+    }
+
+    // the response has been sent in full
+    fn finish(&mut self, id: u32) {
+        if let Some(stream) = self.streams.remove(&id) {
+            self.resets = self.resets.saturating_sub(1);
+
+            if !stream.closed {
+                // the client is still sending a request we've already
+                // answered, e.g. with a 413
+                self.reset(id, NO_ERROR);
+            }
+        }
+    }
+
+    // send as much of a stream's response body as flow control allows, and
+    // as the output has room for
+    fn send_data(&mut self, id: u32) {
+        loop {
+            let room = MAX_OUTPUT.saturating_sub(self.output.len());
+            let (chunk, done) = match self.streams.get_mut(&id) {
+                Some(stream) if stream.state == State::Sending => {
+                    let len = stream.pending.len()
+                                            .min(self.max_frame)
+                                            .min(room)
+                                            .min(self.window.max(0) as usize)
+                                            .min(stream.window.max(0) as usize);
+                    let last = len == stream.pending.len() && stream.ended;
+
+                    // an ended event stream may have nothing left to send
+                    // but the END_STREAM flag
+                    if len == 0 && !last {
+                        return;
+                    }
+
+                    stream.window -= len as i64;
+                    (stream.pending.take(len), last)
+                },
+                _                                              => return,
+            };
+
+            let chunk = match chunk {
+                Ok(chunk)   => chunk,
+                Err(_)      => {
+                    // the body's file can't be read; the headers are gone,
+                    // so all that can be done is to abandon the stream
+                    self.streams.remove(&id);
+                    return self.reset(id, INTERNAL_ERROR);
+                },
+            };
+
+            self.window -= chunk.len() as i64;
+
+            self.frame(DATA, if done { END_STREAM } else { 0 }, id, &chunk);
+
+            if done {
+                return self.finish(id);
+            }
+        }
+    }
+
+    /// Send as much of every response body as flow control allows, e.g.
+    /// once the output so far has been written.
+    pub fn send_all(&mut self) {
+        let mut ids: Vec<u32> = self.streams.iter()
+                                            .filter(|(_, stream)| stream.state == State::Sending)
+                                            .map(|(id, _)| *id)
+                                            .collect();
+
+        ids.sort();
+
+        for id in ids {
+            self.send_data(id);
+        }
+    }
+
+    fn on_frame<F>(&mut self, kind: u8, flags: u8, id: u32, payload: &[u8],
+                   events: &mut Vec<Event>, body_limit: &mut F) -> Result<(), H2Error>
+            where F: FnMut(Method, &str) -> usize {
+        if self.continuation.is_some() && kind != CONTINUATION {
+            return Err(error(PROTOCOL_ERROR, "expected CONTINUATION"));
+        }
+
+        match kind {
+            DATA            => self.on_data(flags, id, payload, events),
+            HEADERS         => {
+                if id == 0 || id.is_multiple_of(2) {
+                    return Err(error(PROTOCOL_ERROR, "invalid stream for HEADERS"));
+                }
+
+                let mut fragment = unpad(flags, payload)?;
+
+                if flags & PRIORITY_FLAG != 0 {
+                    if fragment.len() < 5 {
+                        return Err(error(FRAME_SIZE_ERROR, "short HEADERS frame"));
+                    }
+
+                    fragment = &fragment[5..];
+                }
+
+                if flags & END_HEADERS != 0 {
+                    self.on_headers(id, flags, fragment, events, body_limit)
+                } else {
+                    self.continuation = Some((id, flags, fragment.to_vec()));
+                    Ok(())
+                }
+            },
+            CONTINUATION    => {
+                let (stream, first, mut block) = match self.continuation.take() {
+                    Some(cont) if cont.0 == id => cont,
+                    _                          => return Err(error(PROTOCOL_ERROR, "unexpected CONTINUATION")),
+                };
+
+                block.extend_from_slice(payload);
+
+                if block.len() > self.max_header * 4 {
+                    return Err(error(ENHANCE_YOUR_CALM, "header block too large"));
+                }
+
+                if flags & END_HEADERS != 0 {
+                    self.on_headers(stream, first, &block, events, body_limit)
+                } else {
+                    self.continuation = Some((stream, first, block));
+                    Ok(())
+                }
+            },
+            PRIORITY        => match (id, payload.len()) {
+                (0, _)  => Err(error(PROTOCOL_ERROR, "PRIORITY on stream 0")),
+                (_, 5)  => Ok(()),
+                _       => Err(error(FRAME_SIZE_ERROR, "bad PRIORITY frame")),
+            },
+            RST_STREAM      => {
+                if id == 0 || id > self.last_stream {
+                    return Err(error(PROTOCOL_ERROR, "RST_STREAM on an idle stream"));
+                }
+
+                if payload.len() != 4 {
+                    return Err(error(FRAME_SIZE_ERROR, "bad RST_STREAM frame"));
+                }
+
+                if self.streams.remove(&id).is_some() {
+                    self.resets += 1;
+
+                    if self.resets > MAX_RESETS {
+                        return Err(error(ENHANCE_YOUR_CALM, "too many streams reset"));
+                    }
+                }
+
+                Ok(())
+            },
+            SETTINGS        => self.on_settings(flags, id, payload),
+            PUSH_PROMISE    => Err(error(PROTOCOL_ERROR, "clients can't push")),
+            PING            => {
+                if id != 0 {
+                    return Err(error(PROTOCOL_ERROR, "PING on a stream"));
+                }
+
+                if payload.len() != 8 {
+                    return Err(error(FRAME_SIZE_ERROR, "bad PING frame"));
+                }
+
+                if flags & ACK == 0 {
+                    self.frame(PING, ACK, 0, payload);
+                }
+
+                Ok(())
+            },
+            GOAWAY          => {
+                if id != 0 {
+                    return Err(error(PROTOCOL_ERROR, "GOAWAY on a stream"));
+                }
+
+                // finish what's in flight, but take nothing new
+                self.closing = true;
+                Ok(())
+            },
+            WINDOW_UPDATE   => self.on_window_update(id, payload),
+            // unknown frame types must be ignored
+            _               => Ok(()),
+        }
+    }
+
+    fn on_data(&mut self, flags: u8, id: u32, payload: &[u8], events: &mut Vec<Event>) -> Result<(), H2Error> {
+        if id == 0 {
+            return Err(error(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+
+        let data = unpad(flags, payload)?;
+
+        // bodies are bounded by their size limit rather than by flow control,
+        // so give back what was used straight away
+        if !payload.is_empty() {
+            self.window_update(0, payload.len());
+        }
+
+        let (state, closed) = match self.streams.get(&id) {
+            Some(stream) => (stream.state, stream.closed),
+            None         => {
+                if id > self.last_stream {
+                    return Err(error(PROTOCOL_ERROR, "DATA on an idle stream"));
+                }
+
+                self.reset(id, STREAM_CLOSED);
+                return Ok(());
+            },
+        };
+
+        if closed {
+            return Err(error(STREAM_CLOSED, "DATA after END_STREAM"));
+        }
+
+        let end = flags & END_STREAM != 0;
+
+        if state != State::Receiving {
+            // the request was rejected before its body had all arrived
+            if let Some(stream) = self.streams.get_mut(&id) {
+                stream.closed = end;
+            }
+
+            return Ok(());
+        }
+
+        if !end && !payload.is_empty() {
+            self.window_update(id, payload.len());
+        }
+
+        let stream = self.streams.get_mut(&id).unwrap();
+
+        stream.body.extend_from_slice(data);
+
+        if stream.body.len() > stream.body_limit {
+            stream.state = State::Handling;
+            stream.closed = end;
+            events.push(Event::Reject(id, 413, "request body too large", std::mem::take(&mut stream.head)));
+        } else if end {
+            stream.closed = true;
+            events.push(Event::Request(id, complete(stream)));
+        }
+
+        Ok(())
+    }
+
+    fn on_headers<F>(&mut self, id: u32, flags: u8, block: &[u8],
+                     events: &mut Vec<Event>, body_limit: &mut F) -> Result<(), H2Error>
+            where F: FnMut(Method, &str) -> usize {
+        // always decode, to keep the compression state in step
+        let fields = self.decoder.decode(block)
+                                 .map_err(|_| error(COMPRESSION_ERROR, "invalid header block"))?;
+        let end = flags & END_STREAM != 0;
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            // trailers, which end the request
+            if stream.state != State::Receiving || !end {
+                return Err(error(PROTOCOL_ERROR, "unexpected HEADERS"));
+            }
+
+            stream.closed = true;
+            events.push(Event::Request(id, complete(stream)));
+            return Ok(());
+        }
+
+        if id <= self.last_stream {
+            return Err(error(STREAM_CLOSED, "HEADERS on a closed stream"));
+        }
+
+        self.last_stream = id;
+
+        if self.closing {
+            return Ok(());
+        }
+
+        if self.streams.len() >= MAX_STREAMS {
+            self.reset(id, REFUSED_STREAM);
+            return Ok(());
+        }
+
+        let mut stream = Stream {
+            state:      State::Handling,
+            head:       Vec::new(),
+            body:       Vec::new(),
+            body_limit: 0,
+            closed:     end,
+            window:     self.initial_window,
+            pending:    Output::new(),
+            ended:      false,
+            events:     None,
+        };
+
+        match request_head(&fields, block.len(), self.max_path, self.max_header) {
+            Ok((head, method, path, length)) => {
+                stream.body_limit = body_limit(method, path.split('?').next().unwrap_or(""));
+                stream.head = head;
+
+                if length.is_some_and(|len| len > stream.body_limit) {
+                    events.push(Event::Reject(id, 413, "request body too large", stream.head.clone()));
+                } else if end {
+                    events.push(Event::Request(id, complete(&mut stream)));
+                } else {
+                    stream.state = State::Receiving;
+                }
+            },
+            Err((status, reason)) => events.push(Event::Reject(id, status, reason, Vec::new())),
+        }
+
+        self.streams.insert(id, stream);
+        Ok(())
+    }
+
+    fn on_settings(&mut self, flags: u8, id: u32, payload: &[u8]) -> Result<(), H2Error> {
+        if id != 0 {
+            return Err(error(PROTOCOL_ERROR, "SETTINGS on a stream"));
+        }
+
+        if flags & ACK != 0 {
+            return match payload.len() {
+                0 => Ok(()),
+                _ => Err(error(FRAME_SIZE_ERROR, "SETTINGS ACK with a payload")),
+            };
+        }
+
+        if !payload.len().is_multiple_of(6) {
+            return Err(error(FRAME_SIZE_ERROR, "bad SETTINGS frame"));
+        }
+
+        for setting in payload.chunks(6) {
+            let key = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+
+            match key {
+                SETTINGS_ENABLE_PUSH if value > 1            => {
+                    return Err(error(PROTOCOL_ERROR, "invalid SETTINGS_ENABLE_PUSH"));
+                },
+                SETTINGS_INITIAL_WINDOW_SIZE                 => {
+                    if value as i64 > MAX_WINDOW {
+                        return Err(error(FLOW_CONTROL_ERROR, "invalid SETTINGS_INITIAL_WINDOW_SIZE"));
+                    }
+
+                    let delta = value as i64 - self.initial_window;
+
+                    for stream in self.streams.values_mut() {
+                        stream.window += delta;
+                    }
+
+                    self.initial_window = value as i64;
+                },
+                SETTINGS_MAX_FRAME_SIZE                      => {
+                    if (value as usize) < MAX_FRAME_SIZE || value > 0xff_ffff {
+                        return Err(error(PROTOCOL_ERROR, "invalid SETTINGS_MAX_FRAME_SIZE"));
+                    }
+
+                    self.max_frame = value as usize;
+                },
+                _                                            => {},
+            }
+        }
+
+        self.frame(SETTINGS, ACK, 0, &[]);
+        self.send_all();
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, id: u32, payload: &[u8]) -> Result<(), H2Error> {
+        if payload.len() != 4 {
+            return Err(error(FRAME_SIZE_ERROR, "bad WINDOW_UPDATE frame"));
+        }
+
+        let increment = (u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & 0x7fff_ffff) as i64;
+
+        if increment == 0 {
+            return Err(error(PROTOCOL_ERROR, "zero WINDOW_UPDATE"));
+        }
+
+        if id == 0 {
+            self.window += increment;
+
+            if self.window > MAX_WINDOW {
+                return Err(error(FLOW_CONTROL_ERROR, "window overflow"));
+            }
+
+            self.send_all();
+        } else if let Some(stream) = self.streams.get_mut(&id) {
+            stream.window += increment;
+
+            if stream.window > MAX_WINDOW {
+                return Err(error(FLOW_CONTROL_ERROR, "window overflow"));
+            }
+
+            self.send_data(id);
+        }
+
+        Ok(())
+    }
+}
+
+// strip the padding from a DATA or HEADERS frame
+fn unpad(flags: u8, payload: &[u8]) -> Result<&[u8], H2Error> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+
+    match payload.split_first() {
+        Some((&pad, rest)) if (pad as usize) <= rest.len() => Ok(&rest[..rest.len() - pad as usize]),
+        _                                                  => Err(error(PROTOCOL_ERROR, "invalid padding")),
+    }
+}
+
+// hand over a stream's request, now that all of it has arrived
+fn complete(stream: &mut Stream) -> Vec<u8> {
+    let mut raw = std::mem::take(&mut stream.head);
+
+    raw.append(&mut stream.body);
+    stream.state = State::Handling;
+    raw
+}
+
+// a request rewritten as HTTP/1.x, with its method, path and Content-Length
+type Head = (Vec<u8>, Method, String, Option<usize>);
+
+// rewrite a request's header fields as an HTTP/1.x request line and headers,
+// returning them with the method, the path and the Content-Length, if any.
+fn request_head(fields: &[(String, String)], size: usize, max_path: usize, max_header: usize)
+        -> Result<Head, (u16, &'static str)> {
+    if size > max_header {
+        return Err((431, "request header fields too large"));
+    }
+
+    let mut method = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut length = None;
+    let mut cookies = Vec::new();
+    let mut headers = String::new();
+
+    for (name, value) in fields {
+        if name.contains(['\r', '\n']) || value.contains(['\r', '\n']) {
+            return Err((400, "invalid header field"));
+        }
+
+        match name.as_str() {
+            ":method"           => method = Some(value.clone()),
+            ":path"             => path = Some(value.clone()),
+            ":authority"        => authority = Some(value.clone()),
+            ":scheme"           => {},
+            "cookie"            => cookies.push(value.as_str()),
+            _ if name.starts_with(':') => return Err((400, "unknown pseudo-header")),
+            _                   => {
+                if name == "content-length" {
+                    length = Some(value.parse::<usize>().map_err(|_| (400, "invalid Content-Length"))?);
+                }
+
+                headers.push_str(&format!("{}: {}\r\n", name, value));
+            },
+        }
+    }
+
+    let (method, path) = match (method, path) {
+        (Some(method), Some(path)) => (method, path),
+        _                          => return Err((400, "missing :method or :path")),
+    };
+
+    if path.len() > max_path {
+        return Err((414, "request line too long"));
+    }
+
+    if let Some(host) = authority {
+        headers.push_str(&format!("host: {}\r\n", host));
+    }
+
+    if !cookies.is_empty() {
+        // split up for better compression, but one header to everyone else
+        headers.push_str(&format!("cookie: {}\r\n", cookies.join("; ")));
+    }
+
+    let head = format!("{} {} HTTP/2.0\r\n{}\r\n", method, path, headers);
+
+    Ok((head.into_bytes(), Method::from(method.as_str()), path, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Request;
+
+    fn frame(kind: u8, flags: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+
+        out.push(kind);
+        out.push(flags);
+        out.extend_from_slice(&id.to_be_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    // a header block of literals without indexing
+    fn block(fields: &[(&str, &str)]) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        for (name, value) in fields {
+            out.push(0x00);
+            out.push(name.len() as u8);
+            out.extend_from_slice(name.as_bytes());
+            out.push(value.len() as u8);
+            out.extend_from_slice(value.as_bytes());
+        }
+
+        out
+    }
+
+    fn frames(mut out: &[u8]) -> Vec<(u8, u8, u32, Vec<u8>)> {
+        let mut list = Vec::new();
+
+        while out.len() >= FRAME_HEADER {
+            let len = (out[0] as usize) << 16 | (out[1] as usize) << 8 | out[2] as usize;
+            let id = u32::from_be_bytes([out[5], out[6], out[7], out[8]]);
+
+            list.push((out[3], out[4], id, out[FRAME_HEADER..FRAME_HEADER + len].to_vec()));
+            out = &out[FRAME_HEADER + len..];
+        }
+
+        list
+    }
+
+    fn start(settings: &[u8]) -> (Connection, Vec<u8>) {
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(SETTINGS, 0, 0, settings));
+
+        (Connection::new(256, 1024), input)
+    }
+
+    const GET: [(&str, &str); 4] = [(":method", "GET"), (":scheme", "https"),
+                                    (":path", "/hello?x=1"), (":authority", "example.com")];
+
+    #[test]
+    fn test_h2_get() {
+        let (mut conn, mut input) = start(&[]);
+        input.extend(frame(HEADERS, END_HEADERS | END_STREAM, 1, &block(&GET)));
+
+        let events = conn.receive(&mut input, |_, _| 16).unwrap();
+        let raw = match &events[..] {
+            [Event::Request(1, raw)] => raw.clone(),
+            other                    => panic!("unexpected events: {:?}", other),
+        };
+        let req = Request::from_bytes(&raw).unwrap();
+
+        assert!(input.is_empty());
+        assert_eq!(Method::Get, req.method);
+        assert_eq!("/hello", req.path);
+        assert_eq!(Some(String::from("example.com")), req.get_header("Host"));
+
+        let sent: Vec<(u8, u8)> = frames(&conn.output).iter().map(|f| (f.0, f.1)).collect();
+        assert_eq!(vec![(SETTINGS, 0), (SETTINGS, ACK)], sent);
+    }
+
+    #[test]
+    fn test_h2_body_and_response() {
+        let (mut conn, mut input) = start(&[]);
+        input.extend(frame(HEADERS, END_HEADERS, 3, &block(&[(":method", "POST"), (":path", "/echo")])));
+        input.extend(frame(DATA, 0, 3, b"hello "));
+
+        // frames may arrive in pieces
+        let mut tail = frame(DATA, END_STREAM, 3, b"world");
+        input.extend(tail.drain(..4));
+
+        assert!(conn.receive(&mut input, |_, _| 16).unwrap().is_empty());
+        assert_eq!(4, input.len());
+
+        input.extend(tail);
+        let events = conn.receive(&mut input, |_, _| 16).unwrap();
+        let req = match &events[..] {
+            [Event::Request(3, raw)] => Request::from_bytes(raw).unwrap(),
+            other                    => panic!("unexpected events: {:?}", other),
+        };
+        assert_eq!(b"hello world", req.payload.as_slice());
+
+        conn.output.clear();
+        conn.respond(3, 200, &[(String::from("Connection"), String::from("close")),
+                               (String::from("Content-Type"), String::from("text/plain"))], b"ok".to_vec(), None);
+
+        let sent = frames(&conn.output);
+        let headers = Decoder::new(4096).decode(&sent[0].3).unwrap();
+
+        assert_eq!((HEADERS, END_HEADERS, 3), (sent[0].0, sent[0].1, sent[0].2));
+        assert_eq!(vec![(String::from(":status"), String::from("200")),
+                        (String::from("content-type"), String::from("text/plain"))], headers);
+        assert_eq!((DATA, END_STREAM, 3, b"ok".to_vec()), sent[1]);
+        assert_eq!(0, conn.open_streams());
+    }
+
+    #[test]
+    fn test_h2_flow_control() {
+        let mut settings = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+        settings.extend_from_slice(&10u32.to_be_bytes());
+
+        let (mut conn, mut input) = start(&settings);
+        input.extend(frame(HEADERS, END_HEADERS | END_STREAM, 1, &block(&GET)));
+        conn.receive(&mut input, |_, _| 16).unwrap();
+
+        conn.output.clear();
+        conn.respond(1, 200, &[], vec![b'a'; 25], None);
+
+        let sent = frames(&conn.output);
+        assert_eq!((DATA, 0, 10), (sent[1].0, sent[1].1, sent[1].3.len()));
+        assert_eq!(1, conn.open_streams());
+
+        conn.output.clear();
+        input.extend(frame(WINDOW_UPDATE, 0, 1, &100u32.to_be_bytes()));
+        conn.receive(&mut input, |_, _| 16).unwrap();
+
+        let sent = frames(&conn.output);
+        assert_eq!((DATA, END_STREAM, 15), (sent[0].0, sent[0].1, sent[0].3.len()));
+        assert_eq!(0, conn.open_streams());
+    }
+
+    #[test]
+    fn test_h2_file_body() {
+        use std::fs::{self, File};
+        use crate::output::FileBody;
+
+        let manifest = fs::read("Cargo.toml").unwrap();
+        let body = |extra: u64| {
+            let file = File::open("Cargo.toml").unwrap();
+            let len = file.metadata().unwrap().len();
+            let mut body = Output::new();
+
+            body.push_file(FileBody::new(file, 0, len + extra));
+            body
+        };
+
+        let mut settings = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+        settings.extend_from_slice(&10u32.to_be_bytes());
+
+        let (mut conn, mut input) = start(&settings);
+        input.extend(frame(HEADERS, END_HEADERS | END_STREAM, 1, &block(&GET)));
+        input.extend(frame(HEADERS, END_HEADERS | END_STREAM, 3, &block(&GET)));
+        conn.receive(&mut input, |_, _| 16).unwrap();
+
+        // the file is only read as far as the window allows
+        conn.output.clear();
+        conn.respond(1, 200, &[], body(0), None);
+
+        let sent = frames(&conn.output);
+        assert_eq!((DATA, 0, 1, manifest[..10].to_vec()), sent[1]);
+
+        conn.output.clear();
+        input.extend(frame(WINDOW_UPDATE, 0, 1, &(1u32 << 20).to_be_bytes()));
+        conn.receive(&mut input, |_, _| 16).unwrap();
+
+        let data: Vec<u8> = frames(&conn.output).into_iter().flat_map(|f| f.3).collect();
+        assert_eq!(&manifest[10..], &data[..]);
+
+        // a file that turns out shorter than it was resets its stream
+        conn.output.clear();
+        conn.respond(3, 200, &[], body(100), None);
+        input.extend(frame(WINDOW_UPDATE, 0, 3, &(1u32 << 20).to_be_bytes()));
+        conn.receive(&mut input, |_, _| 16).unwrap();
+
+        let last = frames(&conn.output).pop().unwrap();
+        assert_eq!((RST_STREAM, 3, INTERNAL_ERROR.to_be_bytes().to_vec()), (last.0, last.2, last.3));
+        assert_eq!(0, conn.open_streams());
+    }
+
+    #[test]
+    fn test_h2_output_limit() {
+        let mut settings = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+        settings.extend_from_slice(&(MAX_WINDOW as u32).to_be_bytes());
+
+        let (mut conn, mut input) = start(&settings);
+        input.extend(frame(WINDOW_UPDATE, 0, 0, &((MAX_WINDOW - DEFAULT_WINDOW) as u32).to_be_bytes()));
+        input.extend(frame(HEADERS, END_HEADERS | END_STREAM, 1, &block(&GET)));
+        conn.receive(&mut input, |_, _| 16).unwrap();
+
+        // however large the window, the body is sent a piece at a time, as
+        // the output is written
+        conn.output.clear();
+        conn.respond(1, 200, &[], vec![b'a'; MAX_OUTPUT * 3], None);
+
+        let mut sent = 0;
+
+        loop {
+            assert!(conn.output.len() <= MAX_OUTPUT + FRAME_HEADER * (MAX_OUTPUT / MAX_FRAME_SIZE + 1));
+
+            sent += frames(&conn.output).iter().filter(|f| f.0 == DATA).map(|f| f.3.len()).sum::<usize>();
+            conn.output.clear();
+
+            if conn.open_streams() == 0 {
+                break;
+            }
+
+            conn.send_all();
+        }
+
+        assert_eq!(MAX_OUTPUT * 3, sent);
+    }
+
+    #[test]
+    fn test_h2_event_stream() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let (mut conn, mut input) = start(&[]);
+        input.extend(frame(HEADERS, END_HEADERS | END_STREAM, 1, &block(&GET)));
+        conn.receive(&mut input, |_, _| 16).unwrap();
+
+        let closed = Arc::new(AtomicBool::new(false));
+
+        conn.output.clear();
+        conn.respond(1, 200, &[], Vec::new(), Some(Attached::new(closed.clone())));
+        conn.push(1, b"data: hi\n\n", false);
+        conn.keep_alive_events();
+
+        let sent = frames(&conn.output);
+        assert_eq!((HEADERS, END_HEADERS), (sent[0].0, sent[0].1));
+        assert_eq!((DATA, 0, b"data: hi\n\n".to_vec()), (sent[1].0, sent[1].1, sent[1].3.clone()));
+        assert_eq!((DATA, 0, b":\n\n".to_vec()), (sent[2].0, sent[2].1, sent[2].3.clone()));
+        assert_eq!(1, conn.open_streams());
+
+        conn.output.clear();
+        conn.end_events();
+
+        assert_eq!(vec![(DATA, END_STREAM, 1, Vec::new())], frames(&conn.output));
+        assert_eq!(0, conn.open_streams());
+        assert!(closed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_h2_limits_and_errors() {
+        let (mut conn, mut input) = start(&[]);
+        let mut post = GET.to_vec();
+        post[0] = (":method", "POST");
+        post.push(("content-length", "17"));
+        input.extend(frame(HEADERS, END_HEADERS, 1, &block(&post)));
+        input.extend(frame(PING, 0, 0, b"12345678"));
+
+        let events = conn.receive(&mut input, |_, _| 16).unwrap();
+
+        // the request's head is passed on for the error handler
+        match events.as_slice() {
+            [Event::Reject(1, 413, _, head)] => assert!(head.starts_with(b"POST /hello?x=1 HTTP/2.0\r\n")),
+            _                                => panic!("{:?}", events),
+        }
+
+        assert_eq!((PING, ACK), frames(&conn.output).last().map(|f| (f.0, f.1)).unwrap());
+
+        // answering before the body has been sent resets the stream
+        conn.output.clear();
+        conn.respond(1, 413, &[], Vec::new(), None);
+        assert_eq!(RST_STREAM, frames(&conn.output)[1].0);
+
+        input.extend(frame(PUSH_PROMISE, END_HEADERS, 1, &[0, 0, 0, 2]));
+        assert_eq!(PROTOCOL_ERROR, conn.receive(&mut input, |_, _| 16).unwrap_err().code);
+        assert_eq!(GOAWAY, frames(&conn.output).last().unwrap().0);
+        assert!(conn.is_closing());
+
+        let mut bad = Connection::new(256, 1024);
+        assert!(bad.receive(&mut b"GET / HTTP/1.1\r\n\r\n\r\n\r\n\r\n".to_vec(), |_, _| 16).is_err());
+    }
+
+    #[test]
+    fn test_h2_rapid_reset() {
+        let (mut conn, mut input) = start(&[]);
+        let mut id = 1;
+
+        // streams that are answered don't count against the client
+        for _ in 0..MAX_RESETS {
+            input.extend(frame(HEADERS, END_HEADERS | END_STREAM, id, &block(&GET)));
+            input.extend(frame(RST_STREAM, 0, id, &STREAM_CLOSED.to_be_bytes()));
+            input.extend(frame(HEADERS, END_HEADERS | END_STREAM, id + 2, &block(&GET)));
+            assert_eq!(2, conn.receive(&mut input, |_, _| 16).unwrap().len());
+            conn.respond(id + 2, 200, &[], Vec::new(), None);
+            id += 4;
+        }
+
+        // but cancelling more than that closes the connection
+        for _ in 0..=MAX_RESETS {
+            input.extend(frame(HEADERS, END_HEADERS | END_STREAM, id, &block(&GET)));
+            input.extend(frame(RST_STREAM, 0, id, &STREAM_CLOSED.to_be_bytes()));
+            id += 2;
+        }
+
+        assert_eq!(ENHANCE_YOUR_CALM, conn.receive(&mut input, |_, _| 16).unwrap_err().code);
+
+        let last = frames(&conn.output).pop().unwrap();
+
+        assert_eq!((GOAWAY, ENHANCE_YOUR_CALM.to_be_bytes().to_vec()), (last.0, last.3[4..].to_vec()));
+    }
+}
@@ -22,18 +22,32 @@
 //! - `<str:name>` will match anything inside a path segment, returns a `String`
 //! - `<int:name>` will return a signed integer (`i32`) from a path segment
 //!   - ex: `cnt.add_route("/api/foo/<int:foo_id>", &[Method::Get], my_handler)` will match
-//!   `"/api/foo/123"` but not `"/api/foo/123.34"` or `"/api/foo/bar"`
+//!     `"/api/foo/123"` but not `"/api/foo/123.34"` or `"/api/foo/bar"`
 //! - `<uint:name>` will return an unsigned integer (`u32`)
 //! - `<float:name>` does the same thing as the `int` parameter definition, but matches numbers
-//! with decimal points and returns an `f32`
+//!   with decimal points and returns an `f32`
 //! - `<path:name>` will greedily take all path data contained, returns a `String`
 //!   - ex: `cnt.add_route("/static/<path:name>", &[Method::Get], utils::static_file)` will
-//!   serve anything in the `/static/` directory as a file
+//!     serve anything in the `/static/` directory as a file
+//!
+//! Handlers aren't limited to returning a `Response` -- anything that implements
+//! `IntoResponse` will do, such as a `String`, `Json(value)`, `Html(body)`, a
+//! `Redirect`, a `(status, body)` tuple, or a `Result` of any of these. Nor are
+//! they limited to taking a `&Request`: see the `extract` module for handlers
+//! that take typed arguments like `Path<(i32, String)>` or `Json<T>`.
 //!
 //! After the handlers are attached to routes, the next step is to simply start the
 //! server. Any time a request is received, it is dispatched with the associated handler
 //! to a threadpool worker. The worker notifies the parent process when it's finished,
 //! and then the response is transmitted back to the client. Pretty straightforward stuff!
+//! Connections are kept alive between requests by default; the size of the threadpool,
+//! the connection and request size limits, and the timeouts can all be changed with
+//! `Canteen::builder()`.
+//!
+//! With the `http2` feature, clients can also speak HTTP/2: over plain TCP by sending
+//! the HTTP/2 preface straight away ("prior knowledge"), and over TLS by choosing it
+//! during the handshake. Requests on an HTTP/2 connection are handled concurrently,
+//! by the same handlers and with the same `Request` and `Response` types.
 //!
 //! ## Example
 //!
@@ -64,7 +78,7 @@
 //!     let mut cnt = Canteen::new();
 //!
 //!     // bind to an address
-//!     cnt.bind(("127.0.0.1", 8888));
+//!     cnt.bind(("127.0.0.1", 8888)).unwrap();
 //!
 //!     // set the default route handler to show a 404 message
 //!     cnt.set_default(utils::err_404);
@@ -82,179 +96,196 @@
 
 pub mod utils;
 pub mod route;
+pub mod error;
+pub mod extract;
+pub mod config;
 pub mod request;
+pub mod shutdown;
+pub mod stats;
+pub mod websocket;
+pub mod sse;
+pub mod fileserver;
+pub mod conditional;
+pub mod compress;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "http2")]
+mod hpack;
+#[cfg(feature = "http2")]
+mod http2;
+mod client;
+mod event_loop;
+mod listener;
+mod executor;
+mod output;
+mod range;
 pub mod response;
 
 #[cfg(test)]
 #[macro_use]
 extern crate serde_derive;
 
-use std::str::FromStr;
-use std::io::Result;
+use std::panic;
+use std::thread;
+use std::sync::Arc;
+use std::future::Future;
+use std::io;
 use std::io::prelude::*;
-use std::net::ToSocketAddrs;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::path::Path;
 
 use threadpool::ThreadPool;
-use mio::tcp::{TcpListener, TcpStream};
-use mio::util::Slab;
-use mio::*;
+use mio::{Interest, Token};
+use mio::event::Event;
 
+pub use crate::error::*;
 pub use crate::request::*;
 pub use crate::response::*;
+pub use crate::config::{ServerConfig, CanteenBuilder};
+pub use crate::shutdown::ShutdownHandle;
+pub use crate::stats::ServerStats;
 
-struct Client {
-    sock:   TcpStream,
-    token:  Token,
-    events: EventSet,
-    i_buf:  Vec<u8>,
-    o_buf:  Vec<u8>,
-}
-
-impl Client {
-    fn new(sock: TcpStream, token: Token) -> Client {
-        Client {
-            sock,
-            token,
-            events: EventSet::hup(),
-            i_buf:  Vec::with_capacity(2048),
-            o_buf:  Vec::new(),
-        }
-    }
-
-    fn receive(&mut self) -> Result<bool> {
-        let mut bytes_read: usize = 0;
-
-        loop {
-            let mut buf: Vec<u8> = Vec::with_capacity(2048);
-            match self.sock.try_read_buf(&mut buf) {
-                Ok(size)  => {
-                    match size {
-                        Some(bytes) => {
-                            self.i_buf.extend(buf);
-                            bytes_read += bytes;
-                        },
-                        None    => {
-                            self.events.remove(EventSet::readable());
-                            self.events.insert(EventSet::writable());
-                            break;
-                        },
-                    }
-                },
-                Err(_)  => {
-                    self.events.remove(EventSet::readable());
-                    self.events.insert(EventSet::writable());
-                    break;
-                },
-            };
-        }
-
-        Ok(bytes_read > 0)
-    }
-
-    // write the client's output buffer to the socket.
-    //
-    // the following return values mean:
-    //  - Ok(true):  we can close the connection
-    //  - Ok(false): keep listening for writeable event and continue next time
-    //  - Err(e):    something dun fucked up
-    fn send(&mut self) -> Result<bool> {
-        if self.o_buf.is_empty() {
-            return Ok(false);
-        }
-
-        while !self.o_buf.is_empty() {
-            match self.sock.write(&self.o_buf.as_slice()) {
-                Ok(sz)  => {
-                    if sz == self.o_buf.len() {
-                        // we did it!
-                        self.events.remove(EventSet::writable());
-                        break;
-                    } else {
-                        // keep going
-                        self.o_buf = self.o_buf.split_off(sz);
-                    }
-                },
-                Err(_)  => {
-                    return Ok(true);
-                }
-            }
-        }
-
-        Ok(true)
-    }
+use crate::client::{Client, Clients, Framing, Notice, Phase, Reply, Responder};
+use crate::event_loop::{EventLoop, Handler};
+use crate::websocket::{Session, WebSocket};
+use crate::listener::{Bound, Listener, Stream};
 
-    fn register(&mut self, evl: &mut EventLoop<Canteen>) -> Result<()> {
-        self.events.insert(EventSet::readable());
-        evl.register(&self.sock, self.token, self.events, PollOpt::edge() | PollOpt::oneshot())
-    }
+// the timer payload for the drain deadline. client tokens start after it, and
+// the event loop's waker comes before it.
+const DRAIN: Token = Token(1);
 
-    fn reregister(&mut self, evl: &mut EventLoop<Canteen>) -> Result<()> {
-        evl.reregister(&self.sock, self.token, self.events, PollOpt::edge() | PollOpt::oneshot())
-    }
-}
+// listeners are registered from here up, well clear of client tokens
+const LISTENER_BASE: usize = usize::MAX / 2;
 
 /// The primary struct provided by the library. The aim is to have a similar
 /// interface to Flask, the Python microframework.
 pub struct Canteen {
     routes:  HashMap<route::RouteDef, route::Route>,
-    rcache:  HashMap<route::RouteDef, route::RouteDef>,
-    server:  Option<TcpListener>,
-    token:   Token,
-    conns:   Slab<Client>,
-    default: fn(&Request) -> Response,
+    rcache:  HashMap<(Option<String>, route::RouteDef), route::RouteDef>,
+    servers: Vec<Bound>,
+    conns:   Clients,
+    default: Option<fn(&Request) -> Response>,
+    errors:  Arc<HashMap<u16, ErrorHandler>>,
+    state:   Arc<extract::StateMap>,
     tpool:   ThreadPool,
+    asyncex: Option<executor::Executor>,
+    config:  ServerConfig,
+    failure: Option<ServerError>,
+    stopper: ShutdownHandle,
+    stats:   ServerStats,
+    peers:   HashMap<IpAddr, usize>,
+    drain:   bool,
+    #[cfg(feature = "compression")]
+    compress: Option<Arc<compress::CompressOptions>>,
+}
+
+// the outcome of trying to accept a connection
+enum Accepted {
+    Stream(Stream, Option<IpAddr>),
+    // the connection went away before it could be accepted
+    Skipped,
+    // there are no more pending connections
+    Drained,
 }
 
 impl Handler for Canteen {
-    type Timeout = ();
-    type Message = (Token, Vec<u8>);
+    fn ready(&mut self, evl: &mut EventLoop, token: Token, event: &Event) {
+        self.check_shutdown(evl);
+
+        if token.0 >= LISTENER_BASE {
+            let index = token.0 - LISTENER_BASE;
+
+            // with edge-triggered events, the listener won't fire again for
+            // connections that were already pending, so take them all now
+            loop {
+                match self.accept(index) {
+                    Ok(Accepted::Stream(sock, peer))    => self.admit(evl, index, sock, peer),
+                    Ok(Accepted::Skipped)               => continue,
+                    Ok(Accepted::Drained)               => break,
+                    Err(err)                            => {
+                        // the listener itself is broken; stop and let run() report it
+                        self.failure = Some(ServerError::Accept(err));
+                        evl.shutdown();
+                        return;
+                    },
+                }
+            }
 
-    fn ready(&mut self, evl: &mut EventLoop<Canteen>, token: Token, events: EventSet) {
-        if events.is_error() || events.is_hup() {
-            self.reset_connection(token);
             return;
         }
 
-        if events.is_readable() {
-            if self.token == token {
-                let sock = self.accept().unwrap();
-
-                if let Some(token) = self.conns.insert_with(|token| Client::new(sock, token)) {
-                    self.get_client(token).register(evl).ok();
-                }
+        // the client may have been closed earlier in this batch of events
+        if !self.conns.contains(token) {
+            return;
+        }
 
-                self.reregister(evl);
-            } else {
-                self.readable(evl, token)
-                    .and_then(|_| self.get_client(token)
-                                      .reregister(evl)).ok();
-            }
+        if event.is_error() || (event.is_read_closed() && event.is_write_closed()) {
+            self.reset_connection(evl, token);
+            return;
+        }
 
+        if event.is_readable() {
+            self.readable(evl, token);
             return;
         }
 
-        if events.is_writable() {
-            match self.get_client(token).send() {
-                Ok(true)    => { self.reset_connection(token); },
-                Ok(false)   => { let _ = self.get_client(token).reregister(evl); },
-                Err(_)      => {},
-            }
+        if event.is_writable() {
+            self.writable(evl, token);
         }
     }
 
-    fn notify(&mut self, evl: &mut EventLoop<Canteen>, msg: (Token, Vec<u8>)) {
-        let (token, output) = msg;
-        let client = self.get_client(token);
+    fn notify(&mut self, evl: &mut EventLoop, notice: Notice) {
+        match notice {
+            Notice::Reply(reply)                            => self.reply(evl, reply),
+            Notice::Upgrade(token, seq, handshake, session) => self.start_websocket(evl, token, seq, handshake, session),
+            Notice::Frame(token, seq, frame, close)         => self.send_frame(evl, token, seq, frame, close),
+            Notice::Chunk(token, seq, stream, data, end)    => self.send_chunk(evl, token, seq, stream, data, end),
+            Notice::Shutdown                                => self.check_shutdown(evl),
+        }
+    }
 
-        client.o_buf = output;
-        let _ = client.reregister(evl);
+    fn timeout(&mut self, evl: &mut EventLoop, token: Token) {
+        if token == DRAIN {
+            // the deadline has passed; anything still open is abandoned
+            return evl.shutdown();
+        }
+
+        self.check_shutdown(evl);
+
+        let (phase, idle) = match self.conns.get_mut(token) {
+            Some(client) => {
+                client.timer = None;
+                (client.phase, client.i_buf.is_empty())
+            },
+            None         => return,
+        };
+
+        match phase {
+            // nothing was sent since the connection opened, or since the last
+            // response on a kept-alive connection
+            Phase::Header if idle   => self.reset_connection(evl, token),
+            Phase::Header           |
+            Phase::Body             => {
+                let req = client::head(&self.get_client(token).i_buf);
+                let responder = self.start_request(evl, token, false);
+
+                self.dispatch_error(responder, req, ErrorContext::new(408, "request timeout"));
+            },
+            Phase::Handling         => self.expire_handler(evl, token),
+            Phase::Streaming        => self.keep_alive_events(evl, token),
+            Phase::Idle             |
+            Phase::Writing          |
+            Phase::WebSocket        => self.reset_connection(evl, token),
+            #[cfg(feature = "http2")]
+            Phase::Http2            => self.expire_h2(evl, token),
+        }
     }
 }
 
 impl Canteen {
-    /// Creates a new Canteen instance.
+    /// Creates a new Canteen instance with the default configuration.
     ///
     /// # Examples
     ///
@@ -264,199 +295,2097 @@ impl Canteen {
     /// let cnt = Canteen::new();
     /// ```
     pub fn new() -> Canteen {
+        Canteen::with_config(ServerConfig::default())
+    }
+
+    /// Creates a builder for a Canteen instance with custom settings, such as
+    /// the number of worker threads or the maximum request size.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::Canteen;
+    ///
+    /// let cnt = Canteen::builder().workers(4).build().unwrap();
+    /// ```
+    pub fn builder() -> CanteenBuilder {
+        CanteenBuilder::new()
+    }
+
+    fn with_config(config: ServerConfig) -> Canteen {
         Canteen {
             routes:  HashMap::new(),
             rcache:  HashMap::new(),
-            server:  None,
-            token:   Token(1),
-            conns:   Slab::new_starting_at(Token(2), 2048),
-            default: utils::err_404,
-            tpool:   ThreadPool::new(255),
+            servers: Vec::new(),
+            conns:   Clients::new(Token(2), config.max_connections),
+            default: None,
+            errors:  Arc::new(HashMap::new()),
+            state:   Arc::new(extract::StateMap::default()),
+            tpool:   ThreadPool::with_name(String::from("canteen-worker"), config.workers),
+            asyncex: None,
+            config,
+            failure: None,
+            stopper: ShutdownHandle::new(),
+            stats:   ServerStats::new(),
+            peers:   HashMap::new(),
+            drain:   false,
+            #[cfg(feature = "compression")]
+            compress: None,
         }
     }
 
-    /// Bind to an address on which to listen for connections
+    /// The settings this instance was created with.
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Returns a handle that stops the server once `run` has been called.
+    ///
     /// # Examples
-    /// ```rust,ignore
+    ///
+    /// ```rust,no_run
     /// use canteen::Canteen;
     ///
     /// let mut cnt = Canteen::new();
-    /// cnt.bind(("127.0.0.1", 8080));
+    /// let handle = cnt.shutdown_handle();
+    ///
+    /// // e.g. from a thread waiting for SIGTERM
+    /// handle.shutdown();
+    ///
+    /// cnt.bind(("127.0.0.1", 8080)).unwrap();
+    /// cnt.run().unwrap();
     /// ```
-    pub fn bind<A: ToSocketAddrs>(&mut self, addr: A) {
-        self.server = Some(TcpListener::bind(&addr.to_socket_addrs().unwrap().next().unwrap()).unwrap());
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.stopper.clone()
     }
 
+    /// Returns a handle for reading the server's connection counters.
+    pub fn stats(&self) -> ServerStats {
+        self.stats.clone()
+    }
 
-    /// Adds a new route definition to be handled by Canteen.
+    /// Accept request bodies of up to `size` bytes on the routes defined for a
+    /// path, rather than the server-wide `max_body_size`. Oversized bodies are
+    /// rejected with a 413 as soon as their headers arrive.
     ///
     /// # Examples
     ///
     /// ```rust
-    /// use canteen::{Canteen, Request, Response, Method};
+    /// use canteen::{Canteen, Method};
     /// use canteen::utils;
     ///
-    /// fn handler(_: &Request) -> Response {
-    ///     utils::make_response("<b>Hello, world!</b>", "text/html", 200)
-    /// }
+    /// let mut cnt = Canteen::new();
     ///
-    /// fn main() {
-    ///     let mut cnt = Canteen::new();
-    ///     cnt.add_route("/hello", &[Method::Get], handler);
-    /// }
+    /// cnt.add_route("/upload", &[Method::Post], utils::err_404)
+    ///    .limit_route_body("/upload", 512 * 1024 * 1024);
     /// ```
-    pub fn add_route(&mut self, path: &str, mlist: &[Method],
-                     handler: fn(&Request) -> Response) -> &mut Canteen {
-        let mut methods: HashSet<Method> = HashSet::new();
+    ///
+    /// # Panics
+    ///
+    /// Panics if no route has been added for the path.
+    pub fn limit_route_body(&mut self, path: &str, size: usize) -> &mut Canteen {
+        let mut found = false;
 
-        // make them unique
-        for m in mlist {
-            methods.insert(*m);
+        for (rd, route) in self.routes.iter_mut() {
+            if rd.pathdef == path {
+                route.set_max_body_size(size);
+                found = true;
+            }
         }
 
-        for m in methods {
-            let rd = route::RouteDef {
-                pathdef: String::from(path),
-                method:  m,
-            };
+        if !found {
+            panic!("no routes defined for path: {}", path);
+        }
 
-            if self.routes.contains_key(&rd) {
-                panic!("a route handler for {} has already been defined!", path);
+        self
+    }
+
+    /// Check the preconditions of requests on the routes defined for a path
+    /// before their handlers run, going by the tag and last modification date
+    /// `validator` gives for the resource's current version. This covers
+    /// methods that change the resource, such as PUT and DELETE: a stale
+    /// `If-Match` is answered with a 412 and the handler isn't run.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::{Canteen, Method};
+    /// use canteen::conditional::ETag;
+    /// use canteen::utils;
+    ///
+    /// let mut cnt = Canteen::new();
+    ///
+    /// cnt.add_route("/doc", &[Method::Get, Method::Put], utils::err_404)
+    ///    .validate_route("/doc", |_| (Some(ETag::strong("v1")), None));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if no route has been added for the path.
+    pub fn validate_route<F>(&mut self, path: &str, validator: F) -> &mut Canteen
+            where F: Fn(&Request) -> (Option<conditional::ETag>, Option<chrono::DateTime<chrono::Utc>>) + Send + Sync + 'static {
+        let validator: conditional::Validator = Arc::new(validator);
+        let mut found = false;
+
+        for (rd, route) in self.routes.iter_mut() {
+            if rd.pathdef == path {
+                route.set_validator(validator.clone());
+                found = true;
             }
+        }
 
-            self.routes.insert(rd, route::Route::new(&path, m, handler));
+        if !found {
+            panic!("no routes defined for path: {}", path);
         }
 
         self
     }
 
-    /// Defines a default route for undefined paths.
+    /// Bind to an address on which to listen for connections. This can be
+    /// called more than once to listen on several addresses.
     ///
     /// # Examples
     ///
-    /// ```rust
+    /// ```rust,no_run
     /// use canteen::Canteen;
+    ///
+    /// let mut cnt = Canteen::new();
+    ///
+    /// if let Err(err) = cnt.bind(("127.0.0.1", 8080)) {
+    ///     eprintln!("{}", err);
+    /// }
+    /// cnt.bind(("::1", 8080)).unwrap();
+    /// ```
+    pub fn bind<A: ToSocketAddrs>(&mut self, addr: A) -> Result<(), ServerError> {
+        self.bind_tcp(None, addr)
+    }
+
+    /// Bind to an address under a name, so that routes can be restricted to
+    /// it with `restrict_route`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use canteen::{Canteen, Method};
     /// use canteen::utils;
     ///
     /// let mut cnt = Canteen::new();
-    /// cnt.set_default(utils::err_404);
+    ///
+    /// cnt.bind(("0.0.0.0", 8080)).unwrap();
+    /// cnt.bind_named("admin", ("127.0.0.1", 9090)).unwrap();
+    ///
+    /// // only reachable through 127.0.0.1:9090
+    /// cnt.add_route("/metrics", &[Method::Get], utils::err_404)
+    ///    .restrict_route("/metrics", &["admin"]);
     /// ```
-    pub fn set_default(&mut self, handler: fn(&Request) -> Response) -> &mut Canteen {
-        self.default = handler;
+    pub fn bind_named<A: ToSocketAddrs>(&mut self, name: &str, addr: A) -> Result<(), ServerError> {
+        self.bind_tcp(Some(name.to_string()), addr)
+    }
 
-        self
+    /// Listen on a Unix domain socket. A stale socket file left at the path
+    /// is replaced, and the file is removed when the server shuts down. Only
+    /// available on Unix.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use canteen::Canteen;
+    ///
+    /// let mut cnt = Canteen::new();
+    /// cnt.bind_unix("/run/canteen.sock").unwrap();
+    /// ```
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(&mut self, path: P) -> Result<(), ServerError> {
+        self.bind_unix_as(None, path.as_ref())
     }
 
-    fn get_client(&mut self, token: Token) -> &mut Client {
-        self.conns.get_mut(token).unwrap()
+    /// Listen on a Unix domain socket under a name, so that routes can be
+    /// restricted to it with `restrict_route`. Only available on Unix.
+    #[cfg(unix)]
+    pub fn bind_unix_named<P: AsRef<Path>>(&mut self, name: &str, path: P) -> Result<(), ServerError> {
+        self.bind_unix_as(Some(name.to_string()), path.as_ref())
     }
 
-    fn accept(&mut self) -> Result<TcpStream> {
-        if let Some(ref server) = self.server {
-            if let Ok(s) = server.accept() {
-                if let Some((sock, _)) = s {
-                    return Ok(sock);
-                }
-            }
+    /// Bind to an address on which to listen for HTTPS connections, using
+    /// the certificates in `tls`. Requires the `tls` feature.
+    #[cfg(feature = "tls")]
+    pub fn bind_tls<A: ToSocketAddrs>(&mut self, addr: A, tls: &tls::TlsConfig) -> Result<(), ServerError> {
+        self.bind_tls_as(None, addr, tls)
+    }
+
+    /// Bind to an address for HTTPS connections under a name, so that routes
+    /// can be restricted to it with `restrict_route`. Requires the `tls`
+    /// feature.
+    #[cfg(feature = "tls")]
+    pub fn bind_tls_named<A: ToSocketAddrs>(&mut self, name: &str, addr: A, tls: &tls::TlsConfig) -> Result<(), ServerError> {
+        self.bind_tls_as(Some(name.to_string()), addr, tls)
+    }
+
+    fn resolve<A: ToSocketAddrs>(addr: A) -> Result<SocketAddr, ServerError> {
+        match addr.to_socket_addrs().map_err(ServerError::Resolve)?.next() {
+            Some(addr)  => Ok(addr),
+            None        => Err(ServerError::Resolve(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "address resolved to nothing"
+            ))),
         }
+    }
 
-        Err(std::io::Error::new(
-            std::io::ErrorKind::ConnectionAborted,
-            "connection aborted prematurely".to_string()
-        ))
+    fn bind_tcp<A: ToSocketAddrs>(&mut self, name: Option<String>, addr: A) -> Result<(), ServerError> {
+        let addr = Canteen::resolve(addr)?;
+        let listener = Listener::tcp(&addr, self.config.backlog).map_err(ServerError::Bind)?;
+
+        self.servers.push(Bound { name, listener });
+        Ok(())
     }
 
-    fn handle_request(&mut self, token: Token, tx: Sender<(Token, Vec<u8>)>, rqstr: &str) {
-        let mut req = Request::from_str(&rqstr).unwrap();
-        let mut handler: fn(&Request) -> Response = self.default;
-        let resolved = route::RouteDef {
-            pathdef: req.path.clone(),
-            method:  req.method,
-        };
+    #[cfg(feature = "tls")]
+    fn bind_tls_as<A: ToSocketAddrs>(&mut self, name: Option<String>, addr: A, tls: &tls::TlsConfig) -> Result<(), ServerError> {
+        let addr = Canteen::resolve(addr)?;
+        let config = tls.server_config().map_err(ServerError::Bind)?;
+        let listener = Listener::tls(&addr, self.config.backlog, config).map_err(ServerError::Bind)?;
 
-        if self.rcache.contains_key(&resolved) {
-            let route = &self.routes[&self.rcache[&resolved]];
+        self.servers.push(Bound { name, listener });
+        Ok(())
+    }
 
-            handler = route.handler;
-            req.params = route.parse(&req.path);
-        } else {
-            for (path, route) in &self.routes {
-                if route.is_match(&req) {
-                    handler = route.handler;
-                    req.params = route.parse(&req.path);
-                    self.rcache.insert(resolved, (*path).clone());
-                    break;
-                }
-            }
-        }
+    #[cfg(unix)]
+    fn bind_unix_as(&mut self, name: Option<String>, path: &Path) -> Result<(), ServerError> {
+        let listener = Listener::unix(path).map_err(ServerError::Bind)?;
+        self.servers.push(Bound { name, listener });
 
-        self.tpool.execute(move || {
-            let _ = tx.send((token, handler(&req).gen_output()));
-        });
+        Ok(())
     }
 
-    fn readable(&mut self, evl: &mut EventLoop<Canteen>, token: Token) -> Result<bool> {
-        if let Ok(true) = self.get_client(token).receive() {
-            let buf = self.get_client(token).i_buf.clone();
-            if let Ok(rqstr) = String::from_utf8(buf) {
-                self.handle_request(token, evl.channel(), &rqstr);
-            } else {
-                return Ok(false);
+    /// Only serve the routes defined for a path on the named listeners. Every
+    /// method registered for the path is restricted; routes that aren't
+    /// restricted are served on all listeners.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no route has been added for the path.
+    pub fn restrict_route(&mut self, path: &str, listeners: &[&str]) -> &mut Canteen {
+        let mut found = false;
+
+        for (rd, route) in self.routes.iter_mut() {
+            if rd.pathdef == path {
+                route.restrict(listeners);
+                found = true;
             }
         }
 
-        Ok(true)
+        if !found {
+            panic!("no routes defined for path: {}", path);
+        }
+
+        self.rcache.clear();
+        self
     }
 
-    fn reset_connection(&mut self, token: Token) {
-        // kill the connection
-        self.conns.remove(token);
+    /// Adds a new route definition to be handled by Canteen. The handler may
+    /// return anything that implements `IntoResponse`, including a `Result`
+    /// whose error type does, so that `?` can be used inside it. It may take
+    /// either a `&Request` or up to six arguments implementing
+    /// `extract::FromRequest`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::{Canteen, Request, Response, RequestError, Method};
+    /// use canteen::utils;
+    ///
+    /// fn handler(_: &Request) -> Response {
+    ///     utils::make_response("<b>Hello, world!</b>", "text/html", 200)
+    /// }
+    ///
+    /// fn echo(req: &Request) -> Result<serde_json::Value, RequestError> {
+    ///     Ok(req.get_json()?)
+    /// }
+    ///
+    /// fn main() {
+    ///     let mut cnt = Canteen::new();
+    ///     cnt.add_route("/hello", &[Method::Get], handler)
+    ///        .add_route("/echo", &[Method::Post], echo);
+    /// }
+    /// ```
+    pub fn add_route<H, Args>(&mut self, path: &str, mlist: &[Method], handler: H) -> &mut Canteen
+            where H: extract::HandlerFn<Args> {
+        let handler: route::RouteHandler = Arc::new(move |req: &Request| handler.call(req));
+
+        self.insert_route(path, mlist, route::Endpoint::Sync(handler))
     }
 
-    fn register(&mut self, evl: &mut EventLoop<Canteen>) -> Result<()> {
-        if let Some(ref server) = self.server {
-            return evl.register(server, self.token, EventSet::readable(), PollOpt::edge() | PollOpt::oneshot());
-        }
+    /// Adds a new route definition with an async handler. Rather than occupying
+    /// a threadpool worker for its whole lifetime, the handler's future is run
+    /// on a small executor, so it can wait on slow I/O without blocking other
+    /// requests. The executor is only started if an async route is added.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::{Canteen, Request, Method};
+    ///
+    /// async fn slow_hello(req: Request) -> String {
+    ///     format!("Hello, {}!", req.path)
+    /// }
+    ///
+    /// let mut cnt = Canteen::new();
+    /// cnt.add_async_route("/hello", &[Method::Get], slow_hello);
+    /// ```
+    pub fn add_async_route<F, Fut, R>(&mut self, path: &str, mlist: &[Method], handler: F) -> &mut Canteen
+            where F: Fn(Request) -> Fut + Send + Sync + 'static,
+                  Fut: Future<Output = R> + Send + 'static,
+                  R: IntoResponse {
+        let handler: route::AsyncRouteHandler = Arc::new(move |req: Request| {
+            let fut = handler(req);
+            Box::pin(async move { fut.await.into_response() })
+        });
 
-        Ok(())
+        self.insert_route(path, mlist, route::Endpoint::Async(handler))
+    }
+
+    /// Adds a WebSocket endpoint. GET requests to the path that ask for a
+    /// WebSocket are upgraded, and the handler is then run on a thread of its
+    /// own with a `WebSocket` for exchanging messages; the connection closes
+    /// when the handler returns. Messages larger than `max_message_size`
+    /// close the connection.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::{Canteen, Request};
+    /// use canteen::websocket::WebSocket;
+    ///
+    /// fn greet(req: &Request, ws: WebSocket) {
+    ///     let _ = ws.send(format!("hello from {}", req.path));
+    /// }
+    ///
+    /// let mut cnt = Canteen::new();
+    /// cnt.add_websocket("/greet", greet);
+    /// ```
+    pub fn add_websocket<H>(&mut self, path: &str, handler: H) -> &mut Canteen
+            where H: Fn(&Request, WebSocket) + Send + Sync + 'static {
+        let handler: route::WebSocketHandler = Arc::new(handler);
+
+        self.insert_route(path, &[Method::Get], route::Endpoint::WebSocket(handler))
     }
 
-    fn reregister(&mut self, evl: &mut EventLoop<Canteen>) {
-        if let Some(ref server) = self.server {
-            evl.reregister(server, self.token,
-                                 EventSet::readable(),
-                                 PollOpt::edge() | PollOpt::oneshot()).ok();
+    /// Serves the files in `dir` under the URL prefix `prefix`, so that with
+    /// the prefix `"/static"`, a request for `/static/css/site.css` is sent
+    /// `dir/css/site.css`. `options` controls index files, directory
+    /// listings, hidden files and symbolic links.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::Canteen;
+    /// use canteen::fileserver::StaticOptions;
+    ///
+    /// let mut cnt = Canteen::new();
+    /// cnt.mount_static("/static", "./public", StaticOptions::new().index_files(&["index.html", "index.htm"]));
+    /// ```
+    pub fn mount_static<P: AsRef<Path>>(&mut self, prefix: &str, dir: P, options: fileserver::StaticOptions) -> &mut Canteen {
+        let mount = Arc::new(fileserver::Mount::new(prefix, dir.as_ref(), options));
+        let routes = mount.routes();
+        let handler: route::RouteHandler = Arc::new(move |req: &Request| Ok(mount.serve(req)));
+
+        for path in routes.iter() {
+            self.insert_route(path, &[Method::Get], route::Endpoint::Sync(handler.clone()));
         }
+
+        self
     }
 
-    /// Creates the listener and starts a Canteen server's event loop.
+    /// Compresses handlers' responses for clients that accept it, in the
+    /// encoding they prefer. `options` controls which responses are worth
+    /// compressing. Requires the `compression` feature.
     ///
     /// # Examples
     ///
     /// ```rust
     /// use canteen::Canteen;
+    /// use canteen::compress::{CompressOptions, Encoding};
     ///
     /// let mut cnt = Canteen::new();
-    /// cnt.run();
+    /// cnt.compress(CompressOptions::new().encodings(&[Encoding::Gzip, Encoding::Deflate]));
     /// ```
-    pub fn run(&mut self) {
-        let mut evl = match EventLoop::new() {
-            Ok(event_loop)  => event_loop,
-            Err(_)          => panic!("unable to initiate event loop"),
-        };
+    #[cfg(feature = "compression")]
+    pub fn compress(&mut self, options: compress::CompressOptions) -> &mut Canteen {
+        self.compress = Some(Arc::new(options));
+        self
+    }
 
-        match self.server {
-            None    => println!("server not bound to an address!"),
-            Some(_) => {
-                self.register(&mut evl).ok();
-                evl.run(self).unwrap();
-            },
-        };
+    fn insert_route(&mut self, path: &str, mlist: &[Method], handler: route::Endpoint) -> &mut Canteen {
+        let mut methods: HashSet<Method> = HashSet::new();
+
+        // make them unique
+        for m in mlist {
+            methods.insert(*m);
+        }
+
+        for m in methods {
+            let rd = route::RouteDef {
+                pathdef: String::from(path),
+                method:  m,
+            };
+
+            if self.routes.contains_key(&rd) {
+                panic!("a route handler for {} has already been defined!", path);
+            }
+
+            self.routes.insert(rd, route::Route::with_handler(path, m, handler.clone()));
+        }
+
+        self
     }
-}
 
-impl Default for Canteen {
-    fn default() -> Self {
-        Canteen::new()
+    /// Registers a value as shared application state, available to handlers
+    /// through the `extract::State<T>` extractor. Only one value of each type
+    /// can be managed; registering another replaces it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::{Canteen, Method};
+    /// use canteen::extract::State;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// fn hits(State(counter): State<AtomicUsize>) -> String {
+    ///     format!("{} hits", counter.fetch_add(1, Ordering::SeqCst) + 1)
+    /// }
+    ///
+    /// let mut cnt = Canteen::new();
+    /// cnt.manage(AtomicUsize::new(0));
+    /// cnt.add_route("/hits", &[Method::Get], hits);
+    /// ```
+    pub fn manage<T: Send + Sync + 'static>(&mut self, state: T) -> &mut Canteen {
+        Arc::make_mut(&mut self.state).insert(state);
+
+        self
+    }
+
+    /// Defines a default route for undefined paths. If no default is set,
+    /// unmatched paths are answered by the error handler for 404.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::Canteen;
+    /// use canteen::utils;
+    ///
+    /// let mut cnt = Canteen::new();
+    /// cnt.set_default(utils::err_404);
+    /// ```
+    pub fn set_default(&mut self, handler: fn(&Request) -> Response) -> &mut Canteen {
+        self.default = Some(handler);
+
+        self
+    }
+
+    /// Defines the handler used to render error responses with a given HTTP
+    /// status, such as 400 for malformed requests, 404 for unmatched paths,
+    /// 405 for unsupported methods, or 500 for handlers that panic. Statuses
+    /// without a handler use `utils::err_default`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::{Canteen, Request, Response, ErrorContext};
+    /// use canteen::utils;
+    ///
+    /// fn oops(req: &Request, err: &ErrorContext) -> Response {
+    ///     utils::make_response(format!("oops, {} broke it", req.path), "text/plain", err.status)
+    /// }
+    ///
+    /// let mut cnt = Canteen::new();
+    /// cnt.error_handler(500, oops);
+    /// ```
+    pub fn error_handler(&mut self, status: u16, handler: ErrorHandler) -> &mut Canteen {
+        Arc::make_mut(&mut self.errors).insert(status, handler);
+
+        self
+    }
+
+    fn get_error_handler(&self, status: u16) -> ErrorHandler {
+        match self.errors.get(&status) {
+            Some(handler) => *handler,
+            None          => utils::err_default,
+        }
+    }
+
+    fn get_client(&mut self, token: Token) -> &mut Client {
+        self.conns.get_mut(token).unwrap()
+    }
+
+    // take the next pending connection off a listener. errors that only
+    // concern the connection being accepted aren't fatal to the server.
+    fn accept(&mut self, index: usize) -> io::Result<Accepted> {
+        let server = match self.servers.get(index) {
+            Some(server) => server,
+            None         => return Ok(Accepted::Drained),
+        };
+
+        match server.listener.accept() {
+            Ok(Some((sock, peer)))  => Ok(Accepted::Stream(sock, peer)),
+            Ok(None)                => Ok(Accepted::Drained),
+            Err(err)                => match err.kind() {
+                io::ErrorKind::WouldBlock        => Ok(Accepted::Drained),
+                io::ErrorKind::ConnectionAborted |
+                io::ErrorKind::ConnectionReset   |
+                io::ErrorKind::Interrupted       => Ok(Accepted::Skipped),
+                _                                => Err(err),
+            },
+        }
+    }
+
+    // give a new connection a client slot, or turn it away if there are none
+    // left or its peer already has too many.
+    fn admit(&mut self, evl: &mut EventLoop, index: usize, sock: Stream, peer: Option<IpAddr>) {
+        if self.conns.count() >= self.config.max_connections {
+            return self.refuse(sock, "server at connection limit");
+        }
+
+        if let (Some(ip), Some(max)) = (peer, self.config.max_connections_per_ip) {
+            if self.peers.get(&ip).cloned().unwrap_or(0) >= max {
+                return self.refuse(sock, "too many connections from this address");
+            }
+        }
+
+        let name = self.servers[index].name.clone();
+        let chunk = self.config.read_buffer_size;
+
+        let token = match self.conns.insert_with(|token| Client::new(sock, token, name, peer, chunk)) {
+            Some(token) => token,
+            None        => return,
+        };
+
+        if let Some(ip) = peer {
+            *self.peers.entry(ip).or_insert(0) += 1;
+        }
+
+        self.stats.on_accept();
+
+        if self.get_client(token).register(evl).is_ok() {
+            self.enter(evl, token, Phase::Header);
+        } else {
+            self.reset_connection(evl, token);
+        }
+    }
+
+    // answer a connection we have no room for with a 503. this happens on the
+    // event loop, so it's a single non-blocking write of the built-in error
+    // page rather than a call to a user-defined handler. the page is built
+    // in memory, so it can't fail to read, but if it ever does the
+    // connection is just closed.
+    fn refuse(&mut self, mut sock: Stream, reason: &str) {
+        let res = self.overloaded(&Request::new(), reason);
+
+        if let Ok(output) = res.gen_output() {
+            let _ = sock.write(&output);
+        }
+        self.stats.on_refuse();
+    }
+
+    // the built-in 503 page, telling the client when to try again.
+    fn overloaded(&self, req: &Request, reason: &str) -> Response {
+        let ctx = ErrorContext::new(503, reason);
+        let mut res = utils::err_default(req, &ctx);
+        let delay = self.config.retry_after.as_secs().max(1);
+
+        res.add_header("Connection", "close");
+        res.add_header("Retry-After", &delay.to_string());
+        res
+    }
+
+    // whether the threadpool has as much queued work as it's allowed.
+    fn saturated(&self) -> bool {
+        self.tpool.queued_count() >= self.config.max_queued_jobs
+    }
+
+    // answer a request with a 503 from the event loop, without queueing it.
+    fn shed(&mut self, mut responder: Responder, req: &Request) {
+        responder.keep_alive = false;
+        responder.send(self.overloaded(req, "server overloaded"));
+        self.stats.on_shed();
+    }
+
+    fn handle_request(&mut self, mut responder: Responder, listener: Option<String>, raw: &[u8]) {
+        let mut req = match Request::from_bytes(raw) {
+            Ok(req)     => req,
+            Err(err)    => {
+                let ctx = ErrorContext::new(400, format!("bad request ({})", err));
+                responder.keep_alive = false;
+                self.dispatch_error(responder, Request::new(), ctx);
+                return;
+            },
+        };
+
+        responder.keep_alive = responder.keep_alive && req.wants_keep_alive();
+        req.listener = listener;
+
+        let limit = Canteen::body_limit(&self.routes, &self.config, req.method, &req.path, req.listener());
+
+        if let Err(err) = req.decode_payload(limit) {
+            let ctx = match err {
+                compress::DecodeError::Unsupported(name)    => ErrorContext::new(415, format!("unsupported content encoding {:?}", name)),
+                compress::DecodeError::TooLarge             => ErrorContext::new(413, "request body too large"),
+                compress::DecodeError::Corrupt(encoding)    => ErrorContext::new(400, format!("bad request (corrupt {} body)", encoding.name())),
+            };
+
+            return self.dispatch_error(responder, req, ctx);
+        }
+
+        let mut handler: Option<route::Endpoint> = None;
+        let mut validator: Option<conditional::Validator> = None;
+        let resolved = (req.listener.clone(), route::RouteDef {
+            pathdef: req.path.clone(),
+            method:  req.method,
+        });
+
+        if self.rcache.contains_key(&resolved) {
+            let route = &self.routes[&self.rcache[&resolved]];
+
+            handler = Some(route.handler.clone());
+            validator = route.validator();
+            req.params = route.parse(&req.path);
+            req.param_order = route.param_names();
+        } else {
+            for (path, route) in &self.routes {
+                if route.is_match(&req) {
+                    handler = Some(route.handler.clone());
+                    validator = route.validator();
+                    req.params = route.parse(&req.path);
+                    req.param_order = route.param_names();
+                    self.rcache.insert(resolved, (*path).clone());
+                    break;
+                }
+            }
+        }
+
+        let default = self.default.map(|d| route::Endpoint::Sync(Arc::new(move |req: &Request| Ok(d(req)))));
+        req.state = self.state.clone();
+
+        match handler.or(default) {
+            Some(route::Endpoint::Sync(handler))      => return self.dispatch(responder, req, handler, validator),
+            Some(route::Endpoint::Async(handler))     => return self.dispatch_async(responder, req, handler, validator),
+            Some(route::Endpoint::WebSocket(handler)) => return self.upgrade(responder, req, handler),
+            None                                      => {},
+        }
+
+        let mut allowed: Vec<String> = self.routes.values()
+                                                  .filter(|route| route.is_path_match(&req.path))
+                                                  .filter(|route| route.allows(req.listener()))
+                                                  .map(|route| route.method().to_string())
+                                                  .collect();
+
+        if allowed.is_empty() {
+            self.dispatch_error(responder, req, ErrorContext::new(404, "not found"));
+        } else {
+            allowed.sort();
+            allowed.dedup();
+
+            if self.saturated() {
+                return self.shed(responder, &req);
+            }
+
+            let allow = allowed.join(", ");
+            let handler = self.get_error_handler(405);
+
+            self.tpool.execute(move || {
+                let ctx = ErrorContext::new(405, "method not allowed");
+                let mut res = Canteen::run_error_handler(handler, &req, &ctx);
+
+                res.add_header("Allow", &allow);
+                responder.send(res);
+            });
+        }
+    }
+
+    // run the handler for a request on the threadpool, once the route's
+    // validator, if any, has let it through. requests rejected by an
+    // extractor or a precondition go to the error handler for the
+    // rejection's status, and panics go to the one for 500.
+    fn dispatch(&mut self, responder: Responder, req: Request, handler: route::RouteHandler,
+                validator: Option<conditional::Validator>) {
+        if self.saturated() {
+            return self.shed(responder, &req);
+        }
+
+        let errors = self.errors.clone();
+        #[cfg(feature = "compression")]
+        let compress = self.compress.clone();
+
+        self.tpool.execute(move || {
+            let run = || match validator.as_ref().and_then(|validator| conditional::precheck(&req, validator)) {
+                Some(checked)   => checked,
+                None            => handler(&req),
+            };
+
+            let ctx = match panic::catch_unwind(panic::AssertUnwindSafe(run)) {
+                Ok(Ok(mut res)) => {
+                    #[cfg(feature = "compression")]
+                    if let Some(ref options) = compress {
+                        res.compress(&req, options);
+                    }
+
+                    res.finish(&req);
+                    return responder.send(res);
+                },
+                Ok(Err(ctx))    => ctx,
+                Err(_)          => ErrorContext::new(500, "internal server error"),
+            };
+
+            let on_error = errors.get(&ctx.status).cloned().unwrap_or(utils::err_default);
+            responder.send(Canteen::run_error_handler(on_error, &req, &ctx));
+        });
+    }
+
+    // hand the future of an async handler to the executor. it notifies the
+    // event loop over the same channel as the threadpool when it completes.
+    fn dispatch_async(&mut self, responder: Responder, req: Request, handler: route::AsyncRouteHandler,
+                      validator: Option<conditional::Validator>) {
+        let errors = self.errors.clone();
+        let on_panic = self.get_error_handler(500);
+        let head = req.head();
+        let executor = self.asyncex.as_ref().expect("async route without an executor");
+        #[cfg(feature = "compression")]
+        let compress = self.compress.clone();
+
+        executor.spawn(async move {
+            // the validator is run here too, so that it may block
+            let checked = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                validator.and_then(|validator| conditional::precheck(&head, &validator))
+            }));
+
+            let res = match checked {
+                Ok(Some(Ok(res)))   => Ok(res),
+                Ok(Some(Err(ctx)))  => {
+                    let on_error = errors.get(&ctx.status).cloned().unwrap_or(utils::err_default);
+                    return responder.send(Canteen::run_error_handler(on_error, &head, &ctx));
+                },
+                Ok(None)            => match panic::catch_unwind(panic::AssertUnwindSafe(|| handler(req))) {
+                    Ok(fut)  => executor::CatchUnwind(fut).await,
+                    Err(err) => Err(err),
+                },
+                Err(err)            => Err(err),
+            };
+
+            let res = match res {
+                Ok(mut res) => {
+                    #[cfg(feature = "compression")]
+                    if let Some(ref options) = compress {
+                        res.compress(&head, options);
+                    }
+
+                    res.finish(&head);
+                    res
+                },
+                Err(_)      => {
+                    let ctx = ErrorContext::new(500, "internal server error");
+                    Canteen::run_error_handler(on_panic, &head, &ctx)
+                },
+            };
+
+            responder.send(res);
+        });
+    }
+
+    fn dispatch_error(&mut self, responder: Responder, req: Request, ctx: ErrorContext) {
+        if self.saturated() {
+            return self.shed(responder, &req);
+        }
+
+        let handler = self.get_error_handler(ctx.status);
+
+        self.tpool.execute(move || {
+            responder.send(Canteen::run_error_handler(handler, &req, &ctx));
+        });
+    }
+
+    // a misbehaving error handler shouldn't leave the client hanging, so fall
+    // back to the built-in one if it panics.
+    fn run_error_handler(handler: ErrorHandler, req: &Request, ctx: &ErrorContext) -> Response {
+        match panic::catch_unwind(panic::AssertUnwindSafe(|| handler(req, ctx))) {
+            Ok(res) => res,
+            Err(_)  => utils::err_default(req, ctx),
+        }
+    }
+
+    fn readable(&mut self, evl: &mut EventLoop, token: Token) {
+        let body = match self.get_client(token).phase {
+            // room for the frame carrying the longest message allowed
+            Phase::WebSocket    => self.config.max_message_size,
+            _                   => self.get_client(token).body_limit.unwrap_or(self.config.max_body_size),
+        };
+        let limit = self.config.max_header_size + body;
+
+        if self.get_client(token).receive(limit).is_err() {
+            return self.reset_connection(evl, token);
+        }
+
+        let client = self.get_client(token);
+
+        // only HTTP/1.x requests are still answered once the client has
+        // stopped sending
+        if client.eof && !matches!(client.phase, Phase::Idle | Phase::Header | Phase::Body) {
+            return self.reset_connection(evl, token);
+        }
+
+        self.process(evl, token);
+    }
+
+    // check whether the client has sent a full request yet, and dispatch it
+    // if so.
+    fn process(&mut self, evl: &mut EventLoop, token: Token) {
+        let (phase, idle) = {
+            let client = self.get_client(token);
+            (client.phase, client.i_buf.is_empty())
+        };
+
+        if phase == Phase::WebSocket {
+            return self.process_ws(evl, token);
+        }
+
+        if phase == Phase::Streaming {
+            // an event stream only goes one way
+            let client = self.get_client(token);
+
+            client.i_buf.clear();
+            let _ = client.reregister(evl);
+            return;
+        }
+
+        if phase == Phase::Idle && !idle {
+            // the next request has started arriving
+            self.enter(evl, token, Phase::Header);
+        }
+
+        #[cfg(feature = "http2")]
+        {
+            if self.detect_h2(evl, token) {
+                return self.process_h2(evl, token);
+            }
+        }
+
+        let framing = {
+            let routes = &self.routes;
+            let config = &self.config;
+            let client = self.conns.get_mut(token).unwrap();
+            let listener = client.listener.as_deref();
+            let cached = &mut client.body_limit;
+
+            client::frame(&client.i_buf, config, |method, path| {
+                *cached.get_or_insert_with(|| Canteen::body_limit(routes, config, method, path, listener))
+            })
+        };
+
+        match framing {
+            Framing::Incomplete | Framing::AwaitingBody if self.get_client(token).eof => {
+                // the rest of the request is never coming
+                self.reset_connection(evl, token);
+            },
+            Framing::Incomplete | Framing::AwaitingBody => {
+                if framing == Framing::AwaitingBody && self.get_client(token).phase == Phase::Header {
+                    self.enter(evl, token, Phase::Body);
+                }
+
+                let client = self.get_client(token);
+
+                client.events.insert(Interest::READABLE);
+                let _ = client.reregister(evl);
+            },
+            Framing::Complete(len) => {
+                let raw: Vec<u8> = self.get_client(token).i_buf.drain(..len).collect();
+                let listener = self.get_client(token).listener.clone();
+                // a client that has stopped sending is closed on after its
+                // last buffered request
+                let more = !self.get_client(token).eof || !self.get_client(token).i_buf.is_empty();
+                let keep_alive = more && self.config.keep_alive.is_some() && !self.drain;
+                let responder = self.start_request(evl, token, keep_alive);
+
+                self.handle_request(responder, listener, &raw);
+            },
+            Framing::Reject(status, message) => {
+                // a request line or headers too long to accept aren't worth
+                // parsing
+                let req = match status {
+                    414 | 431   => Request::new(),
+                    _           => client::head(&self.get_client(token).i_buf),
+                };
+                let responder = self.start_request(evl, token, false);
+
+                self.dispatch_error(responder, req, ErrorContext::new(status, message));
+            },
+        }
+    }
+
+    // stop reading from the client while its request is being handled.
+    fn start_request(&mut self, evl: &mut EventLoop, token: Token, keep_alive: bool) -> Responder {
+        let seq = {
+            let client = self.get_client(token);
+
+            client.seq += 1;
+            client.body_limit = None;
+            client.events.remove(Interest::READABLE);
+            let _ = client.reregister(evl);
+            client.seq
+        };
+
+        self.enter(evl, token, Phase::Handling);
+        Responder { token, seq, stream: 0, tx: evl.channel(), keep_alive }
+    }
+
+    // answer a WebSocket handshake and start the route's handler on a thread
+    // of its own. the handshake response goes through the event loop like
+    // any other, ahead of whatever the handler sends.
+    fn upgrade(&mut self, responder: Responder, req: Request, handler: route::WebSocketHandler) {
+        let handshake = match websocket::handshake(&req) {
+            Ok(handshake)   => handshake,
+            Err(reason)     => return self.dispatch_error(responder, req, ErrorContext::new(400, reason)),
+        };
+
+        let (token, seq) = (responder.token, responder.seq);
+        let (ws, session) = websocket::pair(token, seq, responder.tx.clone(), self.config.max_message_size);
+
+        let _ = responder.tx.send(Notice::Upgrade(token, seq, handshake, Box::new(session)));
+        let _ = thread::Builder::new().name(String::from("canteen-websocket")).spawn(move || {
+            // the WebSocket is dropped while unwinding, closing the connection
+            let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| handler(&req, ws)));
+        });
+    }
+
+    fn start_websocket(&mut self, evl: &mut EventLoop, token: Token, seq: u64,
+                       handshake: Vec<u8>, session: Box<Session>) {
+        match self.conns.get_mut(token) {
+            Some(client) if client.phase == Phase::Handling && client.seq == seq => {
+                client.o_buf.push(handshake);
+                client.ws = Some(session);
+                client.events.insert(Interest::WRITABLE);
+            },
+            _ => return,
+        }
+
+        self.enter(evl, token, Phase::WebSocket);
+
+        // the client may not have waited for the handshake to finish
+        self.process(evl, token);
+    }
+
+    // a frame from a WebSocket handler, for a connection that may have closed
+    fn send_frame(&mut self, evl: &mut EventLoop, token: Token, seq: u64, frame: Vec<u8>, close: bool) {
+        if let Some(client) = self.conns.get_mut(token) {
+            if client.phase != Phase::WebSocket || client.seq != seq {
+                return;
+            }
+
+            if let Some(ref mut session) = client.ws {
+                if session.is_done() {
+                    return;
+                }
+
+                if close {
+                    session.sent_close();
+                }
+            }
+
+            client.o_buf.push(frame);
+            client.events.insert(Interest::WRITABLE);
+            let _ = client.reregister(evl);
+        }
+    }
+
+    // pass the frames that have arrived on a WebSocket to its session
+    fn process_ws(&mut self, evl: &mut EventLoop, token: Token) {
+        let client = self.get_client(token);
+        let session = client.ws.as_mut().unwrap();
+
+        session.receive(&mut client.i_buf);
+        client.o_buf.push(std::mem::take(&mut session.output));
+
+        let done = session.is_done();
+
+        if done && client.o_buf.is_empty() {
+            return self.reset_connection(evl, token);
+        }
+
+        if !client.o_buf.is_empty() {
+            client.events.insert(Interest::WRITABLE);
+        }
+
+        if !done {
+            client.events.insert(Interest::READABLE);
+        }
+
+        let _ = client.reregister(evl);
+    }
+
+    fn writable_ws(&mut self, evl: &mut EventLoop, token: Token) {
+        match self.get_client(token).send() {
+            Ok(true)    => {},
+            Ok(false)   => { let _ = self.get_client(token).reregister(evl); return; },
+            Err(_)      => return self.reset_connection(evl, token),
+        }
+
+        let client = self.get_client(token);
+        let done = client.ws.as_ref().is_none_or(|session| session.is_done());
+
+        if done || !client.o_buf.is_empty() {
+            return self.reset_connection(evl, token);
+        }
+
+        let _ = client.reregister(evl);
+    }
+
+    // switch a new connection to HTTP/2 if the client asked for it, either
+    // during the TLS handshake or by opening with the HTTP/2 preface. returns
+    // whether the connection is speaking HTTP/2.
+    #[cfg(feature = "http2")]
+    fn detect_h2(&mut self, evl: &mut EventLoop, token: Token) -> bool {
+        let (max_path, max_header) = (self.config.max_request_line, self.config.max_header_size);
+        let client = self.get_client(token);
+
+        if client.h2.is_some() {
+            return true;
+        }
+
+        if client.seq > 0 || client.phase != Phase::Header {
+            return false;
+        }
+
+        // no HTTP/1.x method starts with "PRI", so there's no need to wait
+        // for the whole preface, which would otherwise pass for a request
+        let preface = client.i_buf.len() >= 3 && (client.i_buf.starts_with(http2::PREFACE) ||
+                                                  http2::PREFACE.starts_with(&client.i_buf));
+
+        if !preface && !client.sock.negotiated_h2() {
+            return false;
+        }
+
+        client.h2 = Some(Box::new(http2::Connection::new(max_path, max_header)));
+        client.keep_alive = true;
+
+        self.enter(evl, token, Phase::Http2);
+        true
+    }
+
+    // read whatever frames have arrived, and dispatch any requests they
+    // complete. each stream's response is sent through `notify` as usual.
+    #[cfg(feature = "http2")]
+    fn process_h2(&mut self, evl: &mut EventLoop, token: Token) {
+        let received = {
+            let routes = &self.routes;
+            let config = &self.config;
+            let client = self.conns.get_mut(token).unwrap();
+            let listener = client.listener.as_deref();
+            let conn = client.h2.as_mut().unwrap();
+
+            conn.receive(&mut client.i_buf, |method, path| Canteen::body_limit(routes, config, method, path, listener))
+        };
+
+        let events = match received {
+            Ok(events)  => events,
+            Err(_)      => {
+                // the GOAWAY has been queued; close once it's written
+                self.get_client(token).keep_alive = false;
+                return self.flush_h2(evl, token);
+            },
+        };
+
+        if !events.is_empty() {
+            // restart the idle timer
+            self.enter(evl, token, Phase::Http2);
+        }
+
+        let (seq, listener) = {
+            let client = self.get_client(token);
+            (client.seq, client.listener.clone())
+        };
+
+        let responder = |stream| Responder { token, seq, stream, tx: evl.channel(), keep_alive: true };
+
+        for event in events {
+            match event {
+                http2::Event::Request(stream, raw)              => {
+                    self.handle_request(responder(stream), listener.clone(), &raw);
+                },
+                http2::Event::Reject(stream, status, message, head) => {
+                    let req = Request::from_bytes(&head).unwrap_or_default();
+                    self.dispatch_error(responder(stream), req, ErrorContext::new(status, message));
+                },
+            }
+        }
+
+        self.flush_h2(evl, token);
+    }
+
+    // queue an HTTP/2 connection's pending frames for writing, and keep
+    // reading from it.
+    #[cfg(feature = "http2")]
+    fn flush_h2(&mut self, evl: &mut EventLoop, token: Token) {
+        let client = self.get_client(token);
+        let output = std::mem::take(&mut client.h2.as_mut().unwrap().output);
+
+        client.o_buf.push(output);
+
+        if !client.o_buf.is_empty() {
+            client.events.insert(Interest::WRITABLE);
+        }
+
+        client.events.insert(Interest::READABLE);
+        let _ = client.reregister(evl);
+    }
+
+    #[cfg(feature = "http2")]
+    fn writable_h2(&mut self, evl: &mut EventLoop, token: Token) {
+        match self.get_client(token).send() {
+            Ok(true)    => {},
+            Ok(false)   => { let _ = self.get_client(token).reregister(evl); return; },
+            Err(_)      => return self.reset_connection(evl, token),
+        }
+
+        // response bodies are held back while the output is full, so carry
+        // on with them now that it's been written
+        let client = self.get_client(token);
+        let conn = client.h2.as_mut().unwrap();
+
+        conn.send_all();
+
+        if !conn.output.is_empty() {
+            return self.flush_h2(evl, token);
+        }
+
+        let draining = self.drain;
+        let client = self.get_client(token);
+        let (closing, open) = {
+            let conn = client.h2.as_ref().unwrap();
+            (conn.is_closing() || draining, conn.open_streams())
+        };
+
+        if !client.keep_alive || !client.o_buf.is_empty() || (closing && open == 0) {
+            return self.reset_connection(evl, token);
+        }
+
+        let _ = client.reregister(evl);
+    }
+
+    // an HTTP/2 connection has been quiet for the keep-alive timeout. it's
+    // closed if no requests are in flight on it.
+    #[cfg(feature = "http2")]
+    fn expire_h2(&mut self, evl: &mut EventLoop, token: Token) {
+        if self.get_client(token).is_busy() {
+            self.get_client(token).h2.as_mut().unwrap().keep_alive_events();
+            self.flush_h2(evl, token);
+            return self.enter(evl, token, Phase::Http2);
+        }
+
+        self.reset_connection(evl, token);
+    }
+
+    // the body size limit for a request, from the route it will be handled
+    // by if that route has its own.
+    fn body_limit(routes: &HashMap<route::RouteDef, route::Route>, config: &ServerConfig,
+                  method: Method, path: &str, listener: Option<&str>) -> usize {
+        routes.values()
+              .find(|route| route.method() == method && route.is_path_match(path) && route.allows(listener))
+              .and_then(|route| route.max_body_size())
+              .unwrap_or(config.max_body_size)
+    }
+
+    // a handler's response has arrived
+    fn reply(&mut self, evl: &mut EventLoop, reply: Reply) {
+        match self.conns.get_mut(reply.token) {
+            #[cfg(feature = "http2")]
+            Some(client) if client.h2.is_some() => {
+                let (status, headers, body, events) = reply.response.into_parts();
+                let (token, seq, stream) = (reply.token, reply.seq, reply.stream);
+                let events = events.map(|events| events.attach(evl.channel(), token, seq, stream));
+
+                client.h2.as_mut().unwrap().respond(stream, status, &headers, body, events);
+                return self.flush_h2(evl, token);
+            },
+            // a response for a request that has since timed out, or for a
+            // connection that has gone away, is dropped
+            Some(client) if client.phase == Phase::Handling && client.seq == reply.seq => {
+                let (token, seq) = (reply.token, reply.seq);
+                let (output, events) = reply.response.into_output();
+
+                client.o_buf = output;
+                client.keep_alive = reply.keep_alive;
+                client.sse = events.map(|events| events.attach(evl.channel(), token, seq, 0));
+                client.events.insert(Interest::WRITABLE);
+                let _ = client.reregister(evl);
+            },
+            _ => return,
+        }
+
+        let streaming = self.get_client(reply.token).sse.is_some();
+
+        self.enter(evl, reply.token, if streaming { Phase::Streaming } else { Phase::Writing });
+    }
+
+    // more of an event stream, for a connection that may have closed
+    #[cfg_attr(not(feature = "http2"), allow(unused_variables))]
+    fn send_chunk(&mut self, evl: &mut EventLoop, token: Token, seq: u64,
+                  stream: u32, data: Vec<u8>, end: bool) {
+        let max_body_size = self.config.max_body_size;
+
+        match self.conns.get_mut(token) {
+            #[cfg(feature = "http2")]
+            Some(client) if client.h2.is_some() => {
+                client.h2.as_mut().unwrap().push(stream, &data, end);
+                return self.flush_h2(evl, token);
+            },
+            Some(client) if client.phase == Phase::Streaming && client.seq == seq => {
+                client.o_buf.push(data);
+                client.events.insert(Interest::WRITABLE);
+            },
+            _ => return,
+        }
+
+        if self.get_client(token).o_buf.len() > max_body_size {
+            // the client isn't keeping up
+            return self.reset_connection(evl, token);
+        }
+
+        if end {
+            self.end_events(evl, token);
+        } else {
+            // the stream isn't quiet, so put off the next keep-alive comment
+            let _ = self.get_client(token).reregister(evl);
+            self.enter(evl, token, Phase::Streaming);
+        }
+    }
+
+    // finish writing an event stream, then close the connection
+    fn end_events(&mut self, evl: &mut EventLoop, token: Token) {
+        {
+            let client = self.get_client(token);
+
+            client.sse = None;
+            client.keep_alive = false;
+            client.events.insert(Interest::WRITABLE);
+            let _ = client.reregister(evl);
+        }
+
+        self.enter(evl, token, Phase::Writing);
+    }
+
+    // an event stream has been quiet for a while: send a comment, which
+    // clients ignore, so that nothing in between decides it's dead
+    fn keep_alive_events(&mut self, evl: &mut EventLoop, token: Token) {
+        {
+            let client = self.get_client(token);
+
+            client.o_buf.extend_from_slice(b":\n\n");
+            client.events.insert(Interest::WRITABLE);
+            let _ = client.reregister(evl);
+        }
+
+        self.enter(evl, token, Phase::Streaming);
+    }
+
+    // the handler has run past its deadline. it can't be stopped, but the
+    // client needn't keep waiting: send the built-in 504 page and drop the
+    // handler's response when it eventually arrives.
+    fn expire_handler(&mut self, evl: &mut EventLoop, token: Token) {
+        let ctx = ErrorContext::new(504, "handler timed out");
+        let mut res = utils::err_default(&Request::new(), &ctx);
+
+        res.add_header("Connection", "close");
+
+        {
+            let client = self.get_client(token);
+
+            client.seq += 1;
+            client.o_buf = res.into_output().0;
+            client.keep_alive = false;
+            client.events.insert(Interest::WRITABLE);
+            let _ = client.reregister(evl);
+        }
+
+        self.enter(evl, token, Phase::Writing);
+    }
+
+    fn writable(&mut self, evl: &mut EventLoop, token: Token) {
+        #[cfg(feature = "http2")]
+        {
+            if self.get_client(token).h2.is_some() {
+                return self.writable_h2(evl, token);
+            }
+        }
+
+        if self.get_client(token).phase == Phase::WebSocket {
+            return self.writable_ws(evl, token);
+        }
+
+        if self.get_client(token).phase == Phase::Streaming {
+            return match self.get_client(token).send() {
+                Ok(_)       => { let _ = self.get_client(token).reregister(evl); },
+                Err(_)      => self.reset_connection(evl, token),
+            };
+        }
+
+        if self.get_client(token).phase != Phase::Writing {
+            // no response yet; the stream has output of its own to finish
+            let client = self.get_client(token);
+
+            match client.flush() {
+                Ok(true)    => client.events.remove(Interest::WRITABLE),
+                Ok(false)   => {},
+                Err(_)      => return self.reset_connection(evl, token),
+            }
+
+            let _ = self.get_client(token).reregister(evl);
+            return;
+        }
+
+        let pending = self.get_client(token).o_buf.len();
+
+        match self.get_client(token).send() {
+            Ok(true)    => {},
+            Ok(false)   => {
+                // the timer is for clients that have stopped reading, so it
+                // starts over whenever some of the response goes out
+                if self.get_client(token).o_buf.len() < pending {
+                    self.enter(evl, token, Phase::Writing);
+                }
+
+                let _ = self.get_client(token).reregister(evl);
+                return;
+            },
+            Err(_)      => return self.reset_connection(evl, token),
+        }
+
+        let draining = self.drain;
+        let client = self.get_client(token);
+
+        if !client.keep_alive || !client.o_buf.is_empty() || draining {
+            return self.reset_connection(evl, token);
+        }
+
+        // wait for the next request, which may already be buffered
+        client.keep_alive = false;
+
+        self.enter(evl, token, Phase::Idle);
+        self.process(evl, token);
+    }
+
+    // move a client to a new phase, replacing its timer with that phase's.
+    fn enter(&mut self, evl: &mut EventLoop, token: Token, phase: Phase) {
+        self.clear_timer(evl, token);
+
+        let delay = match phase {
+            Phase::Idle         => self.config.keep_alive,
+            Phase::Header       => Some(self.config.header_timeout),
+            Phase::Body         => Some(self.config.body_timeout),
+            Phase::Handling     => self.config.handler_timeout,
+            Phase::Writing      => Some(self.config.write_timeout),
+            Phase::Streaming    => Some(self.config.event_keep_alive),
+            Phase::WebSocket    => None,
+            #[cfg(feature = "http2")]
+            Phase::Http2        => Some(self.config.keep_alive.unwrap_or(self.config.header_timeout)),
+        };
+
+        self.get_client(token).phase = phase;
+
+        if let Some(delay) = delay {
+            self.get_client(token).timer = Some(evl.timeout(token, delay));
+        }
+    }
+
+    fn clear_timer(&mut self, evl: &mut EventLoop, token: Token) {
+        if let Some(timer) = self.get_client(token).timer.take() {
+            evl.clear_timeout(timer);
+        }
+    }
+
+    fn reset_connection(&mut self, evl: &mut EventLoop, token: Token) {
+        // kill the connection
+        if let Some(mut client) = self.conns.remove(token) {
+            if let Some(timer) = client.timer.take() {
+                evl.clear_timeout(timer);
+            }
+
+            if let Some(ip) = client.peer {
+                self.release_peer(ip);
+            }
+
+            self.stats.on_close();
+        }
+
+        self.check_drained(evl);
+    }
+
+    fn release_peer(&mut self, ip: IpAddr) {
+        let remaining = match self.peers.get_mut(&ip) {
+            Some(count) => { *count -= 1; *count },
+            None        => return,
+        };
+
+        if remaining == 0 {
+            self.peers.remove(&ip);
+        }
+    }
+
+    fn register(&mut self, evl: &mut EventLoop) -> io::Result<()> {
+        for (index, server) in self.servers.iter_mut().enumerate() {
+            evl.register(&mut server.listener, Token(LISTENER_BASE + index), Interest::READABLE)?;
+        }
+
+        Ok(())
+    }
+
+    // start the drain if the ShutdownHandle has been triggered. its flag may
+    // have been set without waking the loop, so this is checked whenever the
+    // loop has something to do.
+    fn check_shutdown(&mut self, evl: &mut EventLoop) {
+        if !self.drain && self.stopper.is_shutdown() {
+            self.start_drain(evl);
+        }
+    }
+
+    // the drain is over once the last connection has closed
+    fn check_drained(&mut self, evl: &mut EventLoop) {
+        if self.drain && self.conns.count() == 0 {
+            evl.shutdown();
+        }
+    }
+
+    // stop accepting connections and close every one that isn't waiting on
+    // a response. the rest are closed as their responses are written.
+    fn start_drain(&mut self, evl: &mut EventLoop) {
+        self.drain = true;
+        evl.timeout(DRAIN, self.config.shutdown_timeout);
+
+        for mut server in self.servers.drain(..) {
+            let _ = evl.deregister(&mut server.listener);
+        }
+
+        let idle: Vec<Token> = self.conns.iter()
+                                         .filter(|client| !client.is_busy())
+                                         .map(|client| client.token)
+                                         .collect();
+
+        for token in idle {
+            self.reset_connection(evl, token);
+        }
+
+        // WebSocket clients are told the server is going away
+        let sockets: Vec<Token> = self.conns.iter()
+                                            .filter(|client| client.phase == Phase::WebSocket)
+                                            .map(|client| client.token)
+                                            .collect();
+
+        for token in sockets {
+            self.get_client(token).ws.as_mut().unwrap().close(1001, "server shutting down");
+            self.process_ws(evl, token);
+        }
+
+        // event streams are ended; clients will reconnect elsewhere
+        let streams: Vec<Token> = self.conns.iter()
+                                            .filter(|client| client.phase == Phase::Streaming)
+                                            .map(|client| client.token)
+                                            .collect();
+
+        for token in streams {
+            self.end_events(evl, token);
+        }
+
+        // HTTP/2 clients are told to stop opening streams
+        #[cfg(feature = "http2")]
+        {
+            let busy: Vec<Token> = self.conns.iter()
+                                             .filter(|client| client.h2.is_some())
+                                             .map(|client| client.token)
+                                             .collect();
+
+            for token in busy {
+                let conn = self.get_client(token).h2.as_mut().unwrap();
+
+                conn.shutdown();
+                conn.end_events();
+                self.flush_h2(evl, token);
+            }
+        }
+
+        self.check_drained(evl);
+    }
+
+    /// Starts a Canteen server's event loop. This blocks until the server
+    /// is stopped with its `ShutdownHandle` or fails; the listener must
+    /// already have been created with `bind`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use canteen::Canteen;
+    ///
+    /// let mut cnt = Canteen::new();
+    ///
+    /// cnt.bind(("127.0.0.1", 8080)).unwrap();
+    /// if let Err(err) = cnt.run() {
+    ///     eprintln!("server stopped: {}", err);
+    /// }
+    /// ```
+    pub fn run(&mut self) -> Result<(), ServerError> {
+        if self.servers.is_empty() {
+            return Err(ServerError::NotBound);
+        }
+
+        let mut evl = EventLoop::new().map_err(ServerError::EventLoop)?;
+
+        self.register(&mut evl).map_err(ServerError::EventLoop)?;
+
+        if self.routes.values().any(|route| matches!(route.handler, route::Endpoint::Async(_))) {
+            self.asyncex = Some(executor::Executor::new(self.config.async_threads));
+        }
+
+        if self.stopper.attach(evl.channel()) {
+            // told to stop before it started
+            self.start_drain(&mut evl);
+        }
+
+        let result = evl.run(self);
+
+        // stop the async handlers' threads; they're started again by the
+        // next run
+        self.asyncex = None;
+        self.stopper.detach();
+        result.map_err(ServerError::EventLoop)?;
+
+        // anything still open missed the drain deadline
+        for _ in self.conns.iter() {
+            self.stats.on_close();
+        }
+
+        self.drain = false;
+        self.peers.clear();
+        self.conns = Clients::new(Token(2), self.config.max_connections);
+
+        match self.failure.take() {
+            Some(err)   => Err(err),
+            None        => Ok(()),
+        }
+    }
+}
+
+impl Default for Canteen {
+    fn default() -> Self {
+        Canteen::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    // a server on a free port of the loopback interface, running on a thread
+    // of its own until it's stopped or dropped
+    struct Server {
+        addr:   SocketAddr,
+        handle: ShutdownHandle,
+        thread: Option<thread::JoinHandle<Result<(), ServerError>>>,
+    }
+
+    impl Server {
+        fn start<F>(builder: CanteenBuilder, setup: F) -> Server
+                where F: FnOnce(&mut Canteen) + Send + 'static {
+            let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+            let (tx, rx) = mpsc::channel();
+
+            let thread = thread::spawn(move || {
+                let mut cnt = builder.build().unwrap();
+
+                setup(&mut cnt);
+                cnt.bind(addr)?;
+
+                let _ = tx.send(cnt.shutdown_handle());
+                cnt.run()
+            });
+
+            let handle = rx.recv().unwrap();
+
+            Server { addr, handle, thread: Some(thread) }
+        }
+
+        fn connect(&self) -> TcpStream {
+            let sock = TcpStream::connect(self.addr).unwrap();
+
+            sock.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            sock
+        }
+
+        // shut the server down, returning how long it took `run` to return
+        fn stop(&mut self) -> Duration {
+            let start = Instant::now();
+
+            self.handle.shutdown();
+            self.thread.take().unwrap().join().unwrap().unwrap();
+            start.elapsed()
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            self.handle.shutdown();
+
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    // everything the server sends until it closes the connection
+    fn read_all(sock: &mut TcpStream) -> String {
+        let mut output = Vec::new();
+
+        sock.read_to_end(&mut output).unwrap();
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    fn hello(_: &Request) -> Response {
+        utils::make_response("hello", "text/plain", 200)
+    }
+
+    #[test]
+    fn test_half_close() {
+        let builder = Canteen::builder().keep_alive(Some(Duration::from_secs(5)));
+        let server = Server::start(builder, |cnt| {
+            cnt.add_route("/", &[Method::Get], hello);
+        });
+
+        // both requests are answered, and the connection closed after them,
+        // whether or not the end of input was seen before the second one
+        let mut sock = server.connect();
+
+        sock.write_all(b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n").unwrap();
+        sock.shutdown(Shutdown::Write).unwrap();
+
+        let output = read_all(&mut sock);
+        let responses: Vec<&str> = output.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+
+        assert_eq!(2, responses.len());
+        assert!(responses[0].contains("Connection: keep-alive\r\n"));
+        assert!(responses[1].ends_with("\r\n\r\nhello"));
+
+        // a request that can't be finished is dropped
+        let mut sock = server.connect();
+
+        sock.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        sock.shutdown(Shutdown::Write).unwrap();
+
+        assert_eq!("", read_all(&mut sock));
+    }
+
+    #[test]
+    fn test_request_timeouts() {
+        let builder = Canteen::builder()
+            .header_timeout(Duration::from_millis(100))
+            .body_timeout(Duration::from_millis(100));
+        let server = Server::start(builder, |cnt| {
+            cnt.add_route("/", &[Method::Get, Method::Post], hello);
+        });
+
+        // a request line with no end to its headers
+        let mut sock = server.connect();
+
+        sock.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        assert!(read_all(&mut sock).starts_with("HTTP/1.1 408 "));
+
+        // a body that stops short of its length
+        let mut sock = server.connect();
+
+        sock.write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nab").unwrap();
+        assert!(read_all(&mut sock).starts_with("HTTP/1.1 408 "));
+
+        // the error handler sees as much of the request as arrived in time
+        let mut sock = server.connect();
+
+        sock.write_all(b"GET /slow HTTP/1.1\r\nAccept: application/json\r\nX-Unfin").unwrap();
+
+        let output = read_all(&mut sock);
+
+        assert!(output.starts_with("HTTP/1.1 408 "));
+        assert!(output.contains("Content-Type: application/json"));
+        assert!(output.contains("\"path\":\"/slow\""));
+    }
+
+    #[test]
+    fn test_handler_timeout() {
+        let builder = Canteen::builder().handler_timeout(Some(Duration::from_millis(100)));
+        let server = Server::start(builder, |cnt| {
+            cnt.add_route("/", &[Method::Get], |_: &Request| {
+                thread::sleep(Duration::from_millis(500));
+                utils::make_response("too late", "text/plain", 200)
+            });
+        });
+
+        let mut sock = server.connect();
+
+        sock.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+        let output = read_all(&mut sock);
+
+        assert!(output.starts_with("HTTP/1.1 504 "));
+        assert!(!output.contains("too late"));
+    }
+
+    #[test]
+    fn test_keep_alive_timeout() {
+        let builder = Canteen::builder().keep_alive(Some(Duration::from_millis(100)));
+        let server = Server::start(builder, |cnt| {
+            cnt.add_route("/", &[Method::Get], hello);
+        });
+
+        // the response keeps the connection open, until it's been idle for
+        // the keep-alive timeout
+        let mut sock = server.connect();
+        let start = Instant::now();
+
+        sock.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+        let output = read_all(&mut sock);
+
+        assert!(output.contains("Connection: keep-alive\r\n"));
+        assert!(output.ends_with("\r\n\r\nhello"));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_slow_reader() {
+        const SIZE: usize = 16 << 20;
+
+        let builder = Canteen::builder().write_timeout(Duration::from_millis(200));
+        let server = Server::start(builder, |cnt| {
+            cnt.add_route("/", &[Method::Get], |_: &Request| {
+                utils::make_response(vec![b'x'; SIZE], "text/plain", 200)
+            });
+        });
+
+        // the body is bigger than the socket buffers can hold, and is read in
+        // short bursts, so the transfer takes several times the write timeout
+        // while never stalling for long
+        let mut sock = server.connect();
+        let mut buf = vec![0u8; 1 << 16];
+        let (mut received, mut burst) = (0, 0);
+        let start = Instant::now();
+
+        sock.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+
+        loop {
+            match sock.read(&mut buf).unwrap() {
+                0 => break,
+                n => { received += n; burst += n; },
+            }
+
+            if burst >= 1 << 18 {
+                thread::sleep(Duration::from_millis(20));
+                burst = 0;
+            }
+        }
+
+        assert!(start.elapsed() > Duration::from_millis(400));
+        assert!(received > SIZE);
+    }
+
+    #[test]
+    fn test_queue_limit() {
+        let builder = Canteen::builder()
+            .workers(1)
+            .max_queued_jobs(1)
+            .retry_after(Duration::from_secs(2));
+        let server = Server::start(builder, |cnt| {
+            cnt.add_route("/", &[Method::Get], |_: &Request| {
+                thread::sleep(Duration::from_millis(300));
+                utils::make_response("hello", "text/plain", 200)
+            });
+        });
+
+        // the first request keeps the only worker busy and the second waits
+        // for it, which leaves no room for the third
+        let mut socks: Vec<TcpStream> = (0..3).map(|_| {
+            let mut sock = server.connect();
+
+            sock.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+            thread::sleep(Duration::from_millis(50));
+            sock
+        }).collect();
+
+        let refused = read_all(&mut socks[2]);
+
+        assert!(refused.starts_with("HTTP/1.1 503 "));
+        assert!(refused.contains("Retry-After: 2\r\n"));
+        assert!(refused.contains("Connection: close\r\n"));
+
+        for sock in &mut socks[..2] {
+            assert!(read_all(sock).starts_with("HTTP/1.1 200 OK\r\n"));
+        }
+    }
+
+    #[test]
+    fn test_connections_per_ip() {
+        let builder = Canteen::builder()
+            .max_connections_per_ip(Some(1))
+            .retry_after(Duration::from_secs(2));
+        let server = Server::start(builder, |cnt| {
+            cnt.add_route("/", &[Method::Get], hello);
+        });
+
+        let mut first = server.connect();
+        let mut second = server.connect();
+        let refused = read_all(&mut second);
+
+        assert!(refused.starts_with("HTTP/1.1 503 "));
+        assert!(refused.contains("Retry-After: 2\r\n"));
+
+        // the first connection is served as usual, and once it's closed
+        // there's room for another
+        first.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_all(&mut first).starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let mut third = server.connect();
+
+        third.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(read_all(&mut third).starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_shutdown_drain() {
+        const SIZE: usize = 1 << 18;
+
+        let builder = Canteen::builder().shutdown_timeout(Duration::from_millis(500));
+        let mut server = Server::start(builder, |cnt| {
+            cnt.add_route("/slow", &[Method::Get], |_: &Request| {
+                thread::sleep(Duration::from_millis(200));
+                utils::make_response(vec![b'x'; SIZE], "text/plain", 200)
+            });
+            cnt.add_route("/stuck", &[Method::Get], |_: &Request| {
+                thread::sleep(Duration::from_secs(3));
+                utils::make_response("too late", "text/plain", 200)
+            });
+        });
+
+        let mut idle = server.connect();
+        let mut slow = server.connect();
+        let mut stuck = server.connect();
+
+        slow.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        stuck.write_all(b"GET /stuck HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        // the server waits for the slow response, but gives up on the stuck
+        // one at the deadline
+        let elapsed = server.stop();
+
+        assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+
+        // the response in flight was written in full, and the connections
+        // with nothing to send were closed
+        let output = read_all(&mut slow);
+
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with(&format!("\r\n\r\n{}", "x".repeat(SIZE))));
+        assert_eq!("", read_all(&mut idle));
+        assert_eq!("", read_all(&mut stuck));
+    }
+
+    #[test]
+    fn test_shutdown_rerun() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let (done_tx, done) = mpsc::channel();
+
+        let thread = thread::spawn(move || {
+            let mut cnt = Canteen::new();
+
+            cnt.add_route("/", &[Method::Get], hello);
+            cnt.add_async_route("/async", &[Method::Get], |_: Request| async { "hello" });
+
+            for _ in 0..2 {
+                cnt.bind(addr).unwrap();
+                tx.send(cnt.shutdown_handle()).unwrap();
+                done_tx.send(cnt.run()).unwrap();
+            }
+        });
+
+        // the same server can be run again once a shutdown is over
+        for _ in 0..2 {
+            let handle = rx.recv().unwrap();
+
+            for path in &["/", "/async"] {
+                let mut sock = TcpStream::connect(addr).unwrap();
+
+                sock.write_all(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes()).unwrap();
+                assert!(read_all(&mut sock).starts_with("HTTP/1.1 200 OK\r\n"));
+            }
+
+            // the handle wakes the server rather than waiting to be noticed
+            let start = Instant::now();
+
+            handle.shutdown();
+            assert!(done.recv().unwrap().is_ok());
+            assert!(start.elapsed() < Duration::from_millis(50), "{:?}", start.elapsed());
+        }
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_event_stream_headers() {
+        let server = Server::start(Canteen::builder(), |cnt| {
+            cnt.add_route("/events", &[Method::Get], |_: &Request| {
+                let (stream, sender) = sse::EventStream::new();
+
+                let _ = sender.send(sse::Event::new("hi"));
+                stream
+            });
+        });
+
+        let mut sock = server.connect();
+        let mut output = Vec::new();
+        let mut buf = [0u8; 1024];
+
+        sock.write_all(b"GET /events HTTP/1.1\r\n\r\n").unwrap();
+
+        // the stream stays open, so read only as far as the first event
+        while !String::from_utf8_lossy(&output).contains("data: hi\n\n") {
+            match sock.read(&mut buf).unwrap() {
+                0 => break,
+                n => output.extend_from_slice(&buf[..n]),
+            }
+        }
+
+        let output = String::from_utf8_lossy(&output);
+        let (head, body) = output.split_once("\r\n\r\n").unwrap();
+
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: text/event-stream"));
+        assert!(!head.contains("Content-Length"));
+        assert!(body.ends_with("data: hi\n\n"));
+    }
+
+    #[test]
+    fn test_event_keep_alive() {
+        let builder = Canteen::builder().event_keep_alive(Duration::from_millis(300));
+        let server = Server::start(builder, |cnt| {
+            cnt.add_route("/events", &[Method::Get], |_: &Request| {
+                let (stream, sender) = sse::EventStream::new();
+
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        thread::sleep(Duration::from_millis(100));
+                        let _ = sender.send(sse::Event::new("tick"));
+                    }
+
+                    // then go quiet
+                    thread::sleep(Duration::from_secs(5));
+                });
+
+                stream
+            });
+        });
+
+        let mut sock = server.connect();
+        let mut output = Vec::new();
+        let mut buf = [0u8; 1024];
+
+        sock.write_all(b"GET /events HTTP/1.1\r\n\r\n").unwrap();
+
+        while !String::from_utf8_lossy(&output).contains(":\n\n") {
+            match sock.read(&mut buf).unwrap() {
+                0 => break,
+                n => output.extend_from_slice(&buf[..n]),
+            }
+        }
+
+        // the comment is only sent once the events stop
+        let output = String::from_utf8_lossy(&output);
+
+        assert!(output.ends_with("data: tick\n\n:\n\n"));
+        assert_eq!(5, output.matches("data: tick\n\n").count());
+    }
+
+    #[test]
+    fn test_unsupported_encoding() {
+        let server = Server::start(Canteen::builder(), |cnt| {
+            cnt.add_route("/", &[Method::Post], hello);
+        });
+
+        // nothing this build can't decompress gets through to the handler
+        let mut encodings = vec!["compress"];
+
+        if !cfg!(feature = "compression") {
+            encodings.extend_from_slice(&["gzip", "deflate", "br"]);
+        }
+
+        for encoding in encodings {
+            let mut sock = server.connect();
+            let req = format!("POST / HTTP/1.1\r\nConnection: close\r\nContent-Encoding: {}\r\nContent-Length: 4\r\n\r\nabcd", encoding);
+
+            sock.write_all(req.as_bytes()).unwrap();
+            assert!(read_all(&mut sock).starts_with("HTTP/1.1 415 "), "{}", encoding);
+        }
+    }
+
+    #[test]
+    fn test_error_handler_request() {
+        let builder = Canteen::builder().max_body_size(16);
+        let server = Server::start(builder, |cnt| {
+            cnt.add_route("/upload", &[Method::Post], hello);
+        });
+
+        // the 413 is sent before the body is read, but it still follows the
+        // request's Accept header
+        let mut sock = server.connect();
+
+        sock.write_all(b"POST /upload HTTP/1.1\r\nAccept: application/json\r\nContent-Length: 100\r\n\r\n").unwrap();
+
+        let output = read_all(&mut sock);
+
+        assert!(output.starts_with("HTTP/1.1 413 "));
+        assert!(output.contains("Content-Type: application/json"));
+        assert!(output.contains("\"path\":\"/upload\""));
+    }
+
+    #[test]
+    fn test_validate_route() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        static SAVED: AtomicBool = AtomicBool::new(false);
+
+        fn save(_: &Request) -> Response {
+            SAVED.store(true, Ordering::SeqCst);
+            Response::as_json(&"saved")
+        }
+
+        let server = Server::start(Canteen::builder(), |cnt| {
+            cnt.add_route("/doc", &[Method::Get, Method::Put], save)
+               .validate_route("/doc", |_| (Some(conditional::ETag::strong("v2")), None));
+        });
+
+        // a stale If-Match is refused before the handler can apply the write
+        let mut sock = server.connect();
+
+        sock.write_all(b"PUT /doc HTTP/1.1\r\nIf-Match: \"v1\"\r\nContent-Length: 2\r\n\r\n{}").unwrap();
+
+        let output = read_all(&mut sock);
+
+        assert!(output.starts_with("HTTP/1.1 412 "));
+        assert!(!SAVED.load(Ordering::SeqCst));
+
+        // a matching If-None-Match on a GET is answered with a 304
+        let mut sock = server.connect();
+
+        sock.write_all(b"GET /doc HTTP/1.1\r\nIf-None-Match: \"v2\"\r\n\r\n").unwrap();
+
+        let output = read_all(&mut sock);
+
+        assert!(output.starts_with("HTTP/1.1 304 "));
+        assert!(output.contains("ETag: \"v2\""));
+        assert!(!SAVED.load(Ordering::SeqCst));
     }
 }
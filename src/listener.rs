@@ -0,0 +1,272 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+use std::fs;
+use std::io;
+use std::io::IoSlice;
+use std::io::prelude::*;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use mio::event::Source;
+use mio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use mio::net::{UnixListener, UnixStream};
+use mio::{Interest, Registry, Token};
+
+use crate::output::Sink;
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+
+/// A socket accepting connections, on either a TCP address or, on Unix, a
+/// Unix domain socket path.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+    #[cfg(feature = "tls")]
+    Tls(TcpListener, Arc<rustls::ServerConfig>),
+}
+
+fn tcp_listener(addr: &SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    if addr.is_ipv6() {
+        // so that the same port can be bound separately on IPv4
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(backlog as i32)?;
+
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into()))
+}
+
+impl Listener {
+    pub fn tcp(addr: &SocketAddr, backlog: u32) -> io::Result<Listener> {
+        Ok(Listener::Tcp(tcp_listener(addr, backlog)?))
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls(addr: &SocketAddr, backlog: u32, config: Arc<rustls::ServerConfig>) -> io::Result<Listener> {
+        Ok(Listener::Tls(tcp_listener(addr, backlog)?, config))
+    }
+
+    #[cfg(unix)]
+    pub fn unix(path: &Path) -> io::Result<Listener> {
+        // a socket file left behind by a previous run would make bind fail.
+        // anything else at the path is left alone.
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if meta.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(Listener::Unix(UnixListener::bind(path)?, path.to_path_buf()))
+    }
+
+    /// Accept a pending connection, along with the peer's IP address for
+    /// TCP connections. Returns `None` if there are none.
+    pub fn accept(&self) -> io::Result<Option<(Stream, Option<IpAddr>)>> {
+        let accepted = match *self {
+            Listener::Tcp(ref l)        => l.accept().map(|(sock, addr)| (Stream::Tcp(sock), Some(addr.ip()))),
+            #[cfg(unix)]
+            Listener::Unix(ref l, _)    => l.accept().map(|(sock, _)| (Stream::Unix(sock), None)),
+            #[cfg(feature = "tls")]
+            Listener::Tls(ref l, ref config) => match l.accept() {
+                Ok((sock, addr))    => {
+                    let tls = TlsStream::new(sock, config.clone())?;
+                    Ok((Stream::Tls(Box::new(tls)), Some(addr.ip())))
+                },
+                Err(err)            => Err(err),
+            },
+        };
+
+        match accepted {
+            Ok(accepted)                                            => Ok(Some(accepted)),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err)                                                => Err(err),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, ref path) = *self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Source for Listener {
+    fn register(&mut self, registry: &Registry, token: Token, interest: Interest) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref mut l)        => l.register(registry, token, interest),
+            #[cfg(unix)]
+            Listener::Unix(ref mut l, _)    => l.register(registry, token, interest),
+            #[cfg(feature = "tls")]
+            Listener::Tls(ref mut l, _)     => l.register(registry, token, interest),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interest: Interest) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref mut l)        => l.reregister(registry, token, interest),
+            #[cfg(unix)]
+            Listener::Unix(ref mut l, _)    => l.reregister(registry, token, interest),
+            #[cfg(feature = "tls")]
+            Listener::Tls(ref mut l, _)     => l.reregister(registry, token, interest),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref mut l)        => l.deregister(registry),
+            #[cfg(unix)]
+            Listener::Unix(ref mut l, _)    => l.deregister(registry),
+            #[cfg(feature = "tls")]
+            Listener::Tls(ref mut l, _)     => l.deregister(registry),
+        }
+    }
+}
+
+/// A listener along with the name routes can be restricted to.
+pub(crate) struct Bound {
+    pub name:     Option<String>,
+    pub listener: Listener,
+}
+
+/// A client connection accepted from a `Listener`.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Stream {
+    /// Whether the stream has output of its own to write, such as TLS
+    /// handshake messages, even when no response is being sent.
+    pub fn wants_write(&self) -> bool {
+        match *self {
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref s)  => s.wants_write(),
+            _                   => false,
+        }
+    }
+
+    /// Whether the client chose HTTP/2 during the TLS handshake.
+    #[cfg(feature = "http2")]
+    pub fn negotiated_h2(&self) -> bool {
+        match *self {
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref s)  => s.alpn_protocol() == Some(b"h2"),
+            _                   => false,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s)  => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut s)  => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s)  => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut s)  => s.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s)  => s.write_vectored(bufs),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.write_vectored(bufs),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut s)  => s.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s)  => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut s)  => s.flush(),
+        }
+    }
+}
+
+impl Sink for Stream {
+    #[cfg(unix)]
+    fn raw_socket(&self) -> Option<RawFd> {
+        match *self {
+            Stream::Tcp(ref s)  => Some(s.as_raw_fd()),
+            Stream::Unix(ref s) => Some(s.as_raw_fd()),
+            // file data has to be encrypted on its way out
+            #[cfg(feature = "tls")]
+            Stream::Tls(_)      => None,
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(&mut self, registry: &Registry, token: Token, interest: Interest) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s)  => s.register(registry, token, interest),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.register(registry, token, interest),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut s)  => s.get_mut().register(registry, token, interest),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interest: Interest) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s)  => s.reregister(registry, token, interest),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.reregister(registry, token, interest),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut s)  => s.get_mut().reregister(registry, token, interest),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s)  => s.deregister(registry),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.deregister(registry),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut s)  => s.get_mut().deregister(registry),
+        }
+    }
+}
@@ -0,0 +1,523 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ErrorKind, IoSlice, Result, Write};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+
+// the most buffers handed to a single vectored write
+const MAX_SLICES: usize = 64;
+
+// how much of a file is read at a time when it can't be sent with sendfile
+pub(crate) const FILE_CHUNK: usize = 64 * 1024;
+
+// the most a single sendfile call is asked to send
+#[cfg(target_os = "linux")]
+const SENDFILE_MAX: u64 = 1 << 30;
+
+/// Part of a file to be sent as a response body. It's read as the connection
+/// takes it, rather than all at once.
+#[derive(Debug)]
+pub(crate) struct FileBody {
+    file:   File,
+    offset: u64,
+    len:    u64,
+}
+
+impl FileBody {
+    /// Send `len` bytes of `file`, starting `offset` bytes in.
+    pub fn new(file: File, offset: u64, len: u64) -> FileBody {
+        FileBody { file, offset, len }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Another body for `len` bytes of the same file, starting `start`
+    /// bytes into this one.
+    pub fn slice(&self, start: u64, len: u64) -> Result<FileBody> {
+        debug_assert!(start + len <= self.len);
+
+        Ok(FileBody::new(self.file.try_clone()?, self.offset + start, len))
+    }
+
+    /// Read up to `max` bytes, starting `at` bytes into the part being sent.
+    /// Fails if the file has been cut short since.
+    pub fn read(&self, at: u64, max: usize) -> Result<Vec<u8>> {
+        let want = (self.len - at.min(self.len)).min(max as u64) as usize;
+        let mut buf = vec![0; want];
+        let mut done = 0;
+
+        while done < want {
+            match read_at(&self.file, &mut buf[done..], self.offset + at + done as u64) {
+                Ok(0)                                               => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n)                                               => done += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted    => {},
+                Err(e)                                              => return Err(e),
+            }
+        }
+
+        Ok(buf)
+    }
+
+    // skip over the first `n` bytes
+    fn advance(&mut self, n: u64) {
+        self.offset += n;
+        self.len -= n;
+    }
+}
+
+// read from a file at an offset, leaving alone the position that clones
+// of it share
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<usize> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<usize> {
+    use std::os::windows::fs::FileExt;
+
+    file.seek_read(buf, offset)
+}
+
+/// Somewhere output can be written.
+pub(crate) trait Sink: Write {
+    /// The socket to pass to `sendfile`, if file data can be sent straight
+    /// to it without passing through this process.
+    #[cfg(unix)]
+    fn raw_socket(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// Part of a body: either data in memory, or part of a file.
+#[derive(Debug)]
+pub(crate) enum Segment {
+    Buf(Vec<u8>),
+    File(FileBody),
+}
+
+impl Segment {
+    pub fn len(&self) -> usize {
+        match *self {
+            Segment::Buf(ref buf)   => buf.len(),
+            Segment::File(ref file) => file.len as usize,
+        }
+    }
+}
+
+/// Data waiting to be written to a connection, kept in the buffers it was
+/// produced in. Written data is skipped over rather than shifted out, and
+/// the buffers are written together with vectored I/O.
+#[derive(Debug, Default)]
+pub(crate) struct Output {
+    segs:   VecDeque<Segment>,
+    // how much of the first buffer has already been written
+    offset: usize,
+    len:    usize,
+}
+
+impl Output {
+    pub fn new() -> Output {
+        Output::default()
+    }
+
+    /// Queue a buffer without copying it.
+    pub fn push(&mut self, buf: Vec<u8>) {
+        if !buf.is_empty() {
+            self.len += buf.len();
+            self.segs.push_back(Segment::Buf(buf));
+        }
+    }
+
+    /// Queue a file, which is read only as it's written.
+    pub fn push_file(&mut self, file: FileBody) {
+        if file.len > 0 {
+            self.len += file.len as usize;
+            self.segs.push_back(Segment::File(file));
+        }
+    }
+
+    pub fn push_segment(&mut self, seg: Segment) {
+        match seg {
+            Segment::Buf(buf)   => self.push(buf),
+            Segment::File(file) => self.push_file(file),
+        }
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.push(data.to_vec());
+    }
+
+    /// How many bytes are still to be written.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Take up to `max` bytes from the front of the output, reading any
+    /// that are in a file, for protocols that frame the body themselves.
+    #[cfg(feature = "http2")]
+    pub fn take(&mut self, max: usize) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(max.min(self.len));
+
+        while out.len() < max {
+            let n = match self.segs.front() {
+                Some(Segment::Buf(buf))     => {
+                    let data = &buf[self.offset..];
+                    let n = data.len().min(max - out.len());
+
+                    out.extend_from_slice(&data[..n]);
+                    n
+                },
+                Some(Segment::File(file))   => {
+                    let chunk = file.read(0, max - out.len())?;
+                    let n = chunk.len();
+
+                    out.extend(chunk);
+                    n
+                },
+                None                        => break,
+            };
+
+            self.consume(n);
+        }
+
+        Ok(out)
+    }
+
+    /// Make a single write of as much of the output as `w` will take,
+    /// returning how many bytes were written.
+    pub fn write_to<W: Sink + ?Sized>(&mut self, w: &mut W) -> Result<usize> {
+        if let Some(Segment::File(_)) = self.segs.front() {
+            return self.write_file(w);
+        }
+
+        let written = {
+            let mut slices = Vec::with_capacity(self.segs.len().min(MAX_SLICES));
+            let mut offset = self.offset;
+
+            for seg in self.segs.iter().take(MAX_SLICES) {
+                match *seg {
+                    Segment::Buf(ref buf)   => slices.push(IoSlice::new(&buf[offset..])),
+                    Segment::File(_)        => break,
+                }
+
+                offset = 0;
+            }
+
+            w.write_vectored(&slices)?
+        };
+
+        self.consume(written);
+        Ok(written)
+    }
+
+    // write from the file at the front of the output: straight from the
+    // file to the socket if possible, otherwise by reading the next piece of
+    // it into a buffer and writing that
+    fn write_file<W: Sink + ?Sized>(&mut self, w: &mut W) -> Result<usize> {
+        let file = match self.segs.front_mut() {
+            Some(Segment::File(file))   => file,
+            _                           => return Ok(0),
+        };
+
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(socket) = w.raw_socket() {
+                let written = sendfile(socket, file)?;
+
+                self.consume(written);
+                return Ok(written);
+            }
+        }
+
+        let chunk = file.read(0, FILE_CHUNK)?;
+
+        file.advance(chunk.len() as u64);
+
+        if file.len == 0 {
+            self.segs.pop_front();
+        }
+
+        self.segs.push_front(Segment::Buf(chunk));
+        self.write_to(w)
+    }
+
+    // drop `n` bytes from the front of the output
+    fn consume(&mut self, mut n: usize) {
+        self.len -= n;
+
+        while n > 0 {
+            let left = match self.segs[0] {
+                Segment::Buf(ref buf)       => buf.len() - self.offset,
+                Segment::File(ref mut file) => {
+                    if (n as u64) < file.len {
+                        file.advance(n as u64);
+                        return;
+                    }
+
+                    file.len as usize
+                },
+            };
+
+            if n < left {
+                self.offset += n;
+                return;
+            }
+
+            n -= left;
+            self.offset = 0;
+            self.segs.pop_front();
+        }
+    }
+
+    /// Copy the output into a single buffer.
+    #[cfg(test)]
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.len);
+
+        for (i, seg) in self.segs.iter().enumerate() {
+            match *seg {
+                Segment::Buf(ref buf)   => out.extend_from_slice(if i == 0 { &buf[self.offset..] } else { buf }),
+                Segment::File(ref file) => out.extend(file.read(0, file.len as usize).unwrap()),
+            }
+        }
+
+        out
+    }
+}
+
+impl From<Vec<u8>> for Output {
+    fn from(buf: Vec<u8>) -> Output {
+        let mut output = Output::new();
+
+        output.push(buf);
+        output
+    }
+}
+
+// send the start of `file` to `socket`, returning how much was sent
+#[cfg(target_os = "linux")]
+fn sendfile(socket: RawFd, file: &FileBody) -> Result<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut offset = file.offset as libc::off_t;
+    let count = file.len.min(SENDFILE_MAX) as usize;
+
+    // both descriptors stay open for the duration of the call, and the
+    // kernel only writes through `offset`
+    let sent = unsafe { libc::sendfile(socket, file.file.as_raw_fd(), &mut offset, count) };
+
+    if sent < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(sent as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io;
+
+    // takes at most `limit` bytes per write, like a socket with a full buffer
+    struct Trickle {
+        data:  Vec<u8>,
+        limit: usize,
+        calls: usize,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+            self.calls += 1;
+
+            if self.limit == 0 {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+
+            let mut n = 0;
+
+            for buf in bufs {
+                let take = buf.len().min(self.limit - n);
+
+                self.data.extend_from_slice(&buf[..take]);
+                n += take;
+            }
+
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Sink for Trickle {}
+
+    // writes `data` to a fresh file in the temp dir
+    fn temp_file(tag: &str, data: &[u8]) -> File {
+        let path = env::temp_dir().join(format!("canteen-test-{}-{}", tag, std::process::id()));
+
+        fs::write(&path, data).unwrap();
+
+        let file = File::open(&path).unwrap();
+
+        fs::remove_file(&path).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_partial_writes() {
+        let mut output = Output::from(b"HTTP/1.1 200 OK\r\n\r\n".to_vec());
+        let mut sink = Trickle { data: Vec::new(), limit: 7, calls: 0 };
+
+        output.push(Vec::new());
+        output.push(b"hello, ".to_vec());
+        output.extend_from_slice(b"world");
+        assert_eq!(31, output.len());
+
+        while !output.is_empty() {
+            assert!(output.write_to(&mut sink).unwrap() <= 7);
+            assert_eq!(31 - sink.data.len(), output.len());
+        }
+
+        assert_eq!(5, sink.calls);
+        assert_eq!(b"HTTP/1.1 200 OK\r\n\r\nhello, world".to_vec(), sink.data);
+    }
+
+    #[test]
+    fn test_vectored_write() {
+        let mut output = Output::new();
+        let mut sink = Trickle { data: Vec::new(), limit: usize::MAX, calls: 0 };
+
+        output.push(b"head".to_vec());
+        output.push(b"body".to_vec());
+        assert_eq!(8, output.write_to(&mut sink).unwrap());
+        assert_eq!(1, sink.calls);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_would_block() {
+        let mut output = Output::from(b"abcdef".to_vec());
+        let mut sink = Trickle { data: Vec::new(), limit: 4, calls: 0 };
+
+        output.write_to(&mut sink).unwrap();
+        sink.limit = 0;
+
+        let err = output.write_to(&mut sink).unwrap_err();
+
+        assert_eq!(ErrorKind::WouldBlock, err.kind());
+        assert_eq!(b"ef".to_vec(), output.to_vec());
+    }
+
+    #[test]
+    fn test_file_in_chunks() {
+        let data: Vec<u8> = (0..FILE_CHUNK * 2 + 100).map(|n| n as u8).collect();
+        let mut output = Output::from(b"head".to_vec());
+        let mut sink = Trickle { data: Vec::new(), limit: 50_000, calls: 0 };
+
+        // skip the first and last 10 bytes of the file
+        output.push_file(FileBody::new(temp_file("chunks", &data), 10, data.len() as u64 - 20));
+        output.push(b"tail".to_vec());
+        assert_eq!(data.len() - 12, output.len());
+
+        while !output.is_empty() {
+            output.write_to(&mut sink).unwrap();
+            assert_eq!(data.len() - 12 - sink.data.len(), output.len());
+        }
+
+        assert_eq!(b"head", &sink.data[..4]);
+        assert_eq!(&data[10..data.len() - 10], &sink.data[4..sink.data.len() - 4]);
+        assert_eq!(b"tail", &sink.data[sink.data.len() - 4..]);
+    }
+
+    #[cfg(feature = "http2")]
+    #[test]
+    fn test_take() {
+        let data: Vec<u8> = (0..200).map(|n| n as u8).collect();
+        let mut output = Output::from(b"head".to_vec());
+
+        output.push_file(FileBody::new(temp_file("take", &data), 0, 200));
+        output.push(b"tail".to_vec());
+
+        assert_eq!(b"he".to_vec(), output.take(2).unwrap());
+        assert_eq!([&b"ad"[..], &data[..98]].concat(), output.take(100).unwrap());
+        assert_eq!(106, output.len());
+        assert_eq!([&data[98..], &b"tail"[..]].concat(), output.take(1000).unwrap());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_file_cut_short() {
+        let file = FileBody::new(temp_file("short", b"abc"), 0, 10);
+
+        assert_eq!(ErrorKind::UnexpectedEof, file.read(0, 10).unwrap_err().kind());
+        assert_eq!(b"bc".to_vec(), file.read(1, 2).unwrap());
+        assert_eq!(b"c".to_vec(), file.slice(1, 2).unwrap().read(1, 5).unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sendfile() {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        struct Socket(UnixStream);
+
+        impl Write for Socket {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl Sink for Socket {
+            fn raw_socket(&self) -> Option<RawFd> {
+                Some(self.0.as_raw_fd())
+            }
+        }
+
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut output = Output::from(b"<".to_vec());
+        let mut sock = Socket(a);
+
+        output.push_file(FileBody::new(temp_file("sendfile", b"hello, world"), 7, 5));
+        output.push(b">".to_vec());
+
+        while !output.is_empty() {
+            output.write_to(&mut sock).unwrap();
+        }
+
+        drop(sock);
+
+        let mut received = String::new();
+
+        b.read_to_string(&mut received).unwrap();
+        assert_eq!("<world>", received);
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+//! Parsing of `Range` request headers.
+
+// requests for more ranges than this are sent the whole body instead, so
+// that a long list of tiny ranges can't multiply the size of a response
+const MAX_RANGES: usize = 16;
+
+/// What a `Range` header asks for, for a body of a given size.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Ranges {
+    /// The whole body: there's no usable header, or it's better ignored.
+    Full,
+    /// These parts of the body, as offsets and lengths.
+    Partial(Vec<(u64, u64)>),
+    /// None of the ranges asked for overlaps the body.
+    Unsatisfiable,
+}
+
+/// Work out which parts of a body of `size` bytes `header` asks for. A
+/// header that can't be parsed is ignored, as the standard requires.
+pub(crate) fn parse(header: &str, size: u64) -> Ranges {
+    let header = header.trim();
+
+    match header.get(..6) {
+        Some(unit) if unit.eq_ignore_ascii_case("bytes=")   => {},
+        _                                                   => return Ranges::Full,
+    }
+
+    let mut ranges = Vec::new();
+
+    for spec in header[6..].split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        let (first, last) = match spec.split_once('-') {
+            Some((first, last)) => (first.trim(), last.trim()),
+            None                => return Ranges::Full,
+        };
+
+        if first.is_empty() {
+            // the final `last` bytes
+            let count = match last.parse::<u64>() {
+                Ok(count)   => count,
+                Err(_)      => return Ranges::Full,
+            };
+
+            if count > 0 && size > 0 {
+                let count = count.min(size);
+
+                ranges.push((size - count, count));
+            }
+
+            continue;
+        }
+
+        let first = match first.parse::<u64>() {
+            Ok(first)   => first,
+            Err(_)      => return Ranges::Full,
+        };
+
+        let last = if last.is_empty() {
+            u64::MAX
+        } else {
+            match last.parse::<u64>() {
+                Ok(last) if last >= first   => last,
+                _                           => return Ranges::Full,
+            }
+        };
+
+        if first < size {
+            ranges.push((first, last.min(size - 1) - first + 1));
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return Ranges::Full;
+    }
+
+    if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Partial(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let cases = vec![
+            ("bytes=0-499",             Ranges::Partial(vec![(0, 500)])),
+            ("bytes=500-",              Ranges::Partial(vec![(500, 500)])),
+            ("bytes=-200",              Ranges::Partial(vec![(800, 200)])),
+            ("bytes=-2000",             Ranges::Partial(vec![(0, 1000)])),
+            ("bytes=900-1999",          Ranges::Partial(vec![(900, 100)])),
+            ("Bytes=0-0, -1",           Ranges::Partial(vec![(0, 1), (999, 1)])),
+            ("bytes=0-1,,2-3",          Ranges::Partial(vec![(0, 2), (2, 2)])),
+            ("bytes=1000-, 2000-3000",  Ranges::Unsatisfiable),
+            ("bytes=-0",                Ranges::Unsatisfiable),
+            ("bytes=5-4",               Ranges::Full),
+            ("bytes=a-b",               Ranges::Full),
+            ("bytes=10",                Ranges::Full),
+            ("items=0-10",              Ranges::Full),
+            ("",                        Ranges::Full),
+        ];
+
+        for (header, ranges) in cases {
+            assert_eq!(ranges, parse(header, 1000), "{}", header);
+        }
+
+        assert_eq!(Ranges::Unsatisfiable, parse("bytes=-5", 0));
+        assert_eq!(Ranges::Full, parse(&format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(",")), 1000));
+    }
+}
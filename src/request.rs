@@ -6,11 +6,14 @@
 // terms
 
 use std;
+use std::sync::Arc;
 use std::collections::HashMap;
 use serde_json;
 use serde::de::DeserializeOwned;
 
+use crate::compress::{self, DecodeError, Encoding};
 use crate::utils::replace_escape;
+use crate::extract::StateMap;
 
 /// This enum represents the various types of HTTP requests.
 #[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
@@ -23,6 +26,34 @@ pub enum Method {
     NoImpl,
 }
 
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Method::Get     => "GET",
+            Method::Put     => "PUT",
+            Method::Post    => "POST",
+            Method::Delete  => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::NoImpl  => "UNKNOWN",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl<'a> From<&'a str> for Method {
+    fn from(name: &'a str) -> Method {
+        match name {
+            "GET"           => Method::Get,
+            "PUT" | "PATCH" => Method::Put,
+            "POST"          => Method::Post,
+            "DELETE"        => Method::Delete,
+            "OPTIONS"       => Method::Options,
+            _               => Method::NoImpl,
+        }
+    }
+}
+
 /// Storage for URI query parameters -- either single or multiple.
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub enum QueryArg {
@@ -35,6 +66,7 @@ pub enum QueryArg {
 pub enum RequestError {
     JsonStrError(serde_json::Error),
     StrCopyError(std::string::FromUtf8Error),
+    ParseError(String),
 }
 
 impl From<serde_json::Error> for RequestError {
@@ -54,6 +86,7 @@ impl std::fmt::Display for RequestError {
         match self {
             RequestError::JsonStrError(err) => write!(f, "JSON error: {}", err),
             RequestError::StrCopyError(err) => write!(f, "UTF-8 error: {}", err),
+            RequestError::ParseError(msg)   => write!(f, "parse error: {}", msg),
         }
     }
 }
@@ -63,14 +96,21 @@ impl std::error::Error for RequestError {
         match self {
             RequestError::JsonStrError(err) => Some(err),
             RequestError::StrCopyError(err) => Some(err),
+            RequestError::ParseError(_)     => None,
         }
     }
 }
 
 /// A trait that allows for extracting variables from URIs.
-pub trait FromUri {
+pub trait FromUri: Sized {
     /// A function to parse a string into the correct type.
     fn from_uri(data: &str) -> Self;
+
+    /// A function to parse a string into the correct type, without panicking
+    /// if it can't be.
+    fn try_from_uri(data: &str) -> Result<Self, RequestError> {
+        Ok(Self::from_uri(data))
+    }
 }
 
 impl FromUri for String {
@@ -83,21 +123,37 @@ impl FromUri for i32 {
     fn from_uri(data: &str) -> i32 {
         data.parse::<i32>().expect("matched integer can't be parsed")
     }
+
+    fn try_from_uri(data: &str) -> Result<i32, RequestError> {
+        data.parse::<i32>().map_err(|_| RequestError::ParseError(format!("{:?} is not an integer", data)))
+    }
 }
 
 impl FromUri for u32 {
     fn from_uri(data: &str) -> u32 {
         data.parse::<u32>().expect("matched integer can't be parsed")
     }
+
+    fn try_from_uri(data: &str) -> Result<u32, RequestError> {
+        data.parse::<u32>().map_err(|_| RequestError::ParseError(format!("{:?} is not an unsigned integer", data)))
+    }
 }
 
 impl FromUri for f32 {
     fn from_uri(data: &str) -> f32 {
         data.parse::<f32>().expect("matched float can't be parsed")
     }
+
+    fn try_from_uri(data: &str) -> Result<f32, RequestError> {
+        data.parse::<f32>().map_err(|_| RequestError::ParseError(format!("{:?} is not a number", data)))
+    }
 }
 
 /// This struct represents a request from an HTTP client.
+///
+/// A `payload` sent with a `Content-Encoding` has been decompressed by the
+/// time a handler sees it. That needs the `compression` feature: without it,
+/// compressed bodies are answered with a 415 and never reach a handler.
 #[derive(Debug)]
 pub struct Request {
     pub method:  Method,
@@ -108,6 +164,11 @@ pub struct Request {
     pub params:  HashMap<String, String>,
     pub args:    HashMap<String, QueryArg>,
     headers:     HashMap<String, String>,
+
+    pub(crate) version:     String,
+    pub(crate) param_order: Vec<String>,
+    pub(crate) state:       Arc<StateMap>,
+    pub(crate) listener:    Option<String>,
 }
 
 impl Request {
@@ -122,9 +183,40 @@ impl Request {
             params:  HashMap::new(),
             args:    HashMap::new(),
             payload: Vec::with_capacity(2048),
+
+            version:     String::from("HTTP/1.1"),
+            param_order: Vec::new(),
+            state:       Arc::new(StateMap::default()),
+            listener:    None,
         }
     }
 
+    /// Copy everything but the payload, for use when the original has been
+    /// handed off to a handler but an error response still needs rendering.
+    pub(crate) fn head(&self) -> Request {
+        Request {
+            method:  self.method,
+            uri:     self.uri.clone(),
+            path:    self.path.clone(),
+            query:   self.query.clone(),
+            headers: self.headers.clone(),
+            params:  self.params.clone(),
+            args:    self.args.clone(),
+            payload: Vec::new(),
+
+            version:     self.version.clone(),
+            param_order: self.param_order.clone(),
+            state:       self.state.clone(),
+            listener:    self.listener.clone(),
+        }
+    }
+
+    /// The name of the listener the Request arrived on, if it was given one
+    /// with `Canteen::bind_named` or `Canteen::bind_unix_named`.
+    pub fn listener(&self) -> Option<&str> {
+        self.listener.as_deref()
+    }
+
     /// Get an HTTP header contained in the Request.
     ///
     /// # Examples
@@ -144,11 +236,20 @@ impl Request {
     /// }
     /// ```
     pub fn get_header(&self, name: &str) -> Option<String> {
-        let key = String::from(name.to_lowercase());
+        let key = name.to_lowercase();
+
+        self.headers.get(&key).cloned()
+    }
 
-        match self.headers.get(&key) {
-            Some(val)   => Some(val.clone()),
-            None        => None,
+    /// Whether the client is willing to keep the connection open for further
+    /// requests once this one has been answered.
+    pub fn wants_keep_alive(&self) -> bool {
+        let connection = self.get_header("Connection").unwrap_or_default().to_lowercase();
+
+        match self.version.as_str() {
+            "HTTP/1.1"  => !connection.contains("close"),
+            "HTTP/1.0"  => connection.contains("keep-alive"),
+            _           => false,
         }
     }
 
@@ -174,6 +275,41 @@ impl Request {
         FromUri::from_uri(&self.params[name])
     }
 
+    /// Decompress the payload if it was sent with a `Content-Encoding`, and
+    /// update the headers to match. The payload is left as it is if it fails.
+    pub(crate) fn decode_payload(&mut self, limit: usize) -> Result<(), DecodeError> {
+        let header = match self.headers.get("content-encoding") {
+            Some(header)    => header.clone(),
+            None            => return Ok(()),
+        };
+
+        if self.payload.is_empty() {
+            return Ok(());
+        }
+
+        let mut payload: Option<Vec<u8>> = None;
+
+        // the encodings are listed in the order they were applied
+        for name in header.split(',').map(str::trim).rev() {
+            if name.is_empty() || name.eq_ignore_ascii_case("identity") {
+                continue;
+            }
+
+            let encoding = Encoding::from_name(name).ok_or_else(|| DecodeError::Unsupported(name.to_string()))?;
+            let decoded = compress::decode(encoding, payload.as_ref().unwrap_or(&self.payload), limit)?;
+
+            payload = Some(decoded);
+        }
+
+        if let Some(payload) = payload {
+            self.headers.insert(String::from("content-length"), payload.len().to_string());
+            self.payload = payload;
+        }
+
+        self.headers.remove("content-encoding");
+        Ok(())
+    }
+
     /// Get a raw JSON payload from the request.
     ///
     /// # Examples
@@ -229,20 +365,18 @@ impl Request {
         Ok(data)
     }
 
-    fn parse(&mut self, rqstr: &str) {
+    fn parse(&mut self, rqstr: &str) -> Result<(), RequestError> {
         let mut buf: Vec<&str> = rqstr.splitn(2, "\r\n").collect();
         let ask: Vec<&str> = buf[0].splitn(3, ' ').collect();
 
-        self.method = match ask[0] {
-            "GET"           => Method::Get,
-            "PUT" | "PATCH" => Method::Put,
-            "POST"          => Method::Post,
-            "DELETE"        => Method::Delete,
-            "OPTIONS"       => Method::Options,
-            _               => Method::NoImpl,
-        };
+        if ask.len() != 3 || !ask[2].starts_with("HTTP/") {
+            return Err(RequestError::ParseError(format!("malformed request line {:?}", buf[0])));
+        }
+
+        self.method = Method::from(ask[0]);
 
         self.uri = String::from(ask[1]);
+        self.version = String::from(ask[2]);
 
         // Fetch any ?foo=bar&baz=quux query parameters.
         let mut split_uri = ask[1].splitn(2, '?');
@@ -254,11 +388,11 @@ impl Request {
         for pair in self.query.clone().split('&') {
             let mut split_pair = pair.splitn(2, '=');
 
-            let key = String::from(replace_escape(split_pair.next().unwrap()));
-            let val = String::from(replace_escape(split_pair.next().unwrap_or("")));
+            let key = replace_escape(split_pair.next().unwrap());
+            let val = replace_escape(split_pair.next().unwrap_or(""));
 
-            if val.len() > 0 {
-                let key_entry = tmp_query_args.entry(key).or_insert(Vec::new());
+            if !val.is_empty() {
+                let key_entry = tmp_query_args.entry(key).or_default();
                 key_entry.push(val);
             }
         }
@@ -272,10 +406,14 @@ impl Request {
         }
 
         loop {
+            if buf.len() < 2 {
+                return Err(RequestError::ParseError(String::from("unterminated header section")));
+            }
+
             buf = buf[1].splitn(2, "\r\n").collect();
 
-            if buf[0] == "" {
-                if buf.len() == 1 || buf[1] == "" {
+            if buf[0].is_empty() {
+                if buf.len() == 1 || buf[1].is_empty() {
                     // no payload
                     break;
                 }
@@ -284,12 +422,16 @@ impl Request {
                 break;
             }
 
-            let hdr: Vec<&str> = buf[0].splitn(2, ": ").collect();
+            let hdr: Vec<&str> = buf[0].splitn(2, ':').collect();
 
-            if hdr.len() == 2 {
-                self.headers.insert(String::from(hdr[0].to_lowercase()), String::from(hdr[1]));
+            if hdr.len() != 2 {
+                return Err(RequestError::ParseError(format!("malformed header {:?}", buf[0])));
             }
+
+            self.headers.insert(hdr[0].trim().to_lowercase(), String::from(hdr[1].trim()));
         }
+
+        Ok(())
     }
 }
 
@@ -299,13 +441,46 @@ impl Default for Request {
     }
 }
 
+impl Request {
+    /// Create a Request from raw bytes. Unlike `from_str`, only the request
+    /// line and headers need to be valid UTF-8; the body can be anything.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::Request;
+    ///
+    /// let raw = b"POST /upload HTTP/1.1\r\nContent-Length: 2\r\n\r\n\xff\xfe";
+    /// let req = Request::from_bytes(raw).unwrap();
+    ///
+    /// assert_eq!(vec![0xff, 0xfe], req.payload);
+    /// ```
+    pub fn from_bytes(raw: &[u8]) -> Result<Request, RequestError> {
+        let end = match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None      => raw.len(),
+        };
+
+        let head = match std::str::from_utf8(&raw[..end]) {
+            Ok(head)    => head,
+            Err(err)    => return Err(RequestError::ParseError(format!("invalid header encoding ({})", err))),
+        };
+
+        let mut req = Request::new();
+
+        req.parse(head)?;
+        req.payload.extend_from_slice(&raw[end..]);
+        Ok(req)
+    }
+}
+
 impl std::str::FromStr for Request {
     type Err = RequestError;
 
     /// Create a Request from an HTTP request string.
     fn from_str(rqstr: &str) -> Result<Self, Self::Err> {
         let mut req = Request::new();
-        req.parse(rqstr);
+        req.parse(rqstr)?;
         Ok(req)
     }
 }
@@ -350,6 +525,14 @@ mod tests {
         assert_eq!(-54.321f32, <f32 as FromUri>::from_uri(&neg));
     }
 
+    #[test]
+    fn test_fromuri_trait_try() {
+        assert_eq!(-12, <i32 as FromUri>::try_from_uri("-12").unwrap());
+        assert!(<i32 as FromUri>::try_from_uri("twelve").is_err());
+        assert!(<u32 as FromUri>::try_from_uri("-12").is_err());
+        assert_eq!("abc", <String as FromUri>::try_from_uri("abc").unwrap());
+    }
+
     #[test]
     fn test_get_fromuri_i32() {
         let mut req = Request::new();
@@ -366,12 +549,12 @@ mod tests {
 
         let data = req.get_json().unwrap();
 
-        assert_eq!(true, data.is_object());
+        assert!(data.is_object());
 
         let obj = data.as_object().unwrap();
         let val = obj.get("item").unwrap();
 
-        assert_eq!(true, val.is_u64());
+        assert!(val.is_u64());
         assert_eq!(123u64, val.as_u64().unwrap());
     }
 
@@ -392,4 +575,82 @@ mod tests {
         assert_eq!(req.args.get("foo").unwrap(), &QueryArg::Single("bar".into()));
         assert_eq!(req.args.get("baz").unwrap(), &QueryArg::Single("lol".into()));
     }
+
+    #[test]
+    fn test_wants_keep_alive() {
+        let cases = vec![
+            ("GET / HTTP/1.1\r\n\r\n",                              true),
+            ("GET / HTTP/1.1\r\nConnection: close\r\n\r\n",       false),
+            ("GET / HTTP/1.0\r\n\r\n",                              false),
+            ("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n",  true),
+        ];
+
+        for (rqstr, keep_alive) in cases.into_iter() {
+            assert_eq!(keep_alive, Request::from_str(rqstr).unwrap().wants_keep_alive());
+        }
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let req = Request::from_bytes(b"POST /up HTTP/1.1\r\nHost: a\r\n\r\n\x00\xff").unwrap();
+
+        assert_eq!("/up", req.path);
+        assert_eq!(vec![0x00, 0xff], req.payload);
+        assert!(Request::from_bytes(b"GET /\xff HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        assert!(Request::from_str("GET\r\n\r\n").is_err());
+        assert!(Request::from_str("GET / HTTP/1.1\r\nHost: foo").is_err());
+        assert!(Request::from_str("GET / HTTP/1.1\r\nnot a header\r\n\r\n").is_err());
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn test_decode_payload_unsupported() {
+        let mut req = Request::from_str("POST /item HTTP/1.1\r\nContent-Encoding: gzip\r\nContent-Length: 4\r\n\r\nabcd").unwrap();
+        assert_eq!(Err(DecodeError::Unsupported(String::from("gzip"))), req.decode_payload(1024));
+        assert_eq!(b"abcd".to_vec(), req.payload);
+
+        let mut req = Request::from_str("POST /item HTTP/1.1\r\nContent-Encoding: identity\r\nContent-Length: 4\r\n\r\nabcd").unwrap();
+        assert_eq!(Ok(()), req.decode_payload(1024));
+        assert_eq!(b"abcd".to_vec(), req.payload);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_decode_payload() {
+        let json = br#"{ "item": 12345 }"#;
+        let gzipped = compress::encode(Encoding::Gzip, json).unwrap();
+        let twice = compress::encode(Encoding::Brotli, &gzipped).unwrap();
+        let post = |encoding: &str, body: &[u8]| {
+            let mut raw = format!("POST /item HTTP/1.1\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\r\n",
+                                  encoding, body.len()).into_bytes();
+
+            raw.extend_from_slice(body);
+            Request::from_bytes(&raw).unwrap()
+        };
+
+        let mut req = post("gzip", &gzipped);
+        req.decode_payload(1024).unwrap();
+        assert_eq!(12345, req.get_json().unwrap()["item"]);
+        assert_eq!(None, req.get_header("Content-Encoding"));
+        assert_eq!(Some(json.len().to_string()), req.get_header("Content-Length"));
+
+        let mut req = post("gzip, identity, br", &twice);
+        req.decode_payload(1024).unwrap();
+        assert_eq!(json.to_vec(), req.payload);
+
+        let mut req = post("identity", json);
+        req.decode_payload(1024).unwrap();
+        assert_eq!(json.to_vec(), req.payload);
+
+        let mut req = post("compress", &gzipped);
+        assert_eq!(Err(DecodeError::Unsupported(String::from("compress"))), req.decode_payload(1024));
+        assert_eq!(gzipped, req.payload);
+
+        assert_eq!(Err(DecodeError::TooLarge), post("gzip", &gzipped).decode_payload(json.len() - 1));
+        assert_eq!(Err(DecodeError::Corrupt(Encoding::Brotli)), post("br", &gzipped).decode_payload(1024));
+    }
 }
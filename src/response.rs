@@ -6,10 +6,24 @@
 // terms
 
 use std::collections::BTreeMap;
-use chrono::Utc;
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::hash::Hasher;
+use chrono::{DateTime, Utc};
 use serde_json;
 use serde::Serialize;
 
+#[cfg(feature = "compression")]
+use crate::compress::{self, CompressOptions};
+use crate::conditional::{self, ETag, Fnv};
+use crate::output::{FileBody, Output, Segment, FILE_CHUNK};
+use crate::range::{self, Ranges};
+use crate::request::{Method, Request, RequestError};
+use crate::sse::EventStream;
+use crate::utils;
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// A trait that converts data from the handler function to a u8 slice.
@@ -41,6 +55,244 @@ impl ToOutput for Vec<u8> {
     }
 }
 
+/// A trait for types that can be turned into a full HTTP response, allowing
+/// handlers to return plain values instead of building a `Response` by hand.
+///
+/// Because `Result<R, E>` implements `IntoResponse` when both `R` and `E` do,
+/// handlers can use the `?` operator on any error type that implements it.
+///
+/// # Examples
+///
+/// ```rust
+/// use canteen::{Request, RequestError};
+///
+/// // Given the POST route "/echo"
+/// fn handler(req: &Request) -> Result<serde_json::Value, RequestError> {
+///     let data = req.get_json()?;
+///
+///     Ok(data)
+/// }
+/// ```
+pub trait IntoResponse {
+    /// Consume the value and build a Response from it.
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl IntoResponse for &str {
+    fn into_response(self) -> Response {
+        let mut res = Response::new();
+
+        res.payload.extend(self.as_bytes());
+
+        res
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        let mut res = Response::new();
+
+        res.append(self);
+
+        res
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Response {
+        let mut res = Response::new();
+
+        res.set_content_type("application/octet-stream");
+        res.append(self);
+
+        res
+    }
+}
+
+impl IntoResponse for serde_json::Value {
+    fn into_response(self) -> Response {
+        Response::as_json(&self)
+    }
+}
+
+impl IntoResponse for RequestError {
+    fn into_response(self) -> Response {
+        let mut res = Response::new();
+
+        res.set_status(400);
+        res.append(format!("bad request: {}", self));
+
+        res
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (u16, T) {
+    fn into_response(self) -> Response {
+        let (status, body) = self;
+        let mut res = body.into_response();
+
+        res.set_status(status);
+
+        res
+    }
+}
+
+impl<T, H, K, V> IntoResponse for (u16, H, T)
+        where T: IntoResponse,
+              H: IntoIterator<Item = (K, V)>,
+              K: AsRef<str>,
+              V: AsRef<str> {
+    fn into_response(self) -> Response {
+        let (status, headers, body) = self;
+        let mut res = body.into_response();
+
+        res.set_status(status);
+
+        for (key, value) in headers {
+            if key.as_ref().eq_ignore_ascii_case("Content-Type") {
+                res.set_content_type(value.as_ref());
+            } else {
+                res.add_header(key.as_ref(), value.as_ref());
+            }
+        }
+
+        res
+    }
+}
+
+impl<R: IntoResponse, E: IntoResponse> IntoResponse for Result<R, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(val)  => val.into_response(),
+            Err(err) => err.into_response(),
+        }
+    }
+}
+
+/// Wraps a serializable value so that it is sent as an `application/json`
+/// response.
+///
+/// # Examples
+///
+/// ```rust
+/// use canteen::{Request, Json};
+/// use serde_json::json;
+///
+/// fn handler(_: &Request) -> (u16, Json<serde_json::Value>) {
+///     (201, Json(json!({ "id": 1234 })))
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        match serde_json::to_string(&self.0) {
+            Ok(body) => {
+                let mut res = Response::new();
+
+                res.set_content_type("application/json");
+                res.append(body);
+
+                res
+            },
+            Err(err) => {
+                let mut res = Response::new();
+
+                res.set_status(500);
+                res.append(format!("unable to serialize response: {}", err));
+
+                res
+            },
+        }
+    }
+}
+
+/// Wraps a body so that it is sent with a `text/html` content type.
+///
+/// # Examples
+///
+/// ```rust
+/// use canteen::{Request, Html};
+///
+/// fn handler(_: &Request) -> Html<&'static str> {
+///     Html("<b>Hello, world!</b>")
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Html<T>(pub T);
+
+impl<T: ToOutput> IntoResponse for Html<T> {
+    fn into_response(self) -> Response {
+        let mut res = Response::new();
+
+        res.set_content_type("text/html");
+        res.append(self.0);
+
+        res
+    }
+}
+
+/// A response that redirects the client to another location.
+///
+/// # Examples
+///
+/// ```rust
+/// use canteen::{Request, Redirect};
+///
+/// fn handler(_: &Request) -> Redirect {
+///     Redirect::to("/login")
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    status:   u16,
+    location: String,
+}
+
+impl Redirect {
+    /// Redirect with a 302 Found.
+    pub fn to(location: &str) -> Redirect {
+        Redirect { status: 302, location: String::from(location) }
+    }
+
+    /// Redirect with a 303 See Other, telling the client to follow up with a GET.
+    pub fn see_other(location: &str) -> Redirect {
+        Redirect { status: 303, location: String::from(location) }
+    }
+
+    /// Redirect with a 307 Temporary Redirect, preserving the request method.
+    pub fn temporary(location: &str) -> Redirect {
+        Redirect { status: 307, location: String::from(location) }
+    }
+
+    /// Redirect with a 301 Moved Permanently.
+    pub fn permanent(location: &str) -> Redirect {
+        Redirect { status: 301, location: String::from(location) }
+    }
+}
+
+impl IntoResponse for Redirect {
+    fn into_response(self) -> Response {
+        let mut res = Response::new();
+
+        res.set_status(self.status);
+        res.add_header("Location", &self.location);
+
+        res
+    }
+}
+
+// a response's status, headers, body and event stream
+#[cfg(feature = "http2")]
+type Parts = (u16, Vec<(String, String)>, Output, Option<EventStream>);
+
 /// This struct reprsents the response to an HTTP client.
 #[derive(Debug, Default)]
 pub struct Response {
@@ -49,6 +301,11 @@ pub struct Response {
     ctype:      String,
     headers:    BTreeMap<String, String>,
     payload:    Vec<u8>,
+    // the rest of the body, following on from `payload`: files, read only
+    // as they're written, and whatever's appended after them
+    parts:      Vec<Segment>,
+    // set if the response is an event stream, whose body follows later
+    pub(crate) events: Option<EventStream>,
 }
 
 impl Response {
@@ -60,13 +317,12 @@ impl Response {
             ctype:      String::from("text/plain"),
             headers:    BTreeMap::new(),
             payload:    Vec::with_capacity(2048),
+            parts:      Vec::new(),
+            events:     None,
         };
 
-        let now = Utc::now().format("%a, %d %b %Y, %H:%M:%S %Z").to_string();
-
-        res.add_header("Connection", "close");
         res.add_header("Server", &format!("canteen/{}", VERSION));
-        res.add_header("Date", &now);
+        res.add_header("Date", &utils::http_date(Utc::now()));
 
         res
     }
@@ -133,6 +389,7 @@ impl Response {
             415 => "Unsupported Media Type",
             416 => "Requested Range Not Satisfiable",
             417 => "Expectation Failed",
+            431 => "Request Header Fields Too Large",
             500 => "Internal Server Error",
             501 => "Not Implemented",
             502 => "Bad Gateway",
@@ -160,6 +417,20 @@ impl Response {
         self.cmsg = Response::get_http_message(status);
     }
 
+    /// Gets the response status for the HTTP response.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::Response;
+    ///
+    /// let res = Response::new();
+    /// assert_eq!(200, res.get_status());
+    /// ```
+    pub fn get_status(&self) -> u16 {
+        self.status
+    }
+
     /// Sets the Content-Type header for the HTTP response.
     ///
     /// # Examples
@@ -190,6 +461,123 @@ impl Response {
         }
     }
 
+    /// Gets a header that has been added to the HTTP response. The header
+    /// name is case-insensitive.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::Response;
+    ///
+    /// let mut res = Response::new();
+    /// res.add_header("X-Foo", "bar");
+    ///
+    /// assert_eq!(Some(String::from("bar")), res.get_header("x-foo"));
+    /// ```
+    pub fn get_header(&self, key: &str) -> Option<String> {
+        self.headers.iter()
+                    .find(|&(name, _)| name.eq_ignore_ascii_case(key))
+                    .map(|(_, value)| value.clone())
+    }
+
+    // set a header, replacing any already added
+    fn set_header(&mut self, key: &str, value: &str) {
+        self.headers.retain(|name, _| !name.eq_ignore_ascii_case(key));
+        self.headers.insert(String::from(key), String::from(value));
+    }
+
+    // note that the response depends on a request header, for caches
+    pub(crate) fn add_vary(&mut self, header: &str) {
+        let vary = match self.get_header("Vary") {
+            Some(vary)  => vary,
+            None        => return self.add_header("Vary", header),
+        };
+
+        let listed = vary.split(',').map(str::trim).any(|name| name == "*" || name.eq_ignore_ascii_case(header));
+
+        if !listed {
+            self.set_header("Vary", &format!("{}, {}", vary, header));
+        }
+    }
+
+    /// Sets the entity tag for the response. GET requests with `If-None-Match`
+    /// or `If-Match` headers are answered with a 304 or a 412 accordingly.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::Response;
+    /// use canteen::conditional::ETag;
+    ///
+    /// let mut res = Response::new();
+    /// res.set_etag(&ETag::weak("v1"));
+    ///
+    /// assert_eq!(Some(String::from("W/\"v1\"")), res.get_header("ETag"));
+    /// ```
+    pub fn set_etag(&mut self, etag: &ETag) {
+        self.set_header("ETag", &etag.to_string());
+    }
+
+    /// Sets the entity tag for the response to a hash of its body, which has
+    /// to be complete. Any files in the body are read to hash them.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use canteen::Response;
+    ///
+    /// let mut res = Response::new();
+    /// res.append("Hello, world!");
+    /// res.set_body_etag(false).unwrap();
+    ///
+    /// assert!(res.get_header("ETag").unwrap().starts_with('"'));
+    /// ```
+    pub fn set_body_etag(&mut self, weak: bool) -> io::Result<()> {
+        let mut hasher = Fnv::new();
+
+        hasher.write(&self.payload);
+
+        for part in &self.parts {
+            match *part {
+                Segment::Buf(ref buf)   => hasher.write(buf),
+                Segment::File(ref file) => {
+                    let mut at = 0;
+
+                    while at < file.len() {
+                        let chunk = file.read(at, FILE_CHUNK)?;
+
+                        at += chunk.len() as u64;
+                        hasher.write(&chunk);
+                    }
+                },
+            }
+        }
+
+        let etag = hasher.etag();
+
+        self.set_etag(&if weak { ETag::weak(etag.tag()) } else { etag });
+        Ok(())
+    }
+
+    /// Sets the Last-Modified date for the response. GET requests with
+    /// `If-Modified-Since` or `If-Unmodified-Since` headers are answered
+    /// with a 304 or a 412 accordingly.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use chrono::{TimeZone, Utc};
+    /// use canteen::Response;
+    ///
+    /// let mut res = Response::new();
+    /// res.set_last_modified(Utc.with_ymd_and_hms(2018, 1, 1, 0, 0, 0).unwrap());
+    ///
+    /// assert_eq!(Some(String::from("Mon, 01 Jan 2018 00:00:00 GMT")), res.get_header("Last-Modified"));
+    /// ```
+    pub fn set_last_modified(&mut self, when: DateTime<Utc>) {
+        self.set_header("Last-Modified", &utils::http_date(when));
+    }
+
     /// Appends data to the body of the HTTP response. The trait ToOutput must
     /// be implemented for the type passed.
     ///
@@ -203,13 +591,280 @@ impl Response {
     /// res.append(data);
     /// ```
     pub fn append<T: ToOutput>(&mut self, payload: T) {
-        self.payload.extend(payload.to_output().iter());
+        let data = payload.to_output();
+
+        match self.parts.last_mut() {
+            Some(Segment::Buf(buf)) => buf.extend(data.iter()),
+            Some(Segment::File(_))  => self.parts.push(Segment::Buf(data.to_vec())),
+            None                    => self.payload.extend(data.iter()),
+        }
+    }
+
+    /// Add a file to the end of the body. Rather than being read into
+    /// memory, the file is sent straight from disk as the client takes it,
+    /// so large files can be served cheaply.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::fs::File;
+    /// use canteen::{Request, Response};
+    /// use canteen::utils;
+    ///
+    /// fn download(req: &Request) -> Response {
+    ///     let mut res = Response::new();
+    ///
+    ///     res.set_content_type("application/octet-stream");
+    ///
+    ///     match File::open("download.bin").and_then(|f| res.set_file(f)) {
+    ///         Ok(())  => res,
+    ///         Err(_)  => utils::err_404(req),
+    ///     }
+    /// }
+    /// ```
+    pub fn set_file(&mut self, file: File) -> io::Result<()> {
+        let len = file.metadata()?.len();
+
+        self.parts.push(Segment::File(FileBody::new(file, 0, len)));
+        Ok(())
+    }
+
+    /// Compress the body in the encoding the request prefers, if the options
+    /// allow it. Bodies with files in them are left for precompressed files
+    /// to take care of.
+    #[cfg(feature = "compression")]
+    pub(crate) fn compress(&mut self, req: &Request, options: &CompressOptions) {
+        let bodyless = matches!(self.status, 100..=199 | 204 | 206 | 304);
+
+        if bodyless || self.events.is_some() || self.get_header("Content-Encoding").is_some()
+                    || self.parts.iter().any(|part| matches!(part, Segment::File(_)))
+                    || !options.allows(&self.ctype, self.body_len()) {
+            return;
+        }
+
+        self.add_vary("Accept-Encoding");
+
+        let encoding = match compress::negotiate(req.get_header("Accept-Encoding").as_deref(), options.available()) {
+            Some(encoding)  => encoding,
+            None            => return,
+        };
+
+        let mut body = std::mem::take(&mut self.payload);
+
+        for part in self.parts.drain(..) {
+            if let Segment::Buf(buf) = part {
+                body.extend(buf);
+            }
+        }
+
+        match compress::encode(encoding, &body) {
+            Ok(data) if data.len() < body.len() => {
+                self.payload = data;
+                self.add_header("Content-Encoding", encoding.name());
+
+                // the compressed body is a different representation, so it
+                // needs a tag of its own
+                if let Some(etag) = self.get_header("ETag").and_then(|etag| etag.parse::<ETag>().ok()) {
+                    let tag = format!("{}-{}", etag.tag(), encoding.name());
+
+                    self.set_etag(&if etag.is_weak() { ETag::weak(&tag) } else { ETag::strong(&tag) });
+                }
+            },
+            _                                   => self.payload = body,
+        }
+    }
+
+    /// Apply the request's conditional headers, then its `Range` header, to
+    /// the response a handler gave for it.
+    pub(crate) fn finish(&mut self, req: &Request) {
+        self.apply_conditionals(req);
+        self.apply_ranges(req);
+    }
+
+    // replace a successful response with a 304 or a 412 if the request's
+    // preconditions say so, going by the validators the handler set. only
+    // GETs are checked here: by now any other method has had its effect.
+    fn apply_conditionals(&mut self, req: &Request) {
+        if req.method != Method::Get || self.status / 100 != 2 || self.events.is_some() {
+            return;
+        }
+
+        let etag = self.get_header("ETag").and_then(|etag| etag.parse::<ETag>().ok());
+        let last = self.get_header("Last-Modified").and_then(|last| utils::parse_http_date(&last));
+
+        if etag.is_none() && last.is_none() {
+            return;
+        }
+
+        if let Some(status) = conditional::check_preconditions(req, etag.as_ref(), last) {
+            self.set_status(status);
+            self.payload.clear();
+            self.parts.clear();
+        }
+    }
+
+    /// Answer a `Range` header on a request for a single file. The response
+    /// advertises that ranges are accepted, and is cut down to the ranges
+    /// asked for, unless an `If-Range` header says the file has changed.
+    fn apply_ranges(&mut self, req: &Request) {
+        if self.status != 200 || !self.payload.is_empty() || self.parts.len() != 1 {
+            return;
+        }
+
+        let size = match self.parts[0] {
+            Segment::File(ref file) => file.len(),
+            Segment::Buf(_)         => return,
+        };
+
+        self.add_header("Accept-Ranges", "bytes");
+
+        if req.method != Method::Get {
+            return;
+        }
+
+        let header = match req.get_header("Range") {
+            Some(header)    => header,
+            None            => return,
+        };
+
+        if let Some(validator) = req.get_header("If-Range") {
+            if !self.if_range_matches(validator.trim()) {
+                return;
+            }
+        }
+
+        match range::parse(&header, size) {
+            Ranges::Full                => {},
+            Ranges::Unsatisfiable       => {
+                self.set_status(416);
+                self.add_header("Content-Range", &format!("bytes */{}", size));
+                self.parts.clear();
+            },
+            Ranges::Partial(ranges)     => {
+                // on failure the whole file is still there to send
+                if let Ok(parts) = self.range_parts(&ranges, size) {
+                    if ranges.len() == 1 {
+                        let (offset, len) = ranges[0];
+
+                        self.add_header("Content-Range", &format!("bytes {}-{}/{}", offset, offset + len - 1, size));
+                    }
+
+                    self.set_status(206);
+                    self.parts = parts;
+                }
+            },
+        }
+    }
+
+    // whether an If-Range validator names the version being sent: an entity
+    // tag has to match the ETag exactly, and a date the Last-Modified
+    fn if_range_matches(&self, validator: &str) -> bool {
+        if validator.starts_with('"') {
+            self.get_header("ETag").as_deref() == Some(validator)
+        } else if validator.starts_with("W/") {
+            // weak tags are never good enough for a range
+            false
+        } else {
+            self.get_header("Last-Modified").as_deref() == Some(validator)
+        }
+    }
+
+    // the body for a set of ranges of the file: just the part of the file
+    // for one, or a multipart/byteranges body for several
+    fn range_parts(&mut self, ranges: &[(u64, u64)], size: u64) -> io::Result<Vec<Segment>> {
+        let file = match self.parts[0] {
+            Segment::File(ref file) => file,
+            Segment::Buf(_)         => unreachable!(),
+        };
+
+        if ranges.len() == 1 {
+            let (offset, len) = ranges[0];
+
+            return Ok(vec![Segment::File(file.slice(offset, len)?)]);
+        }
+
+        let boundary = boundary();
+        let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+
+        for &(offset, len) in ranges {
+            let head = format!("\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                               boundary, self.ctype, offset, offset + len - 1, size);
+
+            parts.push(Segment::Buf(head.into_bytes()));
+            parts.push(Segment::File(file.slice(offset, len)?));
+        }
+
+        parts.push(Segment::Buf(format!("\r\n--{}--\r\n", boundary).into_bytes()));
+        self.ctype = format!("multipart/byteranges; boundary={}", boundary);
+
+        Ok(parts)
+    }
+
+    // the length of the whole body
+    fn body_len(&self) -> usize {
+        self.payload.len() + self.parts.iter().map(Segment::len).sum::<usize>()
+    }
+
+    /// Splits the response into its status, headers, body and event stream,
+    /// for protocols that don't send it as HTTP/1.1 text. Any file in the
+    /// body is left to be read as it's sent.
+    #[cfg(feature = "http2")]
+    pub(crate) fn into_parts(self) -> Parts {
+        let length = self.body_len();
+        let mut headers: Vec<(String, String)> = self.headers.into_iter().collect();
+
+        if self.status != 304 {
+            headers.push((String::from("Content-Type"), self.ctype));
+        }
+
+        if self.events.is_none() && self.status != 304 {
+            headers.push((String::from("Content-Length"), length.to_string()));
+        }
+
+        let mut body = Output::from(self.payload);
+
+        for part in self.parts {
+            body.push_segment(part);
+        }
+
+        (self.status, headers, body, self.events)
     }
 
     /// Returns a byte array containing the full contents of the HTTP response,
-    /// for use by the Canteen struct.
-    pub fn gen_output(&self) -> Vec<u8> {
-        let mut output: Vec<u8> = Vec::with_capacity(self.payload.len() + 500);
+    /// for use by the Canteen struct. Fails if a file in the body can't be read.
+    pub fn gen_output(&self) -> io::Result<Vec<u8>> {
+        let mut output: Vec<u8> = Vec::with_capacity(self.body_len() + 500);
+
+        output.extend(self.gen_head());
+        output.extend(self.payload.iter());
+
+        for part in &self.parts {
+            match *part {
+                Segment::Buf(ref buf)   => output.extend(buf.iter()),
+                Segment::File(ref file) => output.extend(file.read(0, file.len() as usize)?),
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Splits the response into its output, with the head and body in
+    /// separate buffers so that the body needn't be copied, and its event
+    /// stream.
+    pub(crate) fn into_output(self) -> (Output, Option<EventStream>) {
+        let mut output = Output::from(self.gen_head());
+
+        output.push(self.payload);
+
+        for part in self.parts {
+            output.push_segment(part);
+        }
+
+        (output, self.events)
+    }
+
+    // the status line and headers
+    fn gen_head(&self) -> Vec<u8> {
         let mut inter = String::new();
 
         inter.push_str(&format!("HTTP/1.1 {} {}\r\n", self.status, self.cmsg));
@@ -218,20 +873,41 @@ impl Response {
             inter.push_str(&format!("{}: {}\r\n", key, value));
         }
 
+        if self.status == 304 {
+            // there's no body to describe
+            inter.push_str("\r\n");
+            return inter.into_bytes();
+        }
+
         inter.push_str(&format!("Content-Type: {}\r\n", self.ctype));
-        inter.push_str(&format!("Content-Length: {}\r\n", self.payload.len()));
-        inter.push_str("\r\n");
 
-        output.extend(inter.as_bytes());
-        output.extend(self.payload.iter());
+        if self.events.is_none() {
+            // an event stream's body runs until the connection is closed
+            inter.push_str(&format!("Content-Length: {}\r\n", self.body_len()));
+        }
+
+        inter.push_str("\r\n");
 
-        output
+        inter.into_bytes()
     }
 }
 
+// a multipart boundary that's different for every response, so that it's
+// very unlikely to turn up in the parts themselves
+fn boundary() -> String {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+
+    format!("canteen-{:08x}{:08x}", nanos, COUNT.fetch_add(1, Ordering::Relaxed))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::str::FromStr;
+    #[cfg(feature = "compression")]
+    use crate::compress::Encoding;
 
     #[derive(Serialize)]
     struct Foo {
@@ -247,7 +923,313 @@ mod tests {
         res_r.set_content_type("application/json");
         res_r.append(serde_json::to_string(&foo).unwrap());
 
-        assert_eq!(res_r.gen_output(), res_j.gen_output());
+        assert_eq!(res_r.gen_output().unwrap(), res_j.gen_output().unwrap());
+    }
+
+    #[test]
+    fn test_into_output() {
+        let mut res = Response::new();
+
+        res.append("head, ");
+        res.parts.push(Segment::Buf(b"shoulders, ".to_vec()));
+        res.append("knees");
+
+        let expected = res.gen_output().unwrap();
+        let (output, events) = res.into_output();
+        let text = String::from_utf8(output.to_vec()).unwrap();
+
+        assert!(events.is_none());
+        assert_eq!(expected, output.to_vec());
+        assert!(text.contains("Content-Length: 22\r\n"));
+        assert!(text.ends_with("\r\n\r\nhead, shoulders, knees"));
+    }
+
+    #[test]
+    fn test_file_body() {
+        let manifest = std::fs::read("Cargo.toml").unwrap();
+        let mut res = Response::new();
+
+        res.append("<");
+        res.set_file(File::open("Cargo.toml").unwrap()).unwrap();
+
+        let expected = res.gen_output().unwrap();
+        let (output, _) = res.into_output();
+
+        assert_eq!(expected, output.to_vec());
+        assert!(expected.ends_with(&manifest));
+        assert!(String::from_utf8_lossy(&expected).contains(&format!("Content-Length: {}\r\n", manifest.len() + 1)));
+
+        // HTTP/2 reads the file as it's sent, too
+        #[cfg(feature = "http2")]
+        {
+            let mut res = Response::new();
+
+            res.set_file(File::open("Cargo.toml").unwrap()).unwrap();
+
+            let (_, _, mut body, _) = res.into_parts();
+
+            assert_eq!(manifest.len(), body.len());
+            assert_eq!(manifest, body.take(usize::MAX).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_file_body_truncated() {
+        let path = std::env::temp_dir().join(format!("canteen-truncated-{}", std::process::id()));
+
+        std::fs::write(&path, b"head, shoulders, knees").unwrap();
+
+        let mut res = Response::new();
+
+        res.set_file(File::open(&path).unwrap()).unwrap();
+
+        // a file cut short after the response was built can't be sent
+        std::fs::write(&path, b"head").unwrap();
+
+        let err = res.gen_output().unwrap_err();
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    // a response for the manifest, and the body sent for a request with the
+    // given extra headers
+    fn ranged(headers: &str) -> (Response, String) {
+        let req = Request::from_str(&format!("GET /Cargo.toml HTTP/1.1\r\n{}\r\n", headers)).unwrap();
+        let mut res = Response::new();
+
+        res.set_file(File::open("Cargo.toml").unwrap()).unwrap();
+        res.add_header("ETag", "\"abc\"");
+        res.add_header("Last-Modified", "Mon, 01 Jan 2018 00:00:00 GMT");
+        res.finish(&req);
+
+        let output = String::from_utf8(res.gen_output().unwrap()).unwrap();
+        let body = output.split_once("\r\n\r\n").map_or("", |(_, body)| body).to_string();
+
+        (res, body)
+    }
+
+    #[test]
+    fn test_ranges() {
+        let manifest = std::fs::read_to_string("Cargo.toml").unwrap();
+        let size = manifest.len();
+
+        let (res, body) = ranged("");
+        assert_eq!(200, res.status);
+        assert_eq!(Some(String::from("bytes")), res.get_header("Accept-Ranges"));
+        assert_eq!(manifest, body);
+
+        let (res, body) = ranged("Range: bytes=0-9\r\n");
+        assert_eq!(206, res.status);
+        assert_eq!(Some(format!("bytes 0-9/{}", size)), res.get_header("Content-Range"));
+        assert_eq!(&manifest[..10], body);
+
+        let (res, body) = ranged("Range: bytes=0-4, -5\r\n");
+        let boundary = res.ctype.strip_prefix("multipart/byteranges; boundary=").unwrap().to_string();
+        let expected = format!("\r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-4/{1}\r\n\r\n{2}\
+                                \r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes {3}-{4}/{1}\r\n\r\n{5}\
+                                \r\n--{0}--\r\n",
+                               boundary, size, &manifest[..5], size - 5, size - 1, &manifest[size - 5..]);
+        assert_eq!(206, res.status);
+        assert!(res.get_header("Content-Range").is_none());
+        assert_eq!(expected, body);
+
+        let (res, body) = ranged(&format!("Range: bytes={}-\r\n", size));
+        assert_eq!(416, res.status);
+        assert_eq!(Some(format!("bytes */{}", size)), res.get_header("Content-Range"));
+        assert_eq!("", body);
+
+        assert_eq!(206, ranged("Range: bytes=0-9\r\nIf-Range: \"abc\"\r\n").0.status);
+        assert_eq!(206, ranged("Range: bytes=0-9\r\nIf-Range: Mon, 01 Jan 2018 00:00:00 GMT\r\n").0.status);
+        assert_eq!(200, ranged("Range: bytes=0-9\r\nIf-Range: \"def\"\r\n").0.status);
+        assert_eq!(200, ranged("Range: bytes=0-9\r\nIf-Range: W/\"abc\"\r\n").0.status);
+        assert_eq!(200, ranged("Range: bytes=0-9\r\nIf-Range: Tue, 02 Jan 2018 00:00:00 GMT\r\n").0.status);
+        assert_eq!(200, ranged("Range: items=0-9\r\n").0.status);
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let respond = |headers: &str| {
+            let req = Request::from_str(&format!("GET /doc HTTP/1.1\r\n{}\r\n", headers)).unwrap();
+            let mut res = Response::new();
+
+            res.append("Hello, world!");
+            res.set_body_etag(false).unwrap();
+            res.finish(&req);
+            res
+        };
+
+        let etag = respond("").get_header("ETag").unwrap();
+        assert_eq!(etag, ETag::from_data(b"Hello, world!").to_string());
+
+        let res = respond(&format!("If-None-Match: {}\r\n", etag));
+        let output = String::from_utf8(res.gen_output().unwrap()).unwrap();
+        assert_eq!(304, res.status);
+        assert!(output.contains(&format!("ETag: {}\r\n", etag)));
+        assert!(!output.contains("Content-Length"));
+        assert!(!output.contains("Content-Type"));
+        assert!(output.ends_with("\r\n\r\n"));
+
+        let res = respond("If-Match: \"other\"\r\n");
+        assert_eq!(412, res.status);
+        assert_eq!(0, res.body_len());
+
+        assert_eq!(200, respond(&format!("If-Match: {}\r\n", etag)).status);
+
+        // errors aren't turned into 304s
+        let req = Request::from_str(&format!("GET /doc HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n", etag)).unwrap();
+        let mut res = utils::make_response("gone", "text/plain", 404);
+        res.set_etag(&etag.parse().unwrap());
+        res.finish(&req);
+        assert_eq!(404, res.status);
+
+        // a PUT has already been carried out, so it's left to its handler
+        let req = Request::from_str("PUT /doc HTTP/1.1\r\nIf-Match: \"other\"\r\n\r\n").unwrap();
+        let mut res = utils::make_response("saved", "text/plain", 200);
+        res.set_etag(&ETag::strong("v2"));
+        res.finish(&req);
+        assert_eq!(200, res.status);
+    }
+
+    #[test]
+    fn test_add_vary() {
+        let mut res = Response::new();
+
+        res.add_vary("Accept-Encoding");
+        res.add_vary("accept-encoding");
+        assert_eq!(Some(String::from("Accept-Encoding")), res.get_header("Vary"));
+
+        res.add_vary("Accept-Language");
+        assert_eq!(Some(String::from("Accept-Encoding, Accept-Language")), res.get_header("Vary"));
+
+        let mut res = Response::new();
+
+        res.add_header("vary", "*");
+        res.add_vary("Accept-Encoding");
+        assert_eq!(Some(String::from("*")), res.get_header("Vary"));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compress() {
+        use std::io::Read;
+
+        let options = CompressOptions::new().encodings(&[Encoding::Gzip]);
+        let body = "canteen ".repeat(1000);
+        let respond = |headers: &str, ctype: &str, body: &str| {
+            let req = Request::from_str(&format!("GET /doc HTTP/1.1\r\n{}\r\n", headers)).unwrap();
+            let mut res = utils::make_response(body.to_string(), ctype, 200);
+
+            res.set_etag(&ETag::strong("v1"));
+            res.compress(&req, &options);
+            res.finish(&req);
+            res
+        };
+
+        let res = respond("Accept-Encoding: gzip, br\r\n", "text/html", &body);
+        let mut out = String::new();
+
+        flate2::read::GzDecoder::new(&res.payload[..]).read_to_string(&mut out).unwrap();
+        assert_eq!(body, out);
+        assert_eq!(Some(String::from("gzip")), res.get_header("Content-Encoding"));
+        assert_eq!(Some(String::from("Accept-Encoding")), res.get_header("Vary"));
+        assert_eq!(Some(String::from("\"v1-gzip\"")), res.get_header("ETag"));
+
+        let res = respond("Accept-Encoding: gzip\r\nIf-None-Match: \"v1-gzip\"\r\n", "text/html", &body);
+        assert_eq!(304, res.status);
+
+        let res = respond("Accept-Encoding: br\r\n", "text/html", &body);
+        assert_eq!(None, res.get_header("Content-Encoding"));
+        assert_eq!(Some(String::from("Accept-Encoding")), res.get_header("Vary"));
+        assert_eq!(body.as_bytes(), &res.payload[..]);
+
+        let res = respond("Accept-Encoding: gzip\r\n", "image/png", &body);
+        assert_eq!(None, res.get_header("Content-Encoding"));
+        assert_eq!(None, res.get_header("Vary"));
+
+        let res = respond("Accept-Encoding: gzip\r\n", "text/html", "short");
+        assert_eq!(None, res.get_header("Content-Encoding"));
+        assert_eq!(b"short", &res.payload[..]);
+    }
+
+    #[test]
+    fn test_into_response_str() {
+        let res = "Hello, world!".into_response();
+
+        assert_eq!(200, res.status);
+        assert_eq!("text/plain", res.ctype);
+        assert_eq!(b"Hello, world!", res.payload.as_slice());
+    }
+
+    #[test]
+    fn test_into_response_status_tuple() {
+        let res = (201, String::from("created")).into_response();
+
+        assert_eq!(201, res.status);
+        assert_eq!("Created", res.cmsg);
+        assert_eq!(b"created", res.payload.as_slice());
+    }
+
+    #[test]
+    fn test_into_response_json_value() {
+        let res = serde_json::json!({ "item": 12345 }).into_response();
+
+        assert_eq!("application/json", res.ctype);
+        assert_eq!(b"{\"item\":12345}", res.payload.as_slice());
+    }
+
+    #[test]
+    fn test_into_response_vec() {
+        let res = vec![1u8, 2, 3].into_response();
+
+        assert_eq!("application/octet-stream", res.ctype);
+        assert_eq!(vec![1u8, 2, 3], res.payload);
+    }
+
+    #[test]
+    fn test_into_response_headers_tuple() {
+        let res = (202, [("X-Foo", "bar"), ("Content-Type", "text/csv")], "a,b,c").into_response();
+
+        assert_eq!(202, res.status);
+        assert_eq!("text/csv", res.ctype);
+        assert_eq!("bar", res.headers.get("X-Foo").unwrap());
+    }
+
+    #[test]
+    fn test_into_response_json() {
+        let res_j = (201, Json(Foo { item: 12345 })).into_response();
+
+        assert_eq!(201, res_j.status);
+        assert_eq!("application/json", res_j.ctype);
+        assert_eq!(b"{\"item\":12345}", res_j.payload.as_slice());
+    }
+
+    #[test]
+    fn test_into_response_html() {
+        let res = Html(String::from("<b>hi</b>")).into_response();
+
+        assert_eq!("text/html", res.ctype);
+        assert_eq!(b"<b>hi</b>", res.payload.as_slice());
+    }
+
+    #[test]
+    fn test_into_response_redirect() {
+        let res = Redirect::to("/login").into_response();
+        let perm = Redirect::permanent("/new").into_response();
+
+        assert_eq!(302, res.status);
+        assert_eq!("/login", res.headers.get("Location").unwrap());
+        assert_eq!(301, perm.status);
+    }
+
+    #[test]
+    fn test_into_response_result() {
+        let ok: Result<&str, RequestError> = Ok("fine");
+        let err: Result<&str, RequestError> = Err(RequestError::ParseError(String::from("bad")));
+
+        assert_eq!(200, ok.into_response().status);
+        assert_eq!(400, err.into_response().status);
     }
 
     #[test]
@@ -255,6 +1237,21 @@ mod tests {
         assert_eq!("OK", Response::get_http_message(200));
     }
 
+    #[test]
+    fn test_limit_status_lines() {
+        let cases = vec![
+            (414, "HTTP/1.1 414 Request-URI Too Large\r\n"),
+            (431, "HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+            (413, "HTTP/1.1 413 Request Entity Too Large\r\n"),
+        ];
+
+        for (status, line) in cases {
+            let res = utils::make_response("", "text/plain", status);
+
+            assert!(String::from_utf8(res.gen_output().unwrap()).unwrap().starts_with(line));
+        }
+    }
+
     #[test]
     fn test_tooutput_trait_static_str() {
         let ar: [u8; 3] = [97, 98, 99];
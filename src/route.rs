@@ -8,11 +8,18 @@
 
 extern crate regex;
 
+use std::sync::Arc;
+use std::pin::Pin;
+use std::future::Future;
 use std::collections::HashMap;
 use regex::Regex;
 
 use crate::request::*;
 use crate::response::*;
+use crate::error::ErrorContext;
+use crate::conditional::Validator;
+use crate::extract::HandlerFn;
+use crate::websocket::WebSocket;
 
 // The various types of parameters that can be contained in a URI.
 #[derive(PartialEq, Eq, Hash, Debug)]
@@ -32,21 +39,54 @@ pub struct RouteDef {
     pub method:  Method,
 }
 
+/// A route handler, with its arguments extracted and its return value already
+/// converted to a `Response`. An `Err` means the request was rejected before
+/// the handler ran.
+pub type RouteHandler = Arc<dyn Fn(&Request) -> Result<Response, ErrorContext> + Send + Sync>;
+
+/// An async route handler, with its return value already converted to a
+/// `Response`.
+pub type AsyncRouteHandler = Arc<dyn Fn(Request) -> Pin<Box<dyn Future<Output = Response> + Send>> + Send + Sync>;
+
+/// A WebSocket handler, run on a thread of its own once the connection has
+/// been upgraded.
+pub type WebSocketHandler = Arc<dyn Fn(&Request, WebSocket) + Send + Sync>;
+
+/// The code that runs when a route is matched: either a handler run on the
+/// threadpool, an async handler run on the async executor, or a WebSocket
+/// handler.
+#[derive(Clone)]
+pub enum Endpoint {
+    Sync(RouteHandler),
+    Async(AsyncRouteHandler),
+    WebSocket(WebSocketHandler),
+}
+
 /// This struct defines a route or endpoint.
 pub struct Route {
     matcher:     Regex,
     method:      Method,
-    params:      HashMap<String, ParamType>,
-    pub handler: fn(&Request) -> Response,
+    params:      Vec<(String, ParamType)>,
+    listeners:   Option<Vec<String>>,
+    max_body:    Option<usize>,
+    validator:   Option<Validator>,
+    pub handler: Endpoint,
 }
 
 impl Route {
-    /// Create a new Route. This function is called by the Canteen struct.
-    pub fn new(path: &str, method: Method, handler: fn(&Request) -> Response) -> Route {
+    /// Create a new Route from any function that implements `HandlerFn`.
+    pub fn new<H, Args>(path: &str, method: Method, handler: H) -> Route
+            where H: HandlerFn<Args> {
+        Route::with_handler(path, method, Endpoint::Sync(Arc::new(move |req: &Request| handler.call(req))))
+    }
+
+    /// Create a new Route from an already-wrapped handler. This function is
+    /// called by the Canteen struct.
+    pub fn with_handler(path: &str, method: Method, handler: Endpoint) -> Route {
         let re = Regex::new(r"^<(?:(int|uint|str|float|path):)?([\w_][a-zA-Z0-9_]*)>$").unwrap();
-        let parts: Vec<&str> = path.split('/').filter(|&s| s != "").collect();
+        let parts: Vec<&str> = path.split('/').filter(|&s| !s.is_empty()).collect();
         let mut matcher: String = String::from(r"^");
-        let mut params: HashMap<String, ParamType> = HashMap::new();
+        let mut params: Vec<(String, ParamType)> = Vec::new();
 
         for part in parts {
             let chunk: String = if re.is_match(part) {
@@ -75,7 +115,7 @@ impl Route {
                     ParamType::Path     => String::from(r".+"),
                 };
 
-                params.insert(String::from(param), ptype);
+                params.push((String::from(param), ptype));
 
                 format!("/(?P<{}>{})", &param, &mstr)
             } else {
@@ -92,28 +132,85 @@ impl Route {
             matcher: Regex::new(&matcher).unwrap(),
             params,
             method,
+            listeners: None,
+            max_body:  None,
+            validator: None,
             handler,
         }
     }
 
-    /// Check if this Route matches a given URI.
+    /// Check if this Route matches a given URI, and is served on the listener
+    /// the request arrived on.
     pub fn is_match(&self, req: &Request) -> bool {
-        self.matcher.is_match(&req.path) && self.method == req.method
+        self.matcher.is_match(&req.path) && self.method == req.method && self.allows(req.listener())
+    }
+
+    /// Only serve this Route on the named listeners.
+    pub fn restrict(&mut self, listeners: &[&str]) {
+        self.listeners = Some(listeners.iter().map(|name| name.to_string()).collect());
+    }
+
+    /// Accept request bodies of up to `size` bytes for this Route, rather than
+    /// the server-wide limit.
+    pub fn set_max_body_size(&mut self, size: usize) {
+        self.max_body = Some(size);
+    }
+
+    /// The body size limit specific to this Route, if it has one.
+    pub fn max_body_size(&self) -> Option<usize> {
+        self.max_body
+    }
+
+    /// Check the preconditions of requests for this Route against the
+    /// validators `validator` gives, before the handler runs.
+    pub fn set_validator(&mut self, validator: Validator) {
+        self.validator = Some(validator);
+    }
+
+    /// The validator for this Route's preconditions, if it has one.
+    pub fn validator(&self) -> Option<Validator> {
+        self.validator.clone()
+    }
+
+    /// Check if this Route is served on a listener. Unrestricted routes are
+    /// served on every listener, named or not.
+    pub fn allows(&self, listener: Option<&str>) -> bool {
+        match (&self.listeners, listener) {
+            (None, _)                   => true,
+            (Some(names), Some(name))   => names.iter().any(|n| n == name),
+            (Some(_), None)             => false,
+        }
+    }
+
+    /// Check if this Route matches a given URI, regardless of the HTTP method.
+    pub fn is_path_match(&self, path: &str) -> bool {
+        self.matcher.is_match(path)
+    }
+
+    /// The HTTP method this Route responds to.
+    pub fn method(&self) -> Method {
+        self.method
     }
 
     /// Parse and extract the variables from a URI based on this Route's definition.
     pub fn parse(&self, path: &str) -> HashMap<String, String> {
         let mut params: HashMap<String, String> = HashMap::new();
 
-        if self.matcher.is_match(&path) {
+        if self.matcher.is_match(path) {
             let caps = self.matcher.captures(path).unwrap();
-            for param in self.params.keys() {
-                params.insert(param.clone(), String::from(caps.name(&param).unwrap().as_str()));
+            for (param, _) in &self.params {
+                params.insert(param.clone(), String::from(caps.name(param).unwrap().as_str()));
             }
         }
 
         params
     }
+
+    /// The names of the variables in this Route's definition, in the order
+    /// they appear.
+    pub fn param_names(&self) -> Vec<String> {
+        self.params.iter().map(|(param, _)| param.clone()).collect()
+    }
 }
 
 #[cfg(test)]
@@ -121,6 +218,23 @@ mod tests {
     use super::*;
     use crate::utils;
 
+    #[test]
+    fn test_route_restrict() {
+        let mut rt = Route::new("/metrics", Method::Get, utils::err_404);
+        let mut req = Request::new();
+
+        req.method = Method::Get;
+        req.path = String::from("/metrics");
+        assert!(rt.is_match(&req));
+
+        rt.restrict(&["admin"]);
+        assert!(!rt.is_match(&req));
+        assert!(!rt.allows(Some("public")));
+
+        req.listener = Some(String::from("admin"));
+        assert!(rt.is_match(&req));
+    }
+
     #[test]
     fn test_route_match() {
         let rt = Route::new("/api/v1/foo/<int:foo_id>", Method::Get, utils::err_404);
@@ -142,6 +256,15 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_route_path_match() {
+        let rt = Route::new("/api/v1/foo/<int:foo_id>", Method::Post, utils::err_404);
+
+        assert!(rt.is_path_match("/api/v1/foo/123"));
+        assert!(!rt.is_path_match("/api/v1/foo/bar"));
+        assert_eq!(Method::Post, rt.method());
+    }
+
     #[test]
     fn test_route_match_simple() {
         let route = Route::new("/api/v1/foo/<foo_stuff>", Method::Get, utils::err_404);
@@ -168,7 +291,7 @@ mod tests {
         badreq.path = String::from("/api/v1/foo/-123");
 
         assert_eq!("123", parsed.get("foo_id").unwrap());
-        assert_eq!(false, route.is_match(&badreq));
+        assert!(!route.is_match(&badreq));
     }
 
     #[test]
@@ -0,0 +1,105 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::client::Notice;
+use crate::event_loop::Sender;
+
+/// Stops a running Canteen server. Obtain one with `Canteen::shutdown_handle`
+/// before calling `run`; it can be cloned and sent to other threads.
+///
+/// Once triggered, the server stops accepting connections, closes idle ones,
+/// and waits for in-flight requests to be answered before `run` returns, up to
+/// the configured `shutdown_timeout`. The flag is cleared again once `run`
+/// has returned, so the same handle can stop the server if it's run again.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::thread;
+/// use canteen::Canteen;
+///
+/// let cnt = Canteen::new();
+/// let handle = cnt.shutdown_handle();
+///
+/// thread::spawn(move || handle.shutdown());
+/// ```
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+    // wakes the event loop while the server is running
+    tx:   Arc<Mutex<Option<Sender<Notice>>>>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> ShutdownHandle {
+        ShutdownHandle::default()
+    }
+
+    /// Begin shutting down the server.
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+
+        if let Some(ref tx) = *self.tx.lock().unwrap() {
+            let _ = tx.send(Notice::Shutdown);
+        }
+    }
+
+    /// Whether the server has been told to shut down.
+    pub fn is_shutdown(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// The flag behind this handle. Setting it to `true` is safe to do from
+    /// a signal handler, e.g. with `signal_hook::flag::register(SIGTERM,
+    /// handle.flag())`, but unlike `shutdown` it doesn't wake the server: the
+    /// shutdown begins the next time it has anything else to do.
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        self.flag.clone()
+    }
+
+    // hand over the running event loop's sender, returning whether the
+    // server has already been told to shut down
+    pub(crate) fn attach(&self, tx: Sender<Notice>) -> bool {
+        *self.tx.lock().unwrap() = Some(tx);
+        self.is_shutdown()
+    }
+
+    // the event loop has stopped; ready the handle for the next run
+    pub(crate) fn detach(&self) {
+        *self.tx.lock().unwrap() = None;
+        self.flag.store(false, Ordering::SeqCst);
+    }
+}
+
+impl fmt::Debug for ShutdownHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShutdownHandle").field("flag", &self.flag).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shutdown_handle_clones_share_state() {
+        let handle = ShutdownHandle::new();
+        let other = handle.clone();
+
+        assert!(!other.is_shutdown());
+        handle.shutdown();
+        assert!(other.is_shutdown());
+
+        let flagged = ShutdownHandle::new();
+        flagged.flag().store(true, Ordering::SeqCst);
+        assert!(flagged.is_shutdown());
+    }
+}
@@ -0,0 +1,439 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+//! Server-Sent Events. A handler returns an `EventStream`, and the connection
+//! stays open after its headers have been sent; every `Event` pushed through
+//! the matching `EventSender`, from the handler or from anywhere else it has
+//! been handed to, is written to the client as it's sent. Comments are sent
+//! while the stream is quiet to keep proxies from timing it out.
+//!
+//! ```rust
+//! use std::thread;
+//! use std::time::Duration;
+//! use canteen::Request;
+//! use canteen::sse::{Event, EventStream};
+//!
+//! fn ticks(_: &Request) -> EventStream {
+//!     let (stream, sender) = EventStream::new();
+//!
+//!     thread::spawn(move || {
+//!         for n in 0.. {
+//!             if sender.send(Event::new(n.to_string()).event("tick")).is_err() {
+//!                 break;
+//!             }
+//!
+//!             thread::sleep(Duration::from_secs(1));
+//!         }
+//!     });
+//!
+//!     stream
+//! }
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use mio::Token;
+
+use crate::client::Notice;
+use crate::event_loop::Sender;
+use crate::response::{IntoResponse, Response};
+
+pub use crate::websocket::Closed;
+
+/// A single event, as sent to the client.
+///
+/// # Examples
+///
+/// ```rust
+/// use canteen::sse::Event;
+///
+/// let event = Event::new("{\"cpu\": 0.4}").event("load").id("17");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    data:  String,
+    event: Option<String>,
+    id:    Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    /// Create an event carrying `data`, which may span several lines.
+    pub fn new<T: Into<String>>(data: T) -> Event {
+        Event { data: data.into(), event: None, id: None, retry: None }
+    }
+
+    /// Set the event's type, which clients listen for by name. Events
+    /// without one are delivered as `message` events.
+    pub fn event(mut self, name: &str) -> Event {
+        self.event = Some(single_line(name));
+        self
+    }
+
+    /// Set the event's ID, which the client sends back in the
+    /// `Last-Event-ID` header when it reconnects.
+    pub fn id(mut self, id: &str) -> Event {
+        self.id = Some(single_line(id));
+        self
+    }
+
+    /// Tell the client how long to wait before reconnecting if the
+    /// connection drops.
+    pub fn retry(mut self, delay: Duration) -> Event {
+        self.retry = Some(delay);
+        self
+    }
+
+    /// The event in the `text/event-stream` format.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut out = String::new();
+
+        if let Some(ref event) = self.event {
+            out.push_str(&format!("event: {}\n", event));
+        }
+
+        if let Some(ref id) = self.id {
+            out.push_str(&format!("id: {}\n", id));
+        }
+
+        if let Some(retry) = self.retry {
+            out.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+
+        for line in self.data.split('\n') {
+            out.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+        }
+
+        out.push('\n');
+        out.into_bytes()
+    }
+}
+
+// field values can't contain line breaks, which would end the field
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+// where a stream's events go once its response has been sent
+struct Target {
+    tx:     Sender<Notice>,
+    token:  Token,
+    seq:    u64,
+    stream: u32,
+}
+
+struct Shared {
+    target:  Option<Target>,
+    // events sent before the response was, and whether the stream has ended
+    backlog: Vec<u8>,
+    ended:   bool,
+}
+
+/// A response that stays open, sending events as they arrive. It's created
+/// along with the `EventSender` that sends them; the stream ends when every
+/// sender has been dropped, and the senders fail once the client has gone.
+pub struct EventStream {
+    shared: Arc<Mutex<Shared>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl EventStream {
+    /// Create a stream, and the sender that pushes events onto it.
+    pub fn new() -> (EventStream, EventSender) {
+        let shared = Arc::new(Mutex::new(Shared { target: None, backlog: Vec::new(), ended: false }));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let stream = EventStream { shared: shared.clone(), closed: closed.clone() };
+        let sender = EventSender { inner: Arc::new(SenderInner { shared, closed }) };
+
+        (stream, sender)
+    }
+
+    /// Start delivering the stream's events to a connection, once its
+    /// headers have been queued. The connection keeps the returned guard,
+    /// which marks the stream closed when it's dropped.
+    pub(crate) fn attach(self, tx: Sender<Notice>, token: Token, seq: u64, stream: u32) -> Attached {
+        let mut shared = self.shared.lock().unwrap();
+
+        // whatever was sent before now follows the headers
+        if !shared.backlog.is_empty() || shared.ended {
+            let backlog = mem::take(&mut shared.backlog);
+            let _ = tx.send(Notice::Chunk(token, seq, stream, backlog, shared.ended));
+        }
+
+        shared.target = Some(Target { tx, token, seq, stream });
+        drop(shared);
+
+        Attached::new(self.closed.clone())
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        // a stream that was never sent has nowhere for its events to go
+        if self.shared.lock().unwrap().target.is_none() {
+            self.closed.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl fmt::Debug for EventStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("EventStream")
+    }
+}
+
+impl IntoResponse for EventStream {
+    fn into_response(self) -> Response {
+        let mut res = Response::new();
+
+        res.set_content_type("text/event-stream");
+        res.add_header("Cache-Control", "no-cache");
+        res.events = Some(self);
+
+        res
+    }
+}
+
+/// Marks an event stream closed when its connection goes away.
+pub(crate) struct Attached {
+    closed: Arc<AtomicBool>,
+}
+
+impl Attached {
+    pub(crate) fn new(closed: Arc<AtomicBool>) -> Attached {
+        Attached { closed }
+    }
+}
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+struct SenderInner {
+    shared: Arc<Mutex<Shared>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl SenderInner {
+    fn push(&self, data: Vec<u8>, end: bool) -> Result<(), Closed> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Closed);
+        }
+
+        let mut shared = self.shared.lock().unwrap();
+
+        match shared.target {
+            Some(ref t) => t.tx.send(Notice::Chunk(t.token, t.seq, t.stream, data, end)).map_err(|_| Closed),
+            None        => {
+                shared.backlog.extend(data);
+                shared.ended = end;
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Drop for SenderInner {
+    fn drop(&mut self) {
+        let _ = self.push(Vec::new(), true);
+    }
+}
+
+/// Sends events on an `EventStream`. It can be cloned and moved to other
+/// threads; the stream ends once every clone has been dropped.
+#[derive(Clone)]
+pub struct EventSender {
+    inner: Arc<SenderInner>,
+}
+
+impl EventSender {
+    /// Send an event to the client. Fails once the client has disconnected.
+    pub fn send(&self, event: Event) -> Result<(), Closed> {
+        self.inner.push(event.encode(), false)
+    }
+
+    /// Whether the client has disconnected.
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::SeqCst)
+    }
+}
+
+struct HubInner {
+    next_id:     u64,
+    history:     VecDeque<Event>,
+    capacity:    usize,
+    subscribers: Vec<EventSender>,
+}
+
+/// Publishes events to every connected client, numbering them and keeping
+/// the most recent so that a client reconnecting with `Last-Event-ID` is sent
+/// the ones it missed.
+///
+/// # Examples
+///
+/// ```rust
+/// use canteen::{header_name, Canteen, Method};
+/// use canteen::extract::{Header, State};
+/// use canteen::sse::{Event, EventHub, EventStream};
+///
+/// header_name!(LastEventId, "Last-Event-ID");
+///
+/// fn updates(State(hub): State<EventHub>, last: Option<Header<LastEventId>>) -> EventStream {
+///     hub.subscribe(last.as_deref())
+/// }
+///
+/// let hub = EventHub::new(100);
+/// let mut cnt = Canteen::new();
+///
+/// cnt.manage(hub.clone());
+/// cnt.add_route("/updates", &[Method::Get], updates);
+///
+/// hub.publish(Event::new("deployed").event("status"));
+/// ```
+#[derive(Clone)]
+pub struct EventHub {
+    inner: Arc<Mutex<HubInner>>,
+}
+
+impl EventHub {
+    /// Create a hub that remembers the last `history` events.
+    pub fn new(history: usize) -> EventHub {
+        EventHub {
+            inner: Arc::new(Mutex::new(HubInner {
+                next_id:     1,
+                history:     VecDeque::with_capacity(history),
+                capacity:    history,
+                subscribers: Vec::new(),
+            })),
+        }
+    }
+
+    /// Open a stream for a client. If it's reconnecting, pass the value of
+    /// its `Last-Event-ID` header, and the events it missed since then are
+    /// sent first; an ID the hub no longer remembers replays its whole
+    /// history.
+    pub fn subscribe(&self, last_event_id: Option<&str>) -> EventStream {
+        let (stream, sender) = EventStream::new();
+        let mut hub = self.inner.lock().unwrap();
+
+        if let Some(last) = last_event_id {
+            let last: u64 = last.trim().parse().unwrap_or(0);
+
+            for event in hub.history.iter().filter(|e| e.id.as_ref().and_then(|id| id.parse::<u64>().ok()) > Some(last)) {
+                let _ = sender.send(event.clone());
+            }
+        }
+
+        hub.subscribers.push(sender);
+        stream
+    }
+
+    /// Send an event to every subscribed client, giving it the next ID.
+    /// Returns the ID.
+    pub fn publish(&self, event: Event) -> u64 {
+        let mut hub = self.inner.lock().unwrap();
+        let id = hub.next_id;
+        let event = event.id(&id.to_string());
+
+        hub.next_id += 1;
+        hub.subscribers.retain(|sender| sender.send(event.clone()).is_ok());
+
+        if hub.capacity > 0 {
+            if hub.history.len() == hub.capacity {
+                hub.history.pop_front();
+            }
+
+            hub.history.push_back(event);
+        }
+
+        id
+    }
+
+    /// How many clients are subscribed, as of the last `publish`.
+    pub fn subscribers(&self) -> usize {
+        self.inner.lock().unwrap().subscribers.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backlog(stream: &EventStream) -> String {
+        String::from_utf8(stream.shared.lock().unwrap().backlog.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_event_encode() {
+        let event = Event::new("one\ntwo\r\n").event("up\ndate").id("7").retry(Duration::from_secs(3));
+
+        assert_eq!("event: up date\nid: 7\nretry: 3000\ndata: one\ndata: two\ndata: \n\n",
+                   String::from_utf8(event.encode()).unwrap());
+        assert_eq!("data: hi\n\n", String::from_utf8(Event::new("hi").encode()).unwrap());
+    }
+
+    #[test]
+    fn test_sender_backlog_and_close() {
+        let (stream, sender) = EventStream::new();
+        let other = sender.clone();
+
+        sender.send(Event::new("early")).unwrap();
+        drop(sender);
+        assert!(!stream.shared.lock().unwrap().ended);
+
+        drop(other);
+        assert!(stream.shared.lock().unwrap().ended);
+        assert_eq!("data: early\n\n", backlog(&stream));
+
+        let (stream, sender) = EventStream::new();
+        stream.closed.store(true, Ordering::SeqCst);
+
+        assert!(sender.is_closed());
+        assert_eq!(Err(Closed), sender.send(Event::new("late")));
+    }
+
+    #[test]
+    fn test_stream_headers() {
+        let (stream, _sender) = EventStream::new();
+        let res = stream.into_response();
+        let head = String::from_utf8(res.gen_output().unwrap()).unwrap();
+
+        // the body is open-ended, so it has no length
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: text/event-stream\r\n"));
+        assert!(head.contains("Cache-Control: no-cache\r\n"));
+        assert!(!head.contains("Content-Length"));
+        assert!(head.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_hub_replay() {
+        let hub = EventHub::new(2);
+
+        for n in 0..3 {
+            hub.publish(Event::new(n.to_string()));
+        }
+
+        let fresh = hub.subscribe(None);
+        let back = hub.subscribe(Some("2"));
+
+        assert_eq!("", backlog(&fresh));
+        assert_eq!("id: 3\ndata: 2\n\n", backlog(&back));
+
+        assert_eq!(4, hub.publish(Event::new("new")));
+        assert!(backlog(&fresh).ends_with("id: 4\ndata: new\n\n"));
+        assert_eq!(2, hub.subscribers());
+    }
+}
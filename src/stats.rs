@@ -0,0 +1,102 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Default)]
+struct Counters {
+    accepted: AtomicUsize,
+    refused:  AtomicUsize,
+    shed:     AtomicUsize,
+    active:   AtomicUsize,
+}
+
+/// Connection counters for a Canteen server. Obtain one with `Canteen::stats`;
+/// it can be cloned and read from any thread while the server runs.
+///
+/// # Examples
+///
+/// ```rust
+/// use canteen::Canteen;
+///
+/// let cnt = Canteen::new();
+/// let stats = cnt.stats();
+///
+/// assert_eq!(0, stats.refused());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ServerStats {
+    counters: Arc<Counters>,
+}
+
+impl ServerStats {
+    pub(crate) fn new() -> ServerStats {
+        ServerStats::default()
+    }
+
+    /// The number of connections accepted and handed a client slot.
+    pub fn accepted(&self) -> usize {
+        self.counters.accepted.load(Ordering::Relaxed)
+    }
+
+    /// The number of connections turned away with a 503 because the server,
+    /// or the peer's IP address, was already at its connection limit.
+    pub fn refused(&self) -> usize {
+        self.counters.refused.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests answered with a 503 because the worker queue
+    /// was full.
+    pub fn shed(&self) -> usize {
+        self.counters.shed.load(Ordering::Relaxed)
+    }
+
+    /// The number of connections currently open.
+    pub fn active(&self) -> usize {
+        self.counters.active.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn on_accept(&self) {
+        self.counters.accepted.fetch_add(1, Ordering::Relaxed);
+        self.counters.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_refuse(&self) {
+        self.counters.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_shed(&self) {
+        self.counters.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_close(&self) {
+        self.counters.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_counters() {
+        let stats = ServerStats::new();
+        let other = stats.clone();
+
+        stats.on_accept();
+        stats.on_accept();
+        stats.on_close();
+        stats.on_refuse();
+        stats.on_shed();
+
+        assert_eq!(2, other.accepted());
+        assert_eq!(1, other.shed());
+        assert_eq!(1, other.active());
+        assert_eq!(1, other.refused());
+    }
+}
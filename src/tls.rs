@@ -0,0 +1,322 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+//! HTTPS support, enabled with the `tls` feature. Certificates and keys are
+//! loaded from PEM files; several can be served from one listener, chosen by
+//! the hostname the client asks for (SNI).
+//!
+//! ```rust,no_run
+//! use canteen::Canteen;
+//! use canteen::tls::TlsConfig;
+//!
+//! let tls = TlsConfig::from_pem_files("certs/default.pem", "certs/default.key").unwrap()
+//!     .add_pem_files("api.example.com", "certs/api.pem", "certs/api.key").unwrap();
+//!
+//! let mut cnt = Canteen::new();
+//! cnt.bind_tls(("0.0.0.0", 8443), &tls).unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, IoSlice};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection};
+use rustls_pemfile::Item;
+
+/// The certificates a TLS listener presents to clients.
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    names:   HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl TlsConfig {
+    /// Create a configuration with no certificates. Add some with
+    /// `add_pem_files` before binding with it.
+    pub fn new() -> TlsConfig {
+        TlsConfig::default()
+    }
+
+    /// Create a configuration presenting one certificate to every client.
+    /// `cert` holds the certificate chain, leaf first, and `key` its
+    /// private key, in PKCS#8, PKCS#1 or SEC1 form.
+    pub fn from_pem_files<P: AsRef<Path>>(cert: P, key: P) -> io::Result<TlsConfig> {
+        Ok(TlsConfig {
+            names:   HashMap::new(),
+            default: Some(load_certified_key(cert.as_ref(), key.as_ref())?),
+        })
+    }
+
+    /// Present a certificate to clients asking for `hostname`. Clients asking
+    /// for other names, or none, get the default certificate if there is one.
+    pub fn add_pem_files<P: AsRef<Path>>(mut self, hostname: &str, cert: P, key: P) -> io::Result<TlsConfig> {
+        let certified = load_certified_key(cert.as_ref(), key.as_ref())?;
+
+        self.names.insert(hostname.to_lowercase(), certified);
+        Ok(self)
+    }
+
+    pub(crate) fn server_config(&self) -> io::Result<Arc<ServerConfig>> {
+        if self.names.is_empty() && self.default.is_none() {
+            return Err(invalid("no certificates configured"));
+        }
+
+        let resolver = CertResolver {
+            names:   self.names.clone(),
+            default: self.default.clone(),
+        };
+
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+
+        if cfg!(feature = "http2") {
+            // offer HTTP/2, falling back to HTTP/1.1 for clients without it
+            config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        }
+
+        Ok(Arc::new(config))
+    }
+}
+
+fn invalid<T: Into<String>>(message: T) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> io::Result<Arc<CertifiedKey>> {
+    let mut reader = BufReader::new(File::open(cert)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+                                                 .into_iter()
+                                                 .map(Certificate)
+                                                 .collect();
+
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates found in {}", cert.display())));
+    }
+
+    let mut reader = BufReader::new(File::open(key)?);
+    let mut private = None;
+
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => {
+                private = Some(PrivateKey(der));
+                break;
+            },
+            _ => continue,
+        }
+    }
+
+    let private = private.ok_or_else(|| invalid(format!("no private key found in {}", key.display())))?;
+    let signer = sign::any_supported_type(&private)
+                     .map_err(|_| invalid(format!("unsupported private key type in {}", key.display())))?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, signer)))
+}
+
+// picks a certificate by the hostname the client sent, if any.
+struct CertResolver {
+    names:   HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        hello.server_name()
+             .and_then(|name| self.names.get(&name.to_lowercase()))
+             .or(self.default.as_ref())
+             .cloned()
+    }
+}
+
+/// A server-side TLS session over a socket, read and written like the socket
+/// itself. It works with non-blocking sockets: reads and writes return
+/// `WouldBlock` when the socket does, and encrypted output that couldn't be
+/// written yet is kept until `flush` is called again.
+pub(crate) struct TlsStream<S> {
+    sock: S,
+    conn: ServerConnection,
+}
+
+impl<S: Read + Write> TlsStream<S> {
+    pub fn new(sock: S, config: Arc<ServerConfig>) -> io::Result<TlsStream<S>> {
+        let mut conn = ServerConnection::new(config).map_err(io::Error::other)?;
+
+        // responses are handed over whole; let rustls hold all of one
+        conn.set_buffer_limit(None);
+
+        Ok(TlsStream { sock, conn })
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.sock
+    }
+
+    /// The protocol agreed on with the client through ALPN, once the
+    /// handshake has finished.
+    #[cfg(feature = "http2")]
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.conn.alpn_protocol()
+    }
+
+    /// Whether there is encrypted output waiting to be written.
+    pub fn wants_write(&self) -> bool {
+        self.conn.wants_write()
+    }
+
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            if self.conn.write_tls(&mut self.sock)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed"));
+            }
+        }
+
+        Ok(())
+    }
+
+    // like write_tls, but leaves anything the socket won't take yet for later
+    fn try_write_tls(&mut self) -> io::Result<()> {
+        match self.write_tls() {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res                                                     => res,
+        }
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Ok(len)                                                 => return Ok(len),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {},
+                Err(err)                                                => return Err(err),
+            }
+
+            if self.conn.read_tls(&mut self.sock)? == 0 {
+                return Ok(0);
+            }
+
+            if let Err(err) = self.conn.process_new_packets() {
+                // let the client know why, if we can
+                let _ = self.try_write_tls();
+                return Err(invalid(err.to_string()));
+            }
+
+            // handshake messages go out as soon as they're ready
+            self.try_write_tls()?;
+        }
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.conn.writer().write(buf)?;
+
+        self.try_write_tls()?;
+        Ok(len)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let len = self.conn.writer().write_vectored(bufs)?;
+
+        self.try_write_tls()?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_tls()?;
+        self.sock.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::env;
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // writes a fresh self-signed certificate for `names` to the temp dir
+    fn self_signed(tag: &str, names: &[&str]) -> (Certificate, String, String) {
+        let cert = rcgen::generate_simple_self_signed(names.iter().map(|n| n.to_string()).collect::<Vec<_>>()).unwrap();
+        let dir = env::temp_dir();
+        let cert_path = dir.join(format!("canteen-test-{}-{}.pem", tag, std::process::id()));
+        let key_path = dir.join(format!("canteen-test-{}-{}.key", tag, std::process::id()));
+
+        fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        (Certificate(cert.serialize_der().unwrap()),
+         cert_path.to_string_lossy().into_owned(),
+         key_path.to_string_lossy().into_owned())
+    }
+
+    fn client_for(cert: &Certificate, name: &str, sock: TcpStream) -> rustls::StreamOwned<rustls::ClientConnection, TcpStream> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let conn = rustls::ClientConnection::new(Arc::new(config), rustls::ServerName::try_from(name).unwrap()).unwrap();
+
+        rustls::StreamOwned::new(conn, sock)
+    }
+
+    #[test]
+    fn test_tls_round_trip_with_sni() {
+        let (default_der, default_cert, default_key) = self_signed("default", &["localhost"]);
+        let (api_der, api_cert, api_key) = self_signed("api", &["api.test"]);
+
+        let config = TlsConfig::from_pem_files(&default_cert, &default_key).unwrap()
+            .add_pem_files("API.test", &api_cert, &api_key).unwrap()
+            .server_config().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                let (sock, _) = listener.accept().unwrap();
+                let mut tls = TlsStream::new(sock, config.clone()).unwrap();
+                let mut buf = [0u8; 4];
+
+                tls.read_exact(&mut buf).unwrap();
+                assert_eq!(b"ping", &buf);
+                tls.write_all(b"pong").unwrap();
+                tls.flush().unwrap();
+            }
+        });
+
+        for &(der, name) in &[(&default_der, "localhost"), (&api_der, "api.test")] {
+            let mut client = client_for(der, name, TcpStream::connect(addr).unwrap());
+            let mut buf = [0u8; 4];
+
+            client.write_all(b"ping").unwrap();
+            client.read_exact(&mut buf).unwrap();
+            assert_eq!(b"pong", &buf);
+        }
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_tls_config_errors() {
+        assert!(TlsConfig::new().server_config().is_err());
+        assert!(TlsConfig::from_pem_files("/nonexistent.pem", "/nonexistent.key").is_err());
+    }
+}
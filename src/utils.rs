@@ -6,14 +6,17 @@
 // terms
 
 use std::env;
-use std::fs::File;
-use std::path::PathBuf;
-use std::io::prelude::*;
-use chrono::{Utc, DateTime, TimeZone};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use chrono::{Utc, DateTime, NaiveDateTime, TimeZone};
 use mime_guess::MimeGuess;
 use std::time::{UNIX_EPOCH, SystemTime};
+use crate::compress::{self, Encoding};
+use crate::conditional::ETag;
+use crate::fileserver::StaticOptions;
 use crate::response::{ToOutput, Response};
 use crate::request::Request;
+use crate::error::ErrorContext;
 
 /// Convenience method for creating a response from the basic components
 /// required (a request body, content type, and response status).
@@ -38,6 +41,47 @@ pub fn make_response<T: ToOutput>(body: T, c_type: &str, status: u16) -> Respons
     res
 }
 
+/// Formats a date as an HTTP date, such as `Sun, 06 Nov 1994 08:49:37 GMT`,
+/// for headers like `Last-Modified`.
+///
+/// # Examples
+///
+/// ```rust
+/// use chrono::{TimeZone, Utc};
+/// use canteen::utils;
+///
+/// let when = Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap();
+///
+/// assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", utils::http_date(when));
+/// ```
+pub fn http_date(when: DateTime<Utc>) -> String {
+    when.format(HTTP_DATE).to_string()
+}
+
+/// Parses an HTTP date from a header like `If-Modified-Since`. As well as the
+/// usual format, the obsolete RFC 850 and asctime formats are understood.
+///
+/// # Examples
+///
+/// ```rust
+/// use canteen::utils;
+///
+/// let when = utils::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT");
+///
+/// assert_eq!(when, utils::parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"));
+/// assert_eq!(when, utils::parse_http_date("Sun Nov  6 08:49:37 1994"));
+/// assert!(utils::parse_http_date("yesterday").is_none());
+/// ```
+pub fn parse_http_date(date: &str) -> Option<DateTime<Utc>> {
+    let date = date.trim();
+
+    [HTTP_DATE, "%A, %d-%b-%y %H:%M:%S GMT", "%a %b %e %H:%M:%S %Y"].iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(date, fmt).ok())
+        .map(|dt| Utc.from_utc_datetime(&dt))
+}
+
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
 /// Converts std::time::SystemTime to chrono::DateTime<Utc>
 ///
 /// Code from: https://users.rust-lang.org/t/convert-std-time-systemtime-to-chrono-datetime-datetime/7684/4
@@ -56,7 +100,7 @@ pub fn _conv_systemtime(t: SystemTime) -> DateTime<Utc> {
         },
     };
 
-    Utc.timestamp(sec, nsec)
+    Utc.timestamp_opt(sec, nsec).unwrap()
 }
 
 /// Replace the URI escape codes with their ASCII equivalents.
@@ -111,10 +155,29 @@ pub fn replace_escape(path: &str) -> String {
     fixed
 }
 
+// make text safe to include in an HTML page
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&'     => escaped.push_str("&amp;"),
+            '<'     => escaped.push_str("&lt;"),
+            '>'     => escaped.push_str("&gt;"),
+            '"'     => escaped.push_str("&quot;"),
+            '\''    => escaped.push_str("&#39;"),
+            _       => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
 fn err_body(message: &str, path: &str) -> String {
     format!("<html><head>\
              <style>body {{ font-family: helvetica, sans-serif; }} p {{ font-size: 14 }}</style>\
-             </head><body><h3>Your request failed</h3><p>{}: {}</p></body></html>", message, path)
+             </head><body><h3>Your request failed</h3><p>{}: {}</p></body></html>",
+            escape_html(message), escape_html(path))
 }
 
 /// Default handler function for HTTP 403 errors.
@@ -147,73 +210,171 @@ pub fn err_500_json(message: &str) -> Response {
     make_response(format!("{{ message: 'internal server error: {}' }}", message), "application/json", 500)
 }
 
+/// Returns true if the client's `Accept` header prefers JSON over HTML.
+fn wants_json(req: &Request) -> bool {
+    let accept = match req.get_header("Accept") {
+        Some(accept) => accept,
+        None         => return false,
+    };
+
+    let mut json_q: Option<f32> = None;
+    let mut html_q: Option<f32> = None;
+
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let mtype = parts.next().unwrap_or("").trim().to_lowercase();
+        let q = parts.filter_map(|p| p.trim().strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()))
+                     .next()
+                     .unwrap_or(1.0);
+
+        match mtype.as_str() {
+            "application/json" => json_q = Some(json_q.map_or(q, |cur| cur.max(q))),
+            "text/html"        => html_q = Some(html_q.map_or(q, |cur| cur.max(q))),
+            _                  => {},
+        }
+    }
+
+    match (json_q, html_q) {
+        (Some(j), Some(h)) => j > h,
+        (Some(j), None)    => j > 0.0,
+        _                  => false,
+    }
+}
+
+/// Default handler function for errors, used for any status that doesn't have
+/// a handler registered with `Canteen::error_handler`. Clients that prefer JSON
+/// (based on their `Accept` header) are sent a JSON body, otherwise HTML.
+pub fn err_default(req: &Request, err: &ErrorContext) -> Response {
+    if wants_json(req) {
+        let body = serde_json::json!({
+            "status":  err.status,
+            "message": err.message,
+            "path":    req.path,
+        });
+
+        make_response(body.to_string(), "application/json", err.status)
+    } else {
+        make_response(err_body(&err.message, &req.path), "text/html", err.status)
+    }
+}
+
 /// Handler that sends static files relative to the current working directory.
+/// The file isn't read by the handler: it's sent from disk as the client
+/// takes it, or its precompressed `.br` or `.gz` copy is, if it has one that
+/// the client accepts. See `Canteen::mount_static` for serving a particular
+/// directory.
 pub fn static_file(req: &Request) -> Response {
-    let mut res = Response::new();
-
     let cwd = env::current_dir().unwrap();
     let clean = replace_escape(&req.path);
     let mut fpath = PathBuf::from(&cwd);
-    let mut fbuf: Vec<u8> = Vec::new();
 
     for chunk in clean.split('/') {
-        if chunk == "" || chunk == "." || chunk == ".." {
+        if chunk.is_empty() || chunk == "." || chunk == ".." {
             /* bzzzzt */
             continue;
         }
 
-        fpath.push(&chunk);
+        fpath.push(chunk);
     }
 
-    let file = File::open(&fpath);
+    send_file(req, &fpath, &StaticOptions::new().precompressed(true))
+}
+
+// respond with the file at `fpath`, tagged with its size and modification
+// time, so that conditional requests for it can be answered. anything other
+// than a regular file is a 404.
+pub(crate) fn send_file(req: &Request, fpath: &Path, options: &StaticOptions) -> Response {
+    let mut res = Response::new();
+
+    let (sent, encoding) = if options.precompressed {
+        precompressed(req, fpath, &mut res)
+    } else {
+        (fpath.to_path_buf(), None)
+    };
+
+    let file = File::open(&sent);
 
     match file {
-        Ok(mut f)   => {
-            let last = match f.metadata() {
-                Err(_)  => Utc::now(),
-                Ok(md)  => {
-                    match md.modified() {
-                        Err(_)  => Utc::now(), // should never happen...
-                        Ok(st)  => _conv_systemtime(st),
-                    }
-                }
+        Ok(f)       => {
+            let meta = match f.metadata() {
+                Ok(md) if md.is_file()  => md,
+                Ok(_)                   => return err_404(req),
+                Err(_)                  => return err_500(req),
             };
 
-            if let Some(hdr) = req.get_header("If-Modified-Since") {
-                if let Ok(dt_utc) = Utc.datetime_from_str(&hdr, "%a, %d %b %Y, %H:%M:%S UTC") {
-                    if dt_utc >= last {
-                        // it hasn't been modified, return a 304
-                        res.set_status(304);
-                        return res;
-                    }
-                }
-            }
+            let last = match meta.modified() {
+                Err(_)  => Utc::now(), // should never happen...
+                Ok(st)  => _conv_systemtime(st),
+            };
+
+            let tag = format!("{:x}-{:x}", meta.len(), last.timestamp_nanos_opt().unwrap_or_default());
 
-            match f.read_to_end(&mut fbuf) {
-                Ok(_)   => {
-                    res.add_header("Last-Modified", &last.format("%a, %d %b %Y, %H:%M:%S %Z").to_string());
+            match res.set_file(f) {
+                Ok(())  => {
+                    res.set_last_modified(last);
+                    res.set_etag(&if options.weak { ETag::weak(&tag) } else { ETag::strong(&tag) });
                     res.set_status(200);
 
-                    match MimeGuess::from_path(&fpath).first_raw() {
+                    if let Some(encoding) = encoding {
+                        res.add_header("Content-Encoding", encoding.name());
+                    }
+
+                    match MimeGuess::from_path(fpath).first_raw() {
                         Some(ftype) => res.set_content_type(ftype),
                         None        => res.set_content_type("text/plain"),
                     };
-
-                    res.append(fbuf);
                 },
                 Err(_)  => {
-                    return err_500(&req);
+                    return err_500(req);
                 },
             }
         },
         Err(_)      => {
-            return err_404(&req);
+            return err_404(req);
         }
     }
 
     res
 }
 
+// the path to send for `fpath`, which is a compressed copy of it if there's
+// one that the client accepts, and the copy's encoding
+fn precompressed(req: &Request, fpath: &Path, res: &mut Response) -> (PathBuf, Option<Encoding>) {
+    let mut copies = Vec::new();
+
+    if fpath.is_file() {
+        for &(encoding, ext) in &[(Encoding::Brotli, "br"), (Encoding::Gzip, "gz")] {
+            let mut copy = fpath.as_os_str().to_owned();
+
+            copy.push(".");
+            copy.push(ext);
+
+            let copy = PathBuf::from(copy);
+
+            if fs::symlink_metadata(&copy).map(|meta| meta.is_file()).unwrap_or(false) {
+                copies.push((encoding, copy));
+            }
+        }
+    }
+
+    if copies.is_empty() {
+        return (fpath.to_path_buf(), None);
+    }
+
+    // whether a copy is sent depends on the request
+    res.add_vary("Accept-Encoding");
+
+    let available: Vec<Encoding> = copies.iter().map(|&(encoding, _)| encoding).collect();
+
+    match compress::negotiate(req.get_header("Accept-Encoding").as_deref(), &available) {
+        Some(chosen)    => copies.into_iter()
+                                 .find(|&(encoding, _)| encoding == chosen)
+                                 .map(|(encoding, copy)| (copy, Some(encoding)))
+                                 .unwrap(),
+        None            => (fpath.to_path_buf(), None),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -223,11 +384,70 @@ mod tests {
     #[test]
     fn test_replace_escape() {
         let path = "%61%62%63%64%65%66%67%68%69%6A%6B%6C%6D%6E%6F%70%71%72%73%74%75%76%77%78%79%7A";
-        assert_eq!("abcdefghijklmnopqrstuvwxyz", replace_escape(&path));
+        assert_eq!("abcdefghijklmnopqrstuvwxyz", replace_escape(path));
+    }
+
+    #[test]
+    fn test_err_default_negotiation() {
+        use std::str::FromStr;
+
+        let ctx = ErrorContext::new(405, "method not allowed");
+        let cases = vec![
+            ("",                                            "text/html"),
+            ("Accept: text/html\r\n",                      "text/html"),
+            ("Accept: application/json\r\n",               "application/json"),
+            ("Accept: text/html;q=0.5, application/json\r\n", "application/json"),
+            ("Accept: application/json;q=0.5, text/html\r\n", "text/html"),
+        ];
+
+        for (hdr, ctype) in cases.into_iter() {
+            let req = Request::from_str(&format!("GET /foo HTTP/1.1\r\n{}\r\n", hdr)).unwrap();
+            let output = String::from_utf8(err_default(&req, &ctx).gen_output().unwrap()).unwrap();
+
+            assert!(output.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+            assert!(output.contains(&format!("Content-Type: {}\r\n", ctype)));
+        }
+    }
+
+    #[test]
+    fn test_err_default_escapes_html() {
+        use std::str::FromStr;
+
+        let ctx = ErrorContext::new(400, "bad <b>request</b>");
+        let req = Request::from_str("GET /<script>alert('hi')</script> HTTP/1.1\r\n\r\n").unwrap();
+        let output = String::from_utf8(err_default(&req, &ctx).gen_output().unwrap()).unwrap();
+
+        assert!(output.contains("bad &lt;b&gt;request&lt;/b&gt;: /&lt;script&gt;alert(&#39;hi&#39;)&lt;/script&gt;"));
+        assert!(!output.contains("<script>"));
+    }
+
+    #[test]
+    fn test_static_file() {
+        use std::str::FromStr;
+
+        let req = Request::from_str("GET /Cargo.toml HTTP/1.1\r\n\r\n").unwrap();
+        let output = static_file(&req).gen_output().unwrap();
+        let manifest = std::fs::read("Cargo.toml").unwrap();
+
+        assert!(String::from_utf8_lossy(&output).contains(&format!("Content-Length: {}\r\n", manifest.len())));
+        assert!(output.ends_with(&manifest));
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        let when = Utc.with_ymd_and_hms(1994, 11, 6, 8, 49, 37).unwrap();
+
+        assert_eq!(Some(when), parse_http_date(&http_date(when)));
+        assert_eq!(Some(when), parse_http_date(" Sun, 06 Nov 1994 08:49:37 GMT "));
+        assert_eq!(Some(when), parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"));
+        assert_eq!(Some(when), parse_http_date("Sun Nov  6 08:49:37 1994"));
+        assert_eq!(None, parse_http_date("Sun, 06 Nov 1994, 08:49:37 UTC"));
+        assert_eq!(None, parse_http_date("Sun, 06 Nov 1994 08:49:37 EST"));
+        assert_eq!(None, parse_http_date(""));
     }
 
     #[test]
     fn test_conv_systemtime() {
-        assert_eq!(_conv_systemtime(UNIX_EPOCH), Utc.timestamp(0, 0));
+        assert_eq!(_conv_systemtime(UNIX_EPOCH), Utc.timestamp_opt(0, 0).unwrap());
     }
 }
@@ -0,0 +1,596 @@
+// Copyright (c) 2016
+// Jeff Nettleton
+//
+// Licensed under the MIT license (http://opensource.org/licenses/MIT). This
+// file may not be copied, modified, or distributed except according to those
+// terms
+
+//! WebSocket (RFC 6455) connections. A route added with
+//! `Canteen::add_websocket` completes the opening handshake itself, then runs
+//! its handler on a thread of its own, with a `WebSocket` for exchanging
+//! messages with the client. Pings, fragmented messages and the closing
+//! handshake are dealt with on the event loop.
+//!
+//! ```rust,no_run
+//! use canteen::{Canteen, Request};
+//! use canteen::websocket::WebSocket;
+//!
+//! fn echo(_: &Request, ws: WebSocket) {
+//!     while let Some(msg) = ws.recv() {
+//!         if ws.send(msg).is_err() {
+//!             break;
+//!         }
+//!     }
+//! }
+//!
+//! let mut cnt = Canteen::new();
+//! cnt.add_websocket("/echo", echo);
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use mio::Token;
+
+use crate::client::Notice;
+use crate::event_loop::Sender;
+use crate::request::{Method, Request};
+
+// appended to the client's key to make the accept token
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// opcodes
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// close codes
+const NORMAL: u16 = 1000;
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_DATA: u16 = 1007;
+const TOO_BIG: u16 = 1009;
+const INTERNAL_ERROR: u16 = 1011;
+
+/// A message received from or sent to a WebSocket client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl<'a> From<&'a str> for Message {
+    fn from(text: &'a str) -> Message {
+        Message::Text(String::from(text))
+    }
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Message {
+        Message::Text(text)
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Message {
+        Message::Binary(data)
+    }
+}
+
+/// The error returned when sending on a WebSocket that has been closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the websocket has been closed")
+    }
+}
+
+impl Error for Closed {}
+
+/// The handler's end of a WebSocket connection. Dropping it closes the
+/// connection, if the client hasn't already.
+pub struct WebSocket {
+    token:  Token,
+    seq:    u64,
+    tx:     Sender<Notice>,
+    rx:     mpsc::Receiver<Message>,
+    closed: Arc<AtomicBool>,
+}
+
+impl WebSocket {
+    /// Wait for the next message from the client. Returns `None` once the
+    /// connection has closed.
+    pub fn recv(&self) -> Option<Message> {
+        self.rx.recv().ok()
+    }
+
+    /// Take the next message from the client if one has arrived, without
+    /// waiting.
+    pub fn try_recv(&self) -> Option<Message> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Send a message to the client.
+    pub fn send<M: Into<Message>>(&self, msg: M) -> Result<(), Closed> {
+        if self.is_closed() {
+            return Err(Closed);
+        }
+
+        let frame = match msg.into() {
+            Message::Text(text)   => encode(TEXT, text.as_bytes()),
+            Message::Binary(data) => encode(BINARY, &data),
+        };
+
+        self.tx.send(Notice::Frame(self.token, self.seq, frame, false)).map_err(|_| Closed)
+    }
+
+    /// Whether the connection has closed, from either end.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Close the connection with a status code and reason, as defined in
+    /// section 7.4 of RFC 6455.
+    pub fn close(self, code: u16, reason: &str) {
+        self.send_close(code, reason);
+    }
+
+    fn send_close(&self, code: u16, reason: &str) {
+        if !self.closed.swap(true, Ordering::SeqCst) {
+            let _ = self.tx.send(Notice::Frame(self.token, self.seq, close_frame(code, reason), true));
+        }
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.send_close(INTERNAL_ERROR, "");
+        } else {
+            self.send_close(NORMAL, "");
+        }
+    }
+}
+
+/// Check that a request asks for a WebSocket upgrade, returning the response
+/// that completes the opening handshake.
+pub(crate) fn handshake(req: &Request) -> Result<Vec<u8>, &'static str> {
+    let has_token = |header: &str, token: &str| {
+        req.get_header(header)
+           .map(|value| value.split(',').any(|v| v.trim().eq_ignore_ascii_case(token)))
+           .unwrap_or(false)
+    };
+
+    if req.method != Method::Get || req.version != "HTTP/1.1" {
+        return Err("websocket upgrades need an HTTP/1.1 GET request");
+    }
+
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err("expected a websocket upgrade request");
+    }
+
+    if req.get_header("Sec-WebSocket-Version").as_deref() != Some("13") {
+        return Err("unsupported websocket version");
+    }
+
+    let key = req.get_header("Sec-WebSocket-Key").unwrap_or_default();
+
+    match BASE64.decode(key.trim()) {
+        Ok(ref nonce) if nonce.len() == 16 => {},
+        _                                  => return Err("invalid Sec-WebSocket-Key"),
+    }
+
+    let mut sha = sha1_smol::Sha1::new();
+
+    sha.update(key.trim().as_bytes());
+    sha.update(GUID.as_bytes());
+
+    Ok(format!("HTTP/1.1 101 Switching Protocols\r\n\
+                Upgrade: websocket\r\n\
+                Connection: Upgrade\r\n\
+                Sec-WebSocket-Accept: {}\r\n\r\n", BASE64.encode(sha.digest().bytes())).into_bytes())
+}
+
+/// Create both ends of a connection that has just been upgraded: the one
+/// for its handler, and the one for the event loop. Messages over
+/// `max_message` bytes close the connection.
+pub(crate) fn pair(token: Token, seq: u64, tx: Sender<Notice>, max_message: usize) -> (WebSocket, Session) {
+    let (inbound, rx) = mpsc::channel();
+    let closed = Arc::new(AtomicBool::new(false));
+
+    let ws = WebSocket { token, seq, tx, rx, closed: closed.clone() };
+    let session = Session {
+        inbound:     Some(inbound),
+        closed,
+        message:     None,
+        max_message,
+        close_sent:  false,
+        done:        false,
+        output:      Vec::new(),
+    };
+
+    (ws, session)
+}
+
+/// The event loop's end of a WebSocket connection: it decodes the client's
+/// frames, passes messages on to the handler and answers control frames.
+pub(crate) struct Session {
+    inbound:     Option<mpsc::Sender<Message>>,
+    closed:      Arc<AtomicBool>,
+    // the opcode and data so far of a fragmented message
+    message:     Option<(u8, Vec<u8>)>,
+    max_message: usize,
+    close_sent:  bool,
+    done:        bool,
+    /// Frames waiting to be written to the socket.
+    pub output:  Vec<u8>,
+}
+
+impl Session {
+    /// Whether the connection should be closed once `output` is written.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Queue a close frame, if one hasn't been sent, and stop passing on
+    /// messages.
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if !self.close_sent {
+            self.output.extend(close_frame(code, reason));
+        }
+
+        self.sent_close();
+    }
+
+    /// The handler has sent a close frame of its own.
+    pub fn sent_close(&mut self) {
+        self.close_sent = true;
+        self.done = true;
+        self.inbound = None;
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    /// Consume every complete frame at the start of `buf`.
+    pub fn receive(&mut self, buf: &mut Vec<u8>) {
+        let mut pos = 0;
+
+        while !self.done {
+            // what's left of the limit for a fragmented message's next frame
+            let limit = self.max_message - self.message.as_ref().map_or(0, |m| m.1.len());
+
+            let (fin, opcode, payload, len) = match parse(&buf[pos..], limit) {
+                Ok(Some(frame)) => frame,
+                Ok(None)        => break,
+                Err(code)       => {
+                    self.close(code, "");
+                    break;
+                },
+            };
+
+            pos += len;
+
+            match opcode {
+                TEXT | BINARY if self.message.is_none() => {
+                    if fin {
+                        self.deliver(opcode, payload);
+                    } else {
+                        self.message = Some((opcode, payload));
+                    }
+                },
+                CONTINUATION if self.message.is_some()  => {
+                    self.message.as_mut().unwrap().1.extend(payload);
+
+                    if fin {
+                        let (opcode, data) = self.message.take().unwrap();
+                        self.deliver(opcode, data);
+                    }
+                },
+                PING                                    => self.output.extend(encode(PONG, &payload)),
+                PONG                                    => {},
+                CLOSE                                   => {
+                    // echo the client's status code back, as the closing
+                    // handshake requires, unless it's one that mustn't be
+                    // sent
+                    match payload.len() {
+                        0 => {
+                            if !self.close_sent {
+                                self.output.extend(encode(CLOSE, &[]));
+                            }
+
+                            self.sent_close();
+                        },
+                        1 => self.close(PROTOCOL_ERROR, ""),
+                        _ => match u16::from_be_bytes([payload[0], payload[1]]) {
+                            code if is_valid_close(code)    => self.close(code, ""),
+                            _                               => self.close(PROTOCOL_ERROR, ""),
+                        },
+                    }
+                },
+                _                                       => self.close(PROTOCOL_ERROR, ""),
+            }
+        }
+
+        if self.done {
+            buf.clear();
+        } else {
+            buf.drain(..pos);
+        }
+    }
+
+    fn deliver(&mut self, opcode: u8, data: Vec<u8>) {
+        let msg = match opcode {
+            TEXT => match String::from_utf8(data) {
+                Ok(text) => Message::Text(text),
+                Err(_)   => return self.close(INVALID_DATA, ""),
+            },
+            _    => Message::Binary(data),
+        };
+
+        if let Some(ref inbound) = self.inbound {
+            let _ = inbound.send(msg);
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+// a frame's final flag, opcode, unmasked payload and length on the wire
+type Frame = (bool, u8, Vec<u8>, usize);
+
+// decode the client frame at the start of `buf`, returning whether it's the
+// final fragment, its opcode, its unmasked payload and its length on the
+// wire. Err holds the close code for a malformed frame, or for a data frame
+// longer than `limit`, which is refused as soon as its length is known.
+fn parse(buf: &[u8], limit: usize) -> Result<Option<Frame>, u16> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0f;
+
+    if buf[0] & 0x70 != 0 || buf[1] & 0x80 == 0 {
+        // no extensions have been agreed, and clients must mask their frames
+        return Err(PROTOCOL_ERROR);
+    }
+
+    let (len, mut pos) = match buf[1] & 0x7f {
+        126 => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+
+            (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4)
+        },
+        127 => {
+            if buf.len() < 10 {
+                return Ok(None);
+            }
+
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(len), 10)
+        },
+        len => (len as u64, 2),
+    };
+
+    if opcode >= CLOSE && (len > 125 || !fin) {
+        return Err(PROTOCOL_ERROR);
+    }
+
+    if opcode < CLOSE && len > limit as u64 {
+        return Err(TOO_BIG);
+    }
+
+    let len = len as usize;
+
+    if buf.len() < pos + 4 + len {
+        return Ok(None);
+    }
+
+    let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+    pos += 4;
+
+    let payload = buf[pos..pos + len].iter()
+                                     .enumerate()
+                                     .map(|(i, b)| b ^ mask[i % 4])
+                                     .collect();
+
+    Ok(Some((fin, opcode, payload, pos + len)))
+}
+
+// whether a client may close with `code`. 1005, 1006 and 1015 are only for
+// reporting locally, and the rest of 1000-2999 is reserved for the protocol.
+fn is_valid_close(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+// an unfragmented, unmasked frame, as servers send them
+fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+
+    frame.push(0x80 | opcode);
+
+    match payload.len() {
+        len if len < 126     => frame.push(len as u8),
+        len if len <= 0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        },
+        len                  => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        },
+    }
+
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn close_frame(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+
+    // control frames carry at most 125 bytes
+    payload.extend(reason.bytes().take(123));
+    encode(CLOSE, &payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    // a masked frame, as clients send them
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1u8, 2, 3, 4];
+        let mut frame = encode(opcode, payload);
+        let start = frame.len() - payload.len();
+
+        if !fin {
+            frame[0] &= 0x7f;
+        }
+
+        frame[1] |= 0x80;
+
+        for (i, b) in frame[start..].iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+
+        frame.splice(start..start, mask.iter().cloned());
+        frame
+    }
+
+    fn open() -> (Session, mpsc::Receiver<Message>) {
+        let (inbound, rx) = mpsc::channel();
+        let session = Session {
+            inbound:     Some(inbound),
+            closed:      Arc::new(AtomicBool::new(false)),
+            message:     None,
+            max_message: 1024,
+            close_sent:  false,
+            done:        false,
+            output:      Vec::new(),
+        };
+
+        (session, rx)
+    }
+
+    #[test]
+    fn test_handshake() {
+        // the example from section 1.3 of RFC 6455
+        let req = Request::from_str("GET /chat HTTP/1.1\r\n\
+                                     Host: server.example.com\r\n\
+                                     Upgrade: websocket\r\n\
+                                     Connection: keep-alive, Upgrade\r\n\
+                                     Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                                     Sec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        let res = String::from_utf8(handshake(&req).unwrap()).unwrap();
+
+        assert!(res.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(res.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        let plain = Request::from_str("GET /chat HTTP/1.1\r\nHost: server.example.com\r\n\r\n").unwrap();
+        assert!(handshake(&plain).is_err());
+    }
+
+    #[test]
+    fn test_session_messages() {
+        let (mut session, rx) = open();
+        let mut buf = client_frame(true, TEXT, b"hello");
+
+        buf.extend(client_frame(false, BINARY, &[1, 2]));
+        buf.extend(client_frame(true, PING, b"hi"));
+        buf.extend(client_frame(true, CONTINUATION, &[3]));
+
+        // a frame that hasn't all arrived is left for later
+        let partial = client_frame(true, TEXT, b"later");
+        buf.extend(&partial[..4]);
+
+        session.receive(&mut buf);
+
+        assert_eq!(Message::Text(String::from("hello")), rx.try_recv().unwrap());
+        assert_eq!(Message::Binary(vec![1, 2, 3]), rx.try_recv().unwrap());
+        assert_eq!(encode(PONG, b"hi"), session.output);
+        assert_eq!(&partial[..4], buf.as_slice());
+        assert!(!session.is_done());
+    }
+
+    #[test]
+    fn test_session_close() {
+        let (mut session, rx) = open();
+        let mut buf = client_frame(true, CLOSE, &1000u16.to_be_bytes());
+
+        session.receive(&mut buf);
+
+        assert_eq!(close_frame(1000, ""), session.output);
+        assert!(session.is_done());
+        assert!(rx.recv().is_err());
+
+        // codes a client can't send are answered with a protocol error
+        for code in [999u16, 1004, 1005, 1006, 1015, 2999, 5000] {
+            let (mut session, _rx) = open();
+            let mut buf = client_frame(true, CLOSE, &code.to_be_bytes());
+
+            session.receive(&mut buf);
+            assert_eq!(close_frame(PROTOCOL_ERROR, ""), session.output, "{}", code);
+        }
+
+        let (mut session, _rx) = open();
+        let mut buf = client_frame(true, CLOSE, &4000u16.to_be_bytes());
+
+        session.receive(&mut buf);
+        assert_eq!(close_frame(4000, ""), session.output);
+    }
+
+    #[test]
+    fn test_session_errors() {
+        let (mut session, _rx) = open();
+        let mut unmasked = encode(TEXT, b"hello");
+
+        session.receive(&mut unmasked);
+        assert_eq!(close_frame(PROTOCOL_ERROR, ""), session.output);
+
+        let (mut session, _rx) = open();
+        let mut big = client_frame(true, BINARY, &[0u8; 1025]);
+
+        session.receive(&mut big);
+        assert_eq!(close_frame(TOO_BIG, ""), session.output);
+
+        // a frame that's too big is refused on its header alone, as is one
+        // that would take a fragmented message past the limit
+        let (mut session, _rx) = open();
+        let mut header = client_frame(true, BINARY, &[0u8; 2048]);
+
+        header.truncate(8);
+        session.receive(&mut header);
+        assert_eq!(close_frame(TOO_BIG, ""), session.output);
+
+        let (mut session, _rx) = open();
+        let mut buf = client_frame(false, TEXT, &[b'a'; 1000]);
+
+        buf.extend(&client_frame(true, CONTINUATION, &[b'a'; 100])[..8]);
+        session.receive(&mut buf);
+        assert_eq!(close_frame(TOO_BIG, ""), session.output);
+
+        let (mut session, _rx) = open();
+        let mut invalid = client_frame(true, TEXT, &[0xff, 0xfe]);
+
+        session.receive(&mut invalid);
+        assert_eq!(close_frame(INVALID_DATA, ""), session.output);
+        assert!(session.closed.load(Ordering::SeqCst));
+    }
+}